@@ -1,29 +1,198 @@
 use std::{
     io::{Read, Seek, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::Result;
 
+use crate::{Header, Progress};
+
 pub trait AlgorithmRead: Read + Seek {}
 impl<T: Read + Seek> AlgorithmRead for T {}
 pub trait AlgorithmWrite: Write + Seek {}
 impl<T: Write + Seek> AlgorithmWrite for T {}
 
+/// `header` is the plaintext header of the encrypted file, e.g. to pick the key derivation.
 pub trait Crypto: Send + Sync {
     fn encrypt(
         &self,
+        header: &Header,
         reader: &mut dyn AlgorithmRead,
         writer: &mut dyn AlgorithmWrite,
     ) -> Result<()>;
     fn decrypt(
         &self,
+        header: &Header,
         reader: &mut dyn AlgorithmRead,
         writer: &mut dyn AlgorithmWrite,
     ) -> Result<()>;
+    /// Encrypts a file name deterministically and with authentication, bound to `context`,
+    /// e.g. the encrypted path of its directory.
+    fn seal_name(&self, context: &[u8], name: &[u8]) -> Result<Vec<u8>>;
+    /// Reverses `seal_name`. Fails unless `sealed` was sealed with the same key and `context`.
+    fn open_name(&self, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>>;
+    /// Options besides the key that change how files are encrypted, so that files encrypted
+    /// otherwise can be told apart, see `Process::mirror`.
+    fn settings(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// Lets several `Process`es share one instance, e.g. to derive a key only once.
+impl<T: Crypto + ?Sized> Crypto for Arc<T> {
+    fn encrypt(
+        &self,
+        header: &Header,
+        reader: &mut dyn AlgorithmRead,
+        writer: &mut dyn AlgorithmWrite,
+    ) -> Result<()> {
+        (**self).encrypt(header, reader, writer)
+    }
+
+    fn decrypt(
+        &self,
+        header: &Header,
+        reader: &mut dyn AlgorithmRead,
+        writer: &mut dyn AlgorithmWrite,
+    ) -> Result<()> {
+        (**self).decrypt(header, reader, writer)
+    }
+
+    fn seal_name(&self, context: &[u8], name: &[u8]) -> Result<Vec<u8>> {
+        (**self).seal_name(context, name)
+    }
+
+    fn open_name(&self, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        (**self).open_name(context, sealed)
+    }
+
+    fn settings(&self) -> Vec<u8> {
+        (**self).settings()
+    }
+}
+
+/// Archive format of a `Pack`, recorded in encrypted files so that they are unpacked alike.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackFormat {
+    #[default]
+    Tar,
+    Zip,
+}
+
+impl PackFormat {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            PackFormat::Tar => 0,
+            PackFormat::Zip => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PackFormat::Tar),
+            1 => Some(PackFormat::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// What the packed payload of an encrypted file is, recorded so that it is never guessed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    /// A single file, packed as is.
+    File,
+    /// An archive of the `PackFormat` of the file.
+    Archive,
+}
+
+impl Payload {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Payload::File => 0,
+            Payload::Archive => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Payload::File),
+            1 => Some(Payload::Archive),
+            _ => None,
+        }
+    }
 }
 
 pub trait Pack: Send + Sync {
-    fn compression(&self, in_path: &Path, writer: &mut dyn AlgorithmWrite) -> Result<()>;
-    fn decompression(&self, reader: &mut dyn AlgorithmRead, out_path: &Path) -> Result<()>;
+    fn format(&self) -> PackFormat;
+    /// Packs the directory `in_path` into an archive, single files are packed by `Process`.
+    fn compression(
+        &self,
+        in_path: &Path,
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()>;
+    /// Packs each of `in_paths`, files or directories, under its name from `root_names`,
+    /// so that `decompression` recreates them side by side in `out_path`.
+    fn compression_roots(
+        &self,
+        in_paths: &[PathBuf],
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()>;
+    /// Unpacks the archive of `reader` into `out_path`. Fails if `reader` holds no archive.
+    fn decompression(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()>;
+    /// Like `decompression`, for archives written by this version, see `Metadata::payload`.
+    /// Fails if the archive lacks what its files are verified with, e.g. the manifest of tar
+    /// archives, which older archives only get a warning for.
+    fn decompression_verified(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        self.decompression(reader, out_path, progress)
+    }
+    /// Compares the files packed by `compression` with those in `path`, where `decompression`
+    /// would unpack them, reporting each file that is missing or differs as a warning.
+    /// Returns how many do. Files that are not in the archive are not compared.
+    fn check(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        path: &Path,
+        progress: &Progress,
+    ) -> Result<u64>;
+}
+
+/// Names of the inputs of `Pack::compression_roots`, i.e. their file names. Fails if two are the same.
+pub fn root_names(in_paths: &[PathBuf]) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::<String>::with_capacity(in_paths.len());
+    for path in in_paths {
+        // "." and the like have no file name of their own
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => path
+                .canonicalize()?
+                .file_name()
+                .unwrap_or_default()
+                .to_os_string(),
+        };
+        let name = name.to_string_lossy().into_owned();
+        if name.is_empty() || names.contains(&name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot store {} as {name:?}, names must be distinct",
+                    path.display()
+                ),
+            ));
+        }
+        names.push(name);
+    }
+    Ok(names)
 }
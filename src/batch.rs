@@ -0,0 +1,81 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{Error, ErrorKind, Process, Target};
+
+/// Runs many `Process`es on the tokio runtime, at most `jobs` at a time.
+///
+/// Share one `Arc<Chacha20>` between the processes so that the key is derived only once.
+pub struct Batch {
+    jobs: usize,
+    processes: Vec<Process>,
+}
+
+#[derive(Debug)]
+pub struct BatchItem {
+    pub input: PathBuf,
+    /// Output path on success.
+    pub result: Result<PathBuf, Error>,
+}
+
+impl Batch {
+    pub fn new(jobs: usize) -> Self {
+        Self {
+            jobs: jobs.max(1),
+            processes: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, process: Process) {
+        self.processes.push(process);
+    }
+
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    /// Runs every process, calling `on_item` as each one finishes.
+    /// Returns the results in the order the processes were pushed.
+    pub async fn execute(self, mut on_item: impl FnMut(&BatchItem)) -> Vec<BatchItem> {
+        let semaphore = Arc::new(Semaphore::new(self.jobs));
+        let mut set = JoinSet::new();
+        let mut inputs = Vec::with_capacity(self.processes.len());
+        let mut jobs = HashMap::with_capacity(self.processes.len());
+        for (i, process) in self.processes.into_iter().enumerate() {
+            inputs.push((process.from_path().to_path_buf(), process.target()));
+            let semaphore = semaphore.clone();
+            let job = set.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                process.execute().await
+            });
+            jobs.insert(job.id(), i);
+        }
+
+        let mut items: Vec<Option<BatchItem>> = inputs.iter().map(|_| None).collect();
+        while let Some(joined) = set.join_next_with_id().await {
+            // a job that panicked fails alone, the others are still reported
+            let (i, result) = match joined {
+                Ok((id, result)) => (jobs[&id], result),
+                Err(e) => {
+                    let kind = match inputs[jobs[&e.id()]].1 {
+                        Target::Enc | Target::Append => ErrorKind::EncryptionError,
+                        _ => ErrorKind::DecryptionError,
+                    };
+                    (jobs[&e.id()], Err(Error::new(kind, e.to_string())))
+                }
+            };
+            let item = BatchItem {
+                input: inputs[i].0.clone(),
+                result,
+            };
+            on_item(&item);
+            items[i] = Some(item);
+        }
+        items.into_iter().flatten().collect()
+    }
+}
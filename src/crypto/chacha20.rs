@@ -1,72 +1,581 @@
-use std::path::PathBuf;
+use std::{
+    io::{Read, SeekFrom},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use aes::cipher::{KeyIvInit, StreamCipher};
 use anyhow::Result;
-use pbkdf2::pbkdf2_hmac;
-use sha2::Sha256;
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    KeyInit, XChaCha20Poly1305,
+};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-use crate::{key_file_phrase, Crypto};
+use crate::{Crypto, Error, ErrorKind, Header, Kdf, KeyMaterial};
 
+/// ChaCha20 keyed by a key file.
+///
+/// Authenticated headers (format version 3) get XChaCha20-Poly1305 in the STREAM construction:
+///
+/// ```text
+/// nonce prefix: 19 bytes | key check: 16 bytes | chunk ... | last chunk
+/// ```
+///
+/// Each chunk holds up to 64KiB of plaintext and a 16 bytes tag, with the header as associated data.
+/// The nonce prefix is random unless `deterministic_nonce` is set, and the key check tells a wrong key
+/// from a corrupted file.
+/// Legacy files without a header get the bare ChaCha20 stream with the derived nonce.
+///
+/// Names are sealed in the manner of SIV: a 16 bytes HMAC-SHA256 tag of the context and the name,
+/// followed by the name encrypted with XChaCha20 under the tag as nonce. Both keys are derived
+/// from the key of the current `Kdf`.
 pub struct Chacha20 {
-    salt: Option<String>,
-    key_filepath: PathBuf,
+    salt: Option<Zeroizing<String>>,
+    key_filepath: Option<PathBuf>,
+    /// Derived on first use, one per `Kdf`, and shared by later calls.
+    key_material: Mutex<Vec<Arc<KeyMaterial>>>,
+    deterministic_nonce: bool,
 }
 
-const ITER: u32 = 1_000_000;
 const BUFFER_SIZE: usize = 8192;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 19;
+const KEY_CHECK_SIZE: usize = 16;
+const NAME_TAG_SIZE: usize = 16;
+
+impl std::fmt::Debug for Chacha20 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chacha20")
+            .field("key_filepath", &self.key_filepath)
+            .field("deterministic_nonce", &self.deterministic_nonce)
+            .finish_non_exhaustive()
+    }
+}
 
 impl Chacha20 {
+    /// The key is derived from `key_filepath` and `salt` on first use.
     pub fn new(salt: Option<String>, key_filepath: impl Into<PathBuf>) -> Self {
         Self {
-            salt,
-            key_filepath: key_filepath.into(),
+            salt: salt.map(Zeroizing::new),
+            key_filepath: Some(key_filepath.into()),
+            key_material: Mutex::new(Vec::new()),
+            deterministic_nonce: false,
+        }
+    }
+
+    /// Only files whose header names the `Kdf` of `key_material` can be processed.
+    pub fn with_key_material(key_material: impl Into<Arc<KeyMaterial>>) -> Self {
+        Self {
+            salt: None,
+            key_filepath: None,
+            key_material: Mutex::new(vec![key_material.into()]),
+            deterministic_nonce: false,
         }
     }
 
+    /// Derive the nonce prefix of authenticated files from the key, the header and the plaintext,
+    /// in the manner of SIV, so that encrypting the same input twice gives the same file.
+    /// Reveals which files are equal, and reads the plaintext twice, so it must be seekable.
+    pub fn deterministic_nonce(self, deterministic_nonce: bool) -> Self {
+        Self {
+            deterministic_nonce,
+            ..self
+        }
+    }
+
+    pub fn key_material(&self, kdf: Kdf) -> Result<Arc<KeyMaterial>> {
+        // holding the lock while deriving makes concurrent callers wait for a single derivation
+        let mut cache = self.key_material.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key_material) = cache.iter().find(|m| m.kdf() == kdf) {
+            return Ok(key_material.clone());
+        }
+        let Some(key_filepath) = &self.key_filepath else {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                format!("no key material for key file digest {kdf:?}"),
+            )
+            .into());
+        };
+        let derived = Arc::new(KeyMaterial::derive(
+            key_filepath,
+            self.salt.as_deref().map(String::as_str),
+            kdf,
+        )?);
+        cache.push(derived.clone());
+        Ok(derived)
+    }
+
     fn process_contrast(
         &self,
+        header: &Header,
         reader: &mut dyn crate::AlgorithmRead,
         writer: &mut dyn crate::AlgorithmWrite,
     ) -> Result<()> {
-        let pass = key_file_phrase(&self.key_filepath)?;
-        let salt = match &self.salt {
-            Some(v) => v.as_bytes().to_vec(),
-            None => vec![],
-        };
-        let mut base = [0u8; 32 + 12];
-        pbkdf2_hmac::<Sha256>(&pass, &salt, ITER, &mut base);
-        let key: [u8; 32] = base[..32].try_into()?;
-        let nonce: [u8; 12] = base[32..44].try_into()?;
-
-        let mut cipher = chacha20::ChaCha20::new(&key.into(), &nonce.into());
+        let key_material = self.key_material(header.kdf)?;
+        let mut cipher =
+            chacha20::ChaCha20::new(key_material.key().into(), key_material.nonce().into());
         let mut buffer = [0u8; BUFFER_SIZE];
         loop {
             let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
             cipher.apply_keystream(&mut buffer[..read]);
             writer.write_all(&buffer[..read])?;
-            if read < BUFFER_SIZE {
-                break;
+        }
+
+        Ok(())
+    }
+
+    fn seal(
+        &self,
+        header: &Header,
+        reader: &mut dyn crate::AlgorithmRead,
+        writer: &mut dyn crate::AlgorithmWrite,
+    ) -> Result<()> {
+        let key_material = self.key_material(header.kdf)?;
+        let aad = header.to_bytes();
+        let nonce_prefix = match self.deterministic_nonce {
+            true => synthetic_nonce(key_material.key(), &aad, reader)?,
+            false => {
+                let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+                OsRng.fill_bytes(&mut nonce_prefix);
+                nonce_prefix
+            }
+        };
+        writer.write_all(&nonce_prefix)?;
+        writer.write_all(&key_check(key_material.key(), &nonce_prefix))?;
+
+        let aead = XChaCha20Poly1305::new(key_material.key().into());
+        let mut encryptor = EncryptorBE32::from_aead(aead, (&nonce_prefix).into());
+        let mut current = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        let mut next = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        read_chunk(reader, &mut current, CHUNK_SIZE)?;
+        // the last chunk is sealed differently, so look one chunk ahead
+        let mut last = loop {
+            read_chunk(reader, &mut next, CHUNK_SIZE)?;
+            if next.is_empty() {
+                break current;
             }
+            encryptor
+                .encrypt_next_in_place(&aad, &mut current)
+                .map_err(|_| Error::from(ErrorKind::EncryptionError))?;
+            writer.write_all(&current)?;
+            std::mem::swap(&mut current, &mut next);
+        };
+        encryptor
+            .encrypt_last_in_place(&aad, &mut last)
+            .map_err(|_| Error::from(ErrorKind::EncryptionError))?;
+        writer.write_all(&last)?;
+        Ok(())
+    }
+
+    /// HMAC keys for names, the first for their tags and the second for their encryption.
+    fn name_macs(&self) -> Result<(Hmac<Sha256>, Hmac<Sha256>)> {
+        let key_material = self.key_material(Kdf::CURRENT)?;
+        let derive = |label: &[u8]| -> Result<Hmac<Sha256>> {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key_material.key())?;
+            mac.update(label);
+            let key: Zeroizing<[u8; 32]> = Zeroizing::new(mac.finalize().into_bytes().into());
+            Ok(<Hmac<Sha256> as Mac>::new_from_slice(&*key)?)
+        };
+        Ok((
+            derive(b"mkencbox name tag\0")?,
+            derive(b"mkencbox name encryption\0")?,
+        ))
+    }
+
+    fn open(
+        &self,
+        header: &Header,
+        reader: &mut dyn crate::AlgorithmRead,
+        writer: &mut dyn crate::AlgorithmWrite,
+    ) -> Result<()> {
+        let key_material = self.key_material(header.kdf)?;
+        let aad = header.to_bytes();
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        let mut check = [0u8; KEY_CHECK_SIZE];
+        reader
+            .read_exact(&mut nonce_prefix)
+            .and_then(|_| reader.read_exact(&mut check))
+            .map_err(|e| Error::new(ErrorKind::Corrupted, e))?;
+        if check != key_check(key_material.key(), &nonce_prefix) {
+            return Err(Error::from(ErrorKind::WrongKey).into());
         }
 
+        let aead = XChaCha20Poly1305::new(key_material.key().into());
+        let mut decryptor = DecryptorBE32::from_aead(aead, (&nonce_prefix).into());
+        let mut current = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        let mut next = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        read_chunk(reader, &mut current, CHUNK_SIZE + TAG_SIZE)?;
+        let mut last = loop {
+            read_chunk(reader, &mut next, CHUNK_SIZE + TAG_SIZE)?;
+            if next.is_empty() {
+                break current;
+            }
+            decryptor
+                .decrypt_next_in_place(&aad, &mut current)
+                .map_err(|_| tampered())?;
+            writer.write_all(&current)?;
+            std::mem::swap(&mut current, &mut next);
+        };
+        // also fails if chunks were cut off at the end
+        decryptor
+            .decrypt_last_in_place(&aad, &mut last)
+            .map_err(|_| tampered())?;
+        writer.write_all(&last)?;
         Ok(())
     }
 }
 
+fn key_check(key: &[u8; 32], nonce_prefix: &[u8]) -> [u8; KEY_CHECK_SIZE] {
+    let digest = Sha256::new()
+        .chain_update(b"mkencbox key check\0")
+        .chain_update(key)
+        .chain_update(nonce_prefix)
+        .finalize();
+    let mut check = [0u8; KEY_CHECK_SIZE];
+    check.copy_from_slice(&digest[..KEY_CHECK_SIZE]);
+    check
+}
+
+/// Nonce prefix from a MAC of `aad` and the rest of `reader`, which is rewound afterwards.
+fn synthetic_nonce(
+    key: &[u8; 32],
+    aad: &[u8],
+    reader: &mut dyn crate::AlgorithmRead,
+) -> Result<[u8; NONCE_PREFIX_SIZE]> {
+    let unseekable = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::Unsupported => Error::new(
+            ErrorKind::EncryptionError,
+            "a deterministic nonce needs an input that can be read twice",
+        )
+        .into(),
+        _ => anyhow::Error::from(e),
+    };
+    let start = reader.stream_position().map_err(unseekable)?;
+    // fails on pipes before anything is consumed
+    reader.seek(SeekFrom::Start(start)).map_err(unseekable)?;
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)?;
+    mac.update(b"mkencbox nonce\0");
+    let key: Zeroizing<[u8; 32]> = Zeroizing::new(mac.finalize().into_bytes().into());
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&*key)?;
+    mac.update(&(aad.len() as u64).to_le_bytes());
+    mac.update(aad);
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        mac.update(&buffer[..read]);
+    }
+    reader.seek(SeekFrom::Start(start)).map_err(unseekable)?;
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+    nonce_prefix.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_PREFIX_SIZE]);
+    Ok(nonce_prefix)
+}
+
+fn name_tag(mut mac: Hmac<Sha256>, context: &[u8], name: &[u8]) -> Hmac<Sha256> {
+    mac.update(&(context.len() as u64).to_le_bytes());
+    mac.update(context);
+    mac.update(name);
+    mac
+}
+
+/// XChaCha20 over `name`, keyed by `mac` with `tag` as nonce.
+fn apply_name_keystream(mac: Hmac<Sha256>, tag: &[u8], name: &mut [u8]) {
+    let key: Zeroizing<[u8; 32]> = Zeroizing::new(mac.finalize().into_bytes().into());
+    let mut nonce = [0u8; 24];
+    nonce[..NAME_TAG_SIZE].copy_from_slice(tag);
+    chacha20::XChaCha20::new((&*key).into(), &nonce.into()).apply_keystream(name);
+}
+
+fn tampered() -> Error {
+    Error::new(ErrorKind::Corrupted, "authentication failed")
+}
+
+/// Reads up to `size` bytes into `buffer`, fewer only at the end of `reader`.
+fn read_chunk(
+    reader: &mut dyn crate::AlgorithmRead,
+    buffer: &mut Vec<u8>,
+    size: usize,
+) -> std::io::Result<()> {
+    buffer.clear();
+    Read::take(&mut *reader, size as u64).read_to_end(buffer)?;
+    Ok(())
+}
+
 impl Crypto for Chacha20 {
     fn encrypt(
         &self,
+        header: &Header,
         reader: &mut dyn crate::AlgorithmRead,
         writer: &mut dyn crate::AlgorithmWrite,
     ) -> Result<()> {
-        self.process_contrast(reader, writer)
+        if header.is_authenticated() {
+            return self.seal(header, reader, writer);
+        }
+        self.process_contrast(header, reader, writer)
     }
 
     fn decrypt(
         &self,
+        header: &Header,
         reader: &mut dyn crate::AlgorithmRead,
         writer: &mut dyn crate::AlgorithmWrite,
     ) -> Result<()> {
-        self.process_contrast(reader, writer)
+        if header.is_authenticated() {
+            return self.open(header, reader, writer);
+        }
+        self.process_contrast(header, reader, writer)
+    }
+
+    fn seal_name(&self, context: &[u8], name: &[u8]) -> Result<Vec<u8>> {
+        let (tag_mac, encryption_mac) = self.name_macs()?;
+        let tag = name_tag(tag_mac, context, name).finalize().into_bytes();
+        let mut sealed = tag[..NAME_TAG_SIZE].to_vec();
+        sealed.extend_from_slice(name);
+        let (tag, name) = sealed.split_at_mut(NAME_TAG_SIZE);
+        apply_name_keystream(encryption_mac, tag, name);
+        Ok(sealed)
+    }
+
+    fn open_name(&self, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NAME_TAG_SIZE {
+            return Err(tampered().into());
+        }
+        let (tag_mac, encryption_mac) = self.name_macs()?;
+        let (tag, name) = sealed.split_at(NAME_TAG_SIZE);
+        let mut name = name.to_vec();
+        apply_name_keystream(encryption_mac, tag, &mut name);
+        name_tag(tag_mac, context, &name)
+            .verify_truncated_left(tag)
+            .map_err(|_| tampered())?;
+        Ok(name)
+    }
+
+    fn settings(&self) -> Vec<u8> {
+        vec![self.deterministic_nonce as u8]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{Crypto, ErrorKind, Header, Kdf, KeyMaterial};
+
+    use super::{Chacha20, CHUNK_SIZE, KEY_CHECK_SIZE, NAME_TAG_SIZE, NONCE_PREFIX_SIZE, TAG_SIZE};
+
+    #[test]
+    fn name_test() {
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ));
+        let sealed = crypto.seal_name(b"dir", b"salaries.xlsx").unwrap();
+        assert_eq!(NAME_TAG_SIZE + "salaries.xlsx".len(), sealed.len());
+        assert!(!sealed.windows(8).any(|w| w == b"salaries"));
+        assert_eq!(sealed, crypto.seal_name(b"dir", b"salaries.xlsx").unwrap());
+        assert_ne!(
+            sealed,
+            crypto.seal_name(b"other", b"salaries.xlsx").unwrap()
+        );
+        assert_eq!(
+            b"salaries.xlsx".to_vec(),
+            crypto.open_name(b"dir", &sealed).unwrap()
+        );
+
+        // moved to another directory, tampered with or cut
+        assert!(crypto.open_name(b"other", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[NAME_TAG_SIZE] ^= 1;
+        assert!(crypto.open_name(b"dir", &tampered).is_err());
+        assert!(crypto.open_name(b"dir", &sealed[..8]).is_err());
+
+        let other = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [3u8; 32],
+            &mut [2u8; 12],
+        ));
+        assert!(other.open_name(b"dir", &sealed).is_err());
+    }
+
+    #[test]
+    fn key_material_test() {
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ));
+        let header = Header::current();
+        let plain = vec![7u8; 20000];
+
+        let mut encrypted = Cursor::new(Vec::new());
+        crypto
+            .encrypt(&header, &mut Cursor::new(plain.clone()), &mut encrypted)
+            .unwrap();
+        assert_ne!(plain, encrypted.get_ref()[..]);
+
+        let mut decrypted = Cursor::new(Vec::new());
+        encrypted.set_position(0);
+        crypto
+            .decrypt(&header, &mut encrypted, &mut decrypted)
+            .unwrap();
+        assert_eq!(plain, decrypted.into_inner());
+
+        // no key file is read when the key material is given
+        let shared = crypto.key_material(Kdf::CURRENT).unwrap();
+        assert!(std::sync::Arc::ptr_eq(
+            &shared,
+            &crypto.key_material(Kdf::CURRENT).unwrap()
+        ));
+        let e = crypto
+            .decrypt(
+                &Header::legacy(),
+                &mut Cursor::new(vec![]),
+                &mut Cursor::new(vec![]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            ErrorKind::UnsupportedVersion,
+            e.downcast_ref::<crate::Error>().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn authenticated_test() {
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ));
+        let header = Header::current();
+        let encrypt = |plain: &[u8]| {
+            let mut encrypted = Cursor::new(Vec::new());
+            crypto
+                .encrypt(&header, &mut Cursor::new(plain.to_vec()), &mut encrypted)
+                .unwrap();
+            encrypted.into_inner()
+        };
+        let decrypt = |encrypted: &[u8]| {
+            let mut decrypted = Cursor::new(Vec::new());
+            crypto
+                .decrypt(
+                    &header,
+                    &mut Cursor::new(encrypted.to_vec()),
+                    &mut decrypted,
+                )
+                .map(|_| decrypted.into_inner())
+                .map_err(|e| e.downcast::<crate::Error>().unwrap().kind())
+        };
+
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&plain);
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(
+                NONCE_PREFIX_SIZE + KEY_CHECK_SIZE + len + chunks * TAG_SIZE,
+                encrypted.len()
+            );
+            assert_eq!(Ok(plain), decrypt(&encrypted));
+        }
+
+        // random nonce
+        let plain = vec![7u8; 2 * CHUNK_SIZE + 10];
+        let encrypted = encrypt(&plain);
+        assert_ne!(encrypted, encrypt(&plain));
+
+        let mut flipped = encrypted.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(Err(ErrorKind::Corrupted), decrypt(&flipped));
+
+        // whole chunks cut off
+        let header_len = NONCE_PREFIX_SIZE + KEY_CHECK_SIZE;
+        let truncated = &encrypted[..header_len + CHUNK_SIZE + TAG_SIZE];
+        assert_eq!(Err(ErrorKind::Corrupted), decrypt(truncated));
+
+        // header is authenticated
+        let mut other = Cursor::new(Vec::new());
+        let other_header = Header {
+            kdf: Kdf::V1,
+            ..Header::current()
+        };
+        let crypto_v1 =
+            Chacha20::with_key_material(KeyMaterial::new(Kdf::V1, &mut [1u8; 32], &mut [2u8; 12]));
+        assert!(crypto_v1
+            .decrypt(
+                &other_header,
+                &mut Cursor::new(encrypted.clone()),
+                &mut other
+            )
+            .is_err());
+
+        let wrong = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [3u8; 32],
+            &mut [2u8; 12],
+        ));
+        let e = wrong
+            .decrypt(&header, &mut Cursor::new(encrypted), &mut other)
+            .unwrap_err();
+        assert_eq!(
+            ErrorKind::WrongKey,
+            e.downcast_ref::<crate::Error>().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn deterministic_nonce_test() {
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ))
+        .deterministic_nonce(true);
+        let header = Header::current();
+        let encrypt = |plain: &[u8]| {
+            let mut encrypted = Cursor::new(Vec::new());
+            crypto
+                .encrypt(&header, &mut Cursor::new(plain.to_vec()), &mut encrypted)
+                .unwrap();
+            encrypted.into_inner()
+        };
+
+        let plain = vec![7u8; CHUNK_SIZE + 10];
+        let encrypted = encrypt(&plain);
+        assert_eq!(encrypted, encrypt(&plain));
+        let mut other = plain.clone();
+        other[CHUNK_SIZE] = 8;
+        let other = encrypt(&other);
+        assert_ne!(encrypted[..NONCE_PREFIX_SIZE], other[..NONCE_PREFIX_SIZE]);
+
+        let mut decrypted = Cursor::new(Vec::new());
+        crypto
+            .decrypt(&header, &mut Cursor::new(encrypted), &mut decrypted)
+            .unwrap();
+        assert_eq!(plain, decrypted.into_inner());
+    }
+
+    #[test]
+    fn debug_test() {
+        let crypto = Chacha20::new(Some("secret salt".into()), "key.bin");
+        let debug = format!("{crypto:?}");
+        assert!(debug.contains("key.bin"));
+        assert!(!debug.contains("secret"));
+        assert_eq!(
+            "KeyMaterial { kdf: V1, .. }",
+            format!(
+                "{:?}",
+                KeyMaterial::new(Kdf::V1, &mut [1u8; 32], &mut [2u8; 12])
+            )
+        );
     }
 }
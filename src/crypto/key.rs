@@ -0,0 +1,265 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::Result;
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::{Error, ErrorKind, PathContext};
+
+const ITER: u32 = 1_000_000;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Prefix hashed before the key file contents by `Kdf::V2`.
+const V2_DOMAIN: &[u8] = b"mkencbox keyfile v2\0";
+
+/// Version of the function that turns a key file into a secret. Recorded in the file header.
+///
+/// Both versions read the key file as a stream and feed the secret into
+/// PBKDF2-HMAC-SHA256 with the salt and 1,000,000 iterations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kdf {
+    /// `hex(sha256(file)) | "0" | hex(md5(file))`, the construction of mkencbox 2.0.
+    /// Files without a header use it.
+    V1,
+    /// `sha256("mkencbox keyfile v2\0" | file)`.
+    V2,
+}
+
+impl Kdf {
+    /// Used for new files.
+    pub const CURRENT: Kdf = Kdf::V2;
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Kdf::V1 => 1,
+            Kdf::V2 => 2,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Kdf::V1),
+            2 => Some(Kdf::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Secret derived from the key file contents by `kdf`, wiped from memory on drop.
+pub fn key_file_secret(kfile: &Path, kdf: Kdf) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if !kfile.is_file() {
+        return Err(Error::from(ErrorKind::InvalidKeyfile).with_path(kfile));
+    }
+    let mut file = File::open(kfile).with_path(kfile)?;
+    let mut buffer = Zeroizing::new(vec![0u8; BUFFER_SIZE]);
+
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Context::new();
+    if kdf == Kdf::V2 {
+        sha256.update(V2_DOMAIN);
+    }
+    loop {
+        let read = file.read(&mut buffer).with_path(kfile)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+        if kdf == Kdf::V1 {
+            md5.consume(&buffer[..read]);
+        }
+    }
+    let mut sha256sum: [u8; 32] = sha256.finalize().into();
+
+    let secret = match kdf {
+        Kdf::V1 => {
+            let mut md5sum = md5.compute().0;
+            // sized up front so the buffer is never reallocated
+            let mut p = Zeroizing::new(vec![0u8; 64 + 1 + 32]);
+            hex::encode_to_slice(sha256sum, &mut p[..64])
+                .and_then(|_| hex::encode_to_slice(md5sum, &mut p[65..]))
+                .map_err(|e| Error::new(ErrorKind::InvalidKeyfile, e))?;
+            p[64] = b'0';
+            md5sum.zeroize();
+            p
+        }
+        Kdf::V2 => Zeroizing::new(sha256sum.to_vec()),
+    };
+    sha256sum.zeroize();
+    Ok(secret)
+}
+
+/// Keys derived from a key file and a salt. The derivation is costly, so derive once
+/// and share the result between `Chacha20` instances. Wiped from memory on drop.
+///
+/// With the `mlock` feature on Linux the keys are also locked in memory, if the limits allow it.
+pub struct KeyMaterial {
+    kdf: Kdf,
+    // boxed so that the keys never move and the locked pages stay valid
+    keys: Box<Keys>,
+}
+
+/// The key followed by the nonce, as PBKDF2 outputs them.
+#[derive(Zeroize)]
+struct Keys([u8; 32 + 12]);
+
+impl KeyMaterial {
+    /// Key material for files whose header names `kdf`. `key` and `nonce` are wiped once copied.
+    pub fn new(kdf: Kdf, key: &mut [u8; 32], nonce: &mut [u8; 12]) -> Self {
+        let mut material = Self::zeroed(kdf);
+        material.keys.0[..32].copy_from_slice(key);
+        material.keys.0[32..].copy_from_slice(nonce);
+        key.zeroize();
+        nonce.zeroize();
+        material
+    }
+
+    /// Runs PBKDF2-HMAC-SHA256 over the key file secret.
+    pub fn derive(key_file: &Path, salt: Option<&str>, kdf: Kdf) -> Result<Self> {
+        let pass = key_file_secret(key_file, kdf)?;
+        let salt = salt.map(str::as_bytes).unwrap_or_default();
+        let mut material = Self::zeroed(kdf);
+        pbkdf2_hmac::<Sha256>(&pass, salt, ITER, &mut material.keys.0);
+        Ok(material)
+    }
+
+    /// Empty keys, allocated and locked before anything secret is written to them.
+    fn zeroed(kdf: Kdf) -> Self {
+        let keys = Box::new(Keys([0u8; 32 + 12]));
+        lock(&keys);
+        Self { kdf, keys }
+    }
+
+    pub fn kdf(&self) -> Kdf {
+        self.kdf
+    }
+
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        self.keys.0[..32].try_into().unwrap()
+    }
+
+    pub(crate) fn nonce(&self) -> &[u8; 12] {
+        self.keys.0[32..].try_into().unwrap()
+    }
+}
+
+impl Clone for KeyMaterial {
+    fn clone(&self) -> Self {
+        let mut material = Self::zeroed(self.kdf);
+        material.keys.0.copy_from_slice(&self.keys.0);
+        material
+    }
+}
+
+impl Drop for KeyMaterial {
+    fn drop(&mut self) {
+        self.keys.zeroize();
+        unlock(&self.keys);
+    }
+}
+
+impl std::fmt::Debug for KeyMaterial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyMaterial")
+            .field("kdf", &self.kdf)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(feature = "mlock", target_os = "linux"))]
+fn lock(keys: &Keys) {
+    // best effort, RLIMIT_MEMLOCK may be too low
+    unsafe {
+        libc::mlock(
+            keys as *const Keys as *const libc::c_void,
+            std::mem::size_of::<Keys>(),
+        );
+    }
+}
+
+#[cfg(all(feature = "mlock", target_os = "linux"))]
+fn unlock(keys: &Keys) {
+    unsafe {
+        libc::munlock(
+            keys as *const Keys as *const libc::c_void,
+            std::mem::size_of::<Keys>(),
+        );
+    }
+}
+
+#[cfg(not(all(feature = "mlock", target_os = "linux")))]
+fn lock(_: &Keys) {}
+
+#[cfg(not(all(feature = "mlock", target_os = "linux")))]
+fn unlock(_: &Keys) {}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use sha2::{Digest, Sha256};
+    use tempfile::NamedTempFile;
+
+    use crate::ErrorKind;
+
+    use super::{key_file_secret, Kdf, KeyMaterial};
+
+    #[test]
+    fn key_file_secret_test() {
+        let mut kfile = NamedTempFile::new().unwrap();
+        kfile.write_all(b"abc").unwrap();
+
+        let v1 = key_file_secret(kfile.path(), Kdf::V1).unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\
+             0900150983cd24fb0d6963f7d28e17f72"
+                .as_bytes(),
+            &v1[..]
+        );
+
+        let v2 = key_file_secret(kfile.path(), Kdf::V2).unwrap();
+        let expected = Sha256::new()
+            .chain_update(b"mkencbox keyfile v2\0")
+            .chain_update(b"abc")
+            .finalize();
+        assert_eq!(&expected[..], &v2[..]);
+
+        // larger than the read buffer
+        let big = vec![7u8; 200 * 1024];
+        kfile.write_all(&big).unwrap();
+        let v2 = key_file_secret(kfile.path(), Kdf::V2).unwrap();
+        let expected = Sha256::new()
+            .chain_update(b"mkencbox keyfile v2\0abc")
+            .chain_update(&big)
+            .finalize();
+        assert_eq!(&expected[..], &v2[..]);
+
+        let missing = kfile.path().with_extension("missing");
+        assert_eq!(
+            ErrorKind::InvalidKeyfile,
+            key_file_secret(&missing, Kdf::V2).unwrap_err().kind()
+        );
+
+        for kdf in [Kdf::V1, Kdf::V2] {
+            assert_eq!(Some(kdf), Kdf::from_u8(kdf.to_u8()));
+        }
+        assert_eq!(None, Kdf::from_u8(0));
+    }
+
+    #[test]
+    fn key_material_test() {
+        let (mut key, mut nonce) = ([1u8; 32], [2u8; 12]);
+        let material = KeyMaterial::new(Kdf::V2, &mut key, &mut nonce);
+        // the copies handed over are wiped
+        assert_eq!([0u8; 32], key);
+        assert_eq!([0u8; 12], nonce);
+        assert_eq!(&[1u8; 32], material.key());
+        assert_eq!(&[2u8; 12], material.nonce());
+
+        let clone = material.clone();
+        drop(material);
+        assert_eq!(Kdf::V2, clone.kdf());
+        assert_eq!(&[1u8; 32], clone.key());
+        assert_eq!(&[2u8; 12], clone.nonce());
+    }
+}
@@ -1,8 +1,25 @@
+use std::path::{Path, PathBuf};
+
+/// Kind of failure. Each kind maps to a stable process exit code, see [`ErrorKind::exit_code`].
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ErrorKind {
     EncryptionError,
     DecryptionError,
     InvalidKeyfile,
+    /// Reading or writing a file failed.
+    Io,
+    /// The key file does not match the one used for encryption.
+    WrongKey,
+    /// The input is damaged or failed authentication.
+    Corrupted,
+    /// The input was written by a newer or unknown format version.
+    UnsupportedVersion,
+    OutputExists,
+    /// The signature is missing, invalid or not from a trusted key.
+    BadSignature,
+    /// Files on disk differ from those recorded in an archive, see `Target::Check`.
+    Mismatch,
+    Cancelled,
 }
 
 impl ErrorKind {
@@ -11,6 +28,45 @@ impl ErrorKind {
             ErrorKind::EncryptionError => "encrypt failed",
             ErrorKind::DecryptionError => "decrypt failed",
             ErrorKind::InvalidKeyfile => "invalid keyfile",
+            ErrorKind::Io => "i/o error",
+            ErrorKind::WrongKey => "wrong key file",
+            ErrorKind::Corrupted => "corrupted input or authentication failed",
+            ErrorKind::UnsupportedVersion => "unsupported format version",
+            ErrorKind::OutputExists => "output already exists",
+            ErrorKind::BadSignature => "missing, invalid or untrusted signature",
+            ErrorKind::Mismatch => "files differ from the archive",
+            ErrorKind::Cancelled => "cancelled by user",
+        }
+    }
+
+    /// Exit code of the `mkencbox` command for this kind.
+    ///
+    /// | code | kind |
+    /// |------|------|
+    /// | 1    | `EncryptionError`, `DecryptionError` |
+    /// | 2    | invalid command line (reported by the argument parser) |
+    /// | 3    | `Io` |
+    /// | 4    | `InvalidKeyfile` |
+    /// | 5    | `WrongKey` |
+    /// | 6    | `Corrupted` |
+    /// | 7    | `UnsupportedVersion` |
+    /// | 8    | `OutputExists` |
+    /// | 9    | `BadSignature` |
+    /// | 10   | `Mismatch` |
+    /// | 130  | `Cancelled` |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::EncryptionError => 1,
+            ErrorKind::DecryptionError => 1,
+            ErrorKind::Io => 3,
+            ErrorKind::InvalidKeyfile => 4,
+            ErrorKind::WrongKey => 5,
+            ErrorKind::Corrupted => 6,
+            ErrorKind::UnsupportedVersion => 7,
+            ErrorKind::OutputExists => 8,
+            ErrorKind::BadSignature => 9,
+            ErrorKind::Mismatch => 10,
+            ErrorKind::Cancelled => 130,
         }
     }
 }
@@ -18,18 +74,127 @@ impl ErrorKind {
 #[derive(Debug)]
 pub struct Error {
     kind: ErrorKind,
+    path: Option<PathBuf>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new(
+        kind: ErrorKind,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            kind,
+            path: None,
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn io(source: std::io::Error, path: impl Into<PathBuf>) -> Self {
+        Self::new(ErrorKind::Io, source).with_path(path)
+    }
+
+    /// Converts an error returned by a `Pack` or `Crypto` implementation.
+    /// Errors that are not already classified get `fallback` as their kind.
+    pub fn from_anyhow(e: anyhow::Error, fallback: ErrorKind, path: &Path) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => {
+                if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+                    let inner = e.into_inner().unwrap();
+                    return *inner.downcast::<Error>().unwrap();
+                }
+                Self::io(e, path)
+            }
+            Err(e) => Self::new(fallback, e).with_path(path),
+        }
+    }
+
+    pub fn with_path(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..self
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.kind.description())
+        write!(f, "{}", self.kind.description())?;
+        if let Some(path) = &self.path {
+            write!(f, ": {}", path.display())?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
     }
 }
 
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
-        Self { kind }
+        Self {
+            kind,
+            path: None,
+            source: None,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
     }
 }
 
-impl std::error::Error for Error {}
+pub(crate) trait PathContext<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, Error>;
+}
+
+impl<T> PathContext<T> for std::io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T, Error> {
+        self.map_err(|e| Error::io(e, path.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Error, ErrorKind};
+
+    #[test]
+    fn from_anyhow_test() {
+        let path = Path::new("a.txt");
+
+        let e = anyhow::Error::from(Error::from(ErrorKind::WrongKey));
+        assert_eq!(
+            ErrorKind::WrongKey,
+            Error::from_anyhow(e, ErrorKind::DecryptionError, path).kind()
+        );
+
+        let e = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        let e = Error::from_anyhow(e, ErrorKind::DecryptionError, path);
+        assert_eq!(ErrorKind::Io, e.kind());
+        assert_eq!(Some(path), e.path());
+        assert_eq!(3, e.kind().exit_code());
+
+        let e = anyhow::anyhow!("broken");
+        let e = Error::from_anyhow(e, ErrorKind::DecryptionError, path);
+        assert_eq!(ErrorKind::DecryptionError, e.kind());
+        assert_eq!("decrypt failed: a.txt: broken", e.to_string());
+    }
+}
@@ -0,0 +1,394 @@
+//! Layout of an encrypted file:
+//!
+//! ```text
+//! "MKENCBOX" | version: u8 | kdf: u8 | encrypted( metadata | packed payload [| padding] )
+//! ```
+//!
+//! The payload is encrypted with authentication, see `Chacha20`.
+//! Files that entries were appended to continue with more encrypted segments, see `segment`.
+//! Files written by mkencbox 2.0, before the header existed ("legacy" files), start directly with
+//! the payload encrypted by the bare ChaCha20 stream. No other version was ever written.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{Error, ErrorKind, Kdf, PackFormat, Payload};
+
+pub const MAGIC: &[u8; 8] = b"MKENCBOX";
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Start of the decrypted metadata. Decrypting with a wrong key turns it into noise.
+const METADATA_MARKER: &[u8; 4] = b"MEBM";
+const METADATA_MAX_LEN: u32 = 1024 * 1024;
+
+const TAG_ORIGINAL_NAME: u8 = 1;
+const TAG_ROOT: u8 = 2;
+const TAG_PACK_FORMAT: u8 = 3;
+const TAG_PADDED: u8 = 4;
+const TAG_PAYLOAD: u8 = 5;
+const TAG_INDEXED: u8 = 6;
+
+/// Plaintext header of an encrypted file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Format version, `0` for files without a header.
+    pub version: u8,
+    pub kdf: Kdf,
+}
+
+impl Header {
+    /// Header of new files.
+    pub fn current() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            kdf: Kdf::CURRENT,
+        }
+    }
+
+    /// Stands in for the header of files written before the header existed.
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            kdf: Kdf::V1,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Whether the payload is authenticated, as it is in every file with a header.
+    pub fn is_authenticated(&self) -> bool {
+        !self.is_legacy()
+    }
+
+    /// The header as written to the file, empty for legacy files.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.is_legacy() {
+            return bytes;
+        }
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.kdf.to_u8());
+        bytes
+    }
+}
+
+pub(crate) fn write_header(writer: &mut dyn Write, header: &Header) -> std::io::Result<()> {
+    writer.write_all(&header.to_bytes())
+}
+
+/// Reads the header.
+/// Returns `None` and rewinds if the input has no header.
+pub(crate) fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Option<Header>, Error> {
+    let mut buffer = [0u8; 9];
+    let read = read_full(reader, &mut buffer).map_err(|e| Error::new(ErrorKind::Io, e))?;
+    if read < buffer.len() || &buffer[..8] != MAGIC {
+        reader
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| Error::new(ErrorKind::Io, e))?;
+        return Ok(None);
+    }
+    let version = buffer[8];
+    let kdf = match version {
+        FORMAT_VERSION => {
+            let mut kdf = [0u8; 1];
+            reader.read_exact(&mut kdf).map_err(corrupted)?;
+            Kdf::from_u8(kdf[0]).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnsupportedVersion,
+                    format!("key file digest version {}", kdf[0]),
+                )
+            })?
+        }
+        v => {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                format!("format version {v}"),
+            ))
+        }
+    };
+    Ok(Some(Header { version, kdf }))
+}
+
+/// Information stored next to the payload, inside the encrypted region.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// File name of the encrypted input.
+    pub original_name: Option<String>,
+    /// Names of the inputs stored at the top of the archive, see `Pack::compression_roots`.
+    /// Empty if the archive holds the contents of a single input.
+    pub roots: Vec<String>,
+    /// Format of the packed payload. Not recorded for tar, the format of older files.
+    pub pack_format: PackFormat,
+    /// Whether the payload is followed by padding, see `Padding`.
+    pub padded: bool,
+    /// What the payload is. `None` for headerless files and files upgraded from them,
+    /// which are told apart by their contents.
+    pub payload: Option<Payload>,
+    /// Whether the file ends with a segment index, see `segment`. Set on the archives that entries
+    /// can be appended to, so that cutting off the index and the segments before it is detected.
+    pub indexed: bool,
+}
+
+impl Metadata {
+    pub(crate) fn write(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let mut records = Vec::new();
+        if let Some(name) = &self.original_name {
+            push_record(&mut records, TAG_ORIGINAL_NAME, name.as_bytes());
+        }
+        for root in &self.roots {
+            push_record(&mut records, TAG_ROOT, root.as_bytes());
+        }
+        if self.pack_format != PackFormat::Tar {
+            push_record(&mut records, TAG_PACK_FORMAT, &[self.pack_format.to_u8()]);
+        }
+        if self.padded {
+            push_record(&mut records, TAG_PADDED, &[]);
+        }
+        if let Some(payload) = self.payload {
+            push_record(&mut records, TAG_PAYLOAD, &[payload.to_u8()]);
+        }
+        if self.indexed {
+            push_record(&mut records, TAG_INDEXED, &[]);
+        }
+        writer.write_all(METADATA_MARKER)?;
+        writer.write_all(&(records.len() as u32).to_le_bytes())?;
+        writer.write_all(&records)
+    }
+
+    pub(crate) fn read(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut marker = [0u8; 4];
+        reader.read_exact(&mut marker).map_err(corrupted)?;
+        if &marker != METADATA_MARKER {
+            return Err(ErrorKind::WrongKey.into());
+        }
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).map_err(corrupted)?;
+        let len = u32::from_le_bytes(len);
+        if len > METADATA_MAX_LEN {
+            return Err(Error::new(ErrorKind::Corrupted, "metadata too large"));
+        }
+        let mut records = vec![0u8; len as usize];
+        reader.read_exact(&mut records).map_err(corrupted)?;
+
+        let mut metadata = Metadata::default();
+        let mut rest = records.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(Error::new(ErrorKind::Corrupted, "truncated metadata"));
+            }
+            let tag = rest[0];
+            let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            let Some(value) = rest.get(3..3 + len) else {
+                return Err(Error::new(ErrorKind::Corrupted, "truncated metadata"));
+            };
+            // unknown tags are skipped so that newer writers stay readable
+            match tag {
+                TAG_ORIGINAL_NAME => {
+                    metadata.original_name = Some(String::from_utf8_lossy(value).into_owned())
+                }
+                TAG_ROOT => metadata
+                    .roots
+                    .push(String::from_utf8_lossy(value).into_owned()),
+                TAG_PACK_FORMAT => {
+                    let format = value.first().copied().and_then(PackFormat::from_u8);
+                    metadata.pack_format = format.ok_or_else(|| {
+                        Error::new(ErrorKind::UnsupportedVersion, "unknown pack format")
+                    })?;
+                }
+                TAG_PADDED => metadata.padded = true,
+                TAG_PAYLOAD => {
+                    let payload = value.first().copied().and_then(Payload::from_u8);
+                    metadata.payload = Some(payload.ok_or_else(|| {
+                        Error::new(ErrorKind::UnsupportedVersion, "unknown payload")
+                    })?);
+                }
+                TAG_INDEXED => metadata.indexed = true,
+                _ => {}
+            }
+            rest = &rest[3 + len..];
+        }
+        Ok(metadata)
+    }
+}
+
+fn push_record(records: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    let value = &value[..value.len().min(u16::MAX as usize)];
+    records.push(tag);
+    records.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    records.extend_from_slice(value);
+}
+
+fn corrupted(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Corrupted, e)
+}
+
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// View of a stream that starts at its position when the view was created,
+/// so that `Pack` and `Crypto` implementations can seek without touching what lies before.
+pub(crate) struct Section<T> {
+    inner: T,
+    start: u64,
+    /// End of the view if it stops before the end of the stream.
+    end: Option<u64>,
+    position: u64,
+}
+
+impl<T: Seek> Section<T> {
+    pub(crate) fn new(mut inner: T) -> std::io::Result<Self> {
+        let start = inner.stream_position()?;
+        Ok(Self {
+            inner,
+            start,
+            end: None,
+            position: start,
+        })
+    }
+
+    /// Like `new`, but the view ends `len` bytes further, e.g. at the end of a segment.
+    pub(crate) fn with_len(inner: T, len: u64) -> std::io::Result<Self> {
+        let section = Self::new(inner)?;
+        Ok(Self {
+            end: Some(section.start + len),
+            ..section
+        })
+    }
+}
+
+impl<T: Read> Read for Section<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let buf = match self.end {
+            Some(end) => {
+                let left = end.saturating_sub(self.position);
+                let len = buf.len().min(left.try_into().unwrap_or(usize::MAX));
+                &mut buf[..len]
+            }
+            None => buf,
+        };
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Section<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Section<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        let pos = match (pos, self.end) {
+            (SeekFrom::Start(n), _) => SeekFrom::Start(self.start + n),
+            (SeekFrom::End(n), Some(end)) => SeekFrom::Start(
+                end.checked_add_signed(n)
+                    .ok_or_else(|| invalid("seek before the start of the section"))?,
+            ),
+            (pos, _) => pos,
+        };
+        self.position = self.inner.seek(pos)?;
+        if self.position < self.start {
+            self.position = self.inner.seek(SeekFrom::Start(self.start))?;
+            return Err(invalid("seek before the start of the section"));
+        }
+        Ok(self.position - self.start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Seek, Write};
+
+    use crate::{ErrorKind, Kdf, PackFormat, Payload};
+
+    use super::{read_header, write_header, Header, Metadata, Section};
+
+    #[test]
+    fn header_and_metadata_test() {
+        let metadata = Metadata {
+            original_name: Some("report.pdf".into()),
+            roots: vec!["project".into(), "notes.txt".into()],
+            pack_format: PackFormat::Zip,
+            padded: true,
+            payload: Some(Payload::Archive),
+            indexed: true,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        write_header(&mut buf, &Header::current()).unwrap();
+        metadata.write(&mut buf).unwrap();
+        buf.write_all(b"payload").unwrap();
+
+        buf.rewind().unwrap();
+        assert_eq!(Some(Header::current()), read_header(&mut buf).unwrap());
+        assert_eq!(metadata, Metadata::read(&mut buf).unwrap());
+
+        let mut section = Section::new(&mut buf).unwrap();
+        let mut payload = String::new();
+        section.read_to_string(&mut payload).unwrap();
+        section.rewind().unwrap();
+        section.read_to_string(&mut payload).unwrap();
+        assert_eq!("payloadpayload", payload);
+
+        // a section with an end stops reading there
+        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
+        let mut section = Section::with_len(&mut buf, 3).unwrap();
+        let mut head = String::new();
+        section.read_to_string(&mut head).unwrap();
+        assert_eq!(1, section.seek(std::io::SeekFrom::End(-2)).unwrap());
+        section.read_to_string(&mut head).unwrap();
+        assert_eq!("ENCNC", head);
+
+        // only the current version, older files have no header
+        for bytes in [
+            b"MKENCBOX\x03\x09",
+            b"MKENCBOX\x04\x02",
+            b"MKENCBOX\x02\x01",
+            b"MKENCBOX\x01\x01",
+            b"MKENCBOX\x00\x01",
+        ] {
+            let mut buf = Cursor::new(bytes.to_vec());
+            assert_eq!(
+                ErrorKind::UnsupportedVersion,
+                read_header(&mut buf).unwrap_err().kind()
+            );
+        }
+        let header = Header {
+            kdf: Kdf::V1,
+            ..Header::current()
+        };
+        let mut buf = Cursor::new(header.to_bytes());
+        assert_eq!(Some(header), read_header(&mut buf).unwrap());
+        assert!(Header::legacy().to_bytes().is_empty());
+
+        // no header
+        let mut buf = Cursor::new(b"legacy".to_vec());
+        assert_eq!(None, read_header(&mut buf).unwrap());
+        assert_eq!(0, buf.position());
+
+        // metadata decrypted with another key
+        let mut buf = Cursor::new(b"\x01\x02\x03\x04\x00\x00\x00\x00".to_vec());
+        assert_eq!(
+            ErrorKind::WrongKey,
+            Metadata::read(&mut buf).unwrap_err().kind()
+        );
+    }
+}
@@ -1,11 +1,30 @@
 mod algorithm;
+mod batch;
 mod crypto;
 mod error;
+mod format;
+mod output;
 mod pack;
+mod padding;
+mod parity;
+mod pipe;
 mod process;
+mod progress;
+mod segment;
+mod signature;
+mod volume;
 
 pub use algorithm::*;
+pub use batch::*;
 pub use crypto::*;
 pub use error::*;
+pub use format::*;
+pub use output::*;
 pub use pack::*;
+pub use padding::*;
+pub use parity::*;
 pub use process::*;
+pub use progress::*;
+pub use segment::*;
+pub use signature::*;
+pub use volume::*;
@@ -1,55 +1,213 @@
-use indicatif::ProgressStyle;
-use mkencbox::{Chacha20, Process, Tar};
+use std::{future::Future, path::Path, process::exit, sync::Arc, time::Duration};
+
+use indicatif::ProgressBar;
+use mkencbox::{Batch, Chacha20, Error, ErrorKind, Filters, Pack, PackFormat, Process, Tar, Zip};
 use tokio::sync::mpsc::channel;
 
 mod os_args;
+mod progress_bar;
+
+const CANCEL_GRACE: Duration = Duration::from_secs(3);
 
 #[tokio::main]
 async fn main() {
     let args = os_args::OsArgs::parse();
 
-    let pack_alg = Tar::new();
-    let crypto_alg = Box::new(Chacha20::new(args.salt, args.key_file));
-    let processor = Process::new(
-        args.process,
-        Box::new(pack_alg),
-        crypto_alg,
-        args.input,
-        args.output,
+    // shared by all inputs so that the key is derived once
+    let crypto_alg = Arc::new(
+        Chacha20::new(args.salt.as_deref().cloned(), &args.key_file)
+            .deterministic_nonce(args.deterministic_nonce),
     );
 
-    let (tx, mut rx) = channel(4);
-    let processor = if args.progress {
-        processor.bypass_progress(tx)
+    let code = if args.inputs.len() == 1 || args.bundle {
+        match run_single(&args, crypto_alg).await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("mkencbox: {e}");
+                e.kind().exit_code()
+            }
+        }
     } else {
-        processor
+        run_batch(&args, crypto_alg).await
+    };
+    exit(code);
+}
+
+fn process(args: &os_args::OsArgs, input: &Path, crypto_alg: Arc<Chacha20>) -> Process {
+    // either one unpacks, whichever the file was packed with
+    let filters = filters(args);
+    let (pack_alg, unpacker): (Box<dyn Pack>, Box<dyn Pack>) = match args.pack_format {
+        PackFormat::Tar => (
+            Box::new(tar(args).with_filters(filters.clone())),
+            Box::new(zip(args)),
+        ),
+        PackFormat::Zip => (
+            Box::new(zip(args).with_filters(filters.clone())),
+            Box::new(tar(args)),
+        ),
     };
+    match &args.output {
+        Some(output) => Process::new(args.process, pack_alg, Box::new(crypto_alg), input, output),
+        None => Process::with_default_output(
+            args.process,
+            pack_alg,
+            Box::new(crypto_alg),
+            input,
+            args.output_dir.clone(),
+        ),
+    }
+    .unpacker(unpacker)
+    .output_policy(args.output_policy)
+    .legacy(args.legacy)
+    .keep_root(args.keep_root)
+    .mirror(args.mirror)
+    .filters(filters)
+    .encrypt_names(args.encrypt_names)
+    .volume_size(args.volume_size)
+    .padding(args.padding)
+    .parity(args.parity)
+    .signing_key(args.signing_key.clone())
+    .trusted_keys(args.trusted_keys.clone())
+    .siblings(match args.bundle {
+        true => args.inputs[1..].to_vec(),
+        false => Vec::new(),
+    })
+}
+
+fn filters(args: &os_args::OsArgs) -> Filters {
+    let filters = Filters::new()
+        .symlinks(args.symlinks)
+        .gitignore(args.gitignore)
+        .one_file_system(args.one_file_system);
+    let filters = args
+        .includes
+        .iter()
+        .fold(filters, |filters, glob| filters.include(glob));
+    args.excludes
+        .iter()
+        .fold(filters, |filters, glob| filters.exclude(glob))
+}
+
+fn tar(args: &os_args::OsArgs) -> Tar {
+    Tar::new()
+        .preserve_permissions(args.preserve_permissions)
+        .preserve_mtime(args.preserve_mtime)
+        .preserve_ownership(args.preserve_ownership)
+        .xattrs(args.xattrs)
+        .reproducible(args.reproducible)
+        .max_size(args.max_size)
+        .max_entries(args.max_entries)
+}
+
+fn zip(args: &os_args::OsArgs) -> Zip {
+    Zip::new()
+        .compression_method(args.zip_compression)
+        .preserve_mtime(args.preserve_mtime)
+        .reproducible(args.reproducible)
+        .max_size(args.max_size)
+        .max_entries(args.max_entries)
+}
+
+async fn run_single(args: &os_args::OsArgs, crypto_alg: Arc<Chacha20>) -> Result<(), Error> {
+    let processor = process(args, &args.inputs[0], crypto_alg);
+
+    // events are always received so that warnings are printed
+    let (tx, rx) = channel(64);
+    let processor = processor.bypass_progress(tx);
 
     let run_progress = args.progress;
     let handle = tokio::spawn(async move {
-        if !run_progress {
-            return;
-        }
-        let p_size = 100;
-        let pb = indicatif::ProgressBar::new(p_size);
-        pb.set_style(
-            ProgressStyle::with_template("{spinner:.green} [{bar:.cyan/blue}] {pos}% ").unwrap(),
-        );
-        let mut current = 0;
-        while let Some(p) = rx.recv().await {
-            let inc = ((p as f64 / u8::MAX as f64) * p_size as f64).ceil() as u64;
-            pb.inc(inc - current);
-            current = inc;
+        if run_progress {
+            progress_bar::run(rx).await;
+        } else {
+            progress_bar::warnings(rx, ProgressBar::hidden()).await;
         }
-        pb.finish_with_message("done");
     });
 
-    match processor.execute().await {
-        Ok(_) => {
-            handle.abort();
-        }
+    cancellable(processor.execute()).await?;
+    let _ = handle.await;
+    Ok(())
+}
+
+/// Processes every input and prints a report. Returns the exit code of the first failure.
+async fn run_batch(args: &os_args::OsArgs, crypto_alg: Arc<Chacha20>) -> i32 {
+    let (tx, rx) = channel(64);
+    let mut batch = Batch::new(args.jobs);
+    for input in &args.inputs {
+        batch.push(process(args, input, crypto_alg.clone()).bypass_progress(tx.clone()));
+    }
+    drop(tx);
+
+    let pb = if args.progress {
+        progress_bar::items(batch.len() as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    let handle = tokio::spawn(progress_bar::warnings(rx, pb.clone()));
+    let execute = async {
+        Ok(batch
+            .execute(|item| {
+                pb.set_message(item.input.display().to_string());
+                pb.inc(1);
+            })
+            .await)
+    };
+    let items = match cancellable(execute).await {
+        Ok(items) => items,
         Err(e) => {
-            panic!("{e:?}");
+            pb.finish_and_clear();
+            eprintln!("mkencbox: {e}");
+            return e.kind().exit_code();
+        }
+    };
+    let _ = handle.await;
+    pb.finish_and_clear();
+
+    let mut code = 0;
+    let mut failed = 0;
+    for item in &items {
+        match &item.result {
+            Ok(output) => println!("ok      {} -> {}", item.input.display(), output.display()),
+            Err(e) => {
+                println!("failed  {}: {e}", item.input.display());
+                failed += 1;
+                if code == 0 {
+                    code = e.kind().exit_code();
+                }
+            }
         }
     }
+    println!("{} succeeded, {failed} failed", items.len() - failed);
+    code
+}
+
+/// Awaits `execute`, cancelling it on SIGINT or SIGTERM.
+async fn cancellable<T>(execute: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    tokio::pin!(execute);
+    tokio::select! {
+        r = &mut execute => r,
+        _ = shutdown_signal() => {
+            // let the running processes unwind and remove their partial outputs
+            mkencbox::cancel();
+            let _ = tokio::time::timeout(CANCEL_GRACE, &mut execute).await;
+            mkencbox::remove_partial_outputs();
+            Err(ErrorKind::Cancelled.into())
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
@@ -1,31 +1,129 @@
 use clap::{crate_version, Arg, ArgAction, Command};
-use mkencbox::Target;
-use std::{
-    io::{BufReader, Read},
-    path::PathBuf,
-    process::exit,
+use mkencbox::{
+    ConflictPolicy, Error, OutputPolicy, PackFormat, Padding, SigningKey, SymlinkPolicy, Target,
+    TrustedKeys, ZipCompression,
 };
+use std::{path::PathBuf, process::exit, sync::Arc};
+use zeroize::Zeroizing;
 
-#[derive(Debug)]
 pub struct OsArgs {
-    pub salt: Option<String>,
+    pub salt: Option<Zeroizing<String>>,
     pub process: Target,
     pub key_file: PathBuf,
-    pub input: PathBuf,
-    pub output: PathBuf,
+    pub inputs: Vec<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
     pub progress: bool,
+    pub output_policy: OutputPolicy,
+    pub jobs: usize,
+    pub legacy: bool,
+    pub symlinks: SymlinkPolicy,
+    pub preserve_permissions: bool,
+    pub preserve_mtime: bool,
+    pub preserve_ownership: bool,
+    pub xattrs: bool,
+    pub includes: Vec<String>,
+    pub excludes: Vec<String>,
+    pub gitignore: bool,
+    pub one_file_system: bool,
+    pub reproducible: bool,
+    pub deterministic_nonce: bool,
+    pub keep_root: bool,
+    /// All inputs go into one archive, `output`.
+    pub bundle: bool,
+    pub max_size: Option<u64>,
+    pub max_entries: Option<u64>,
+    pub pack_format: PackFormat,
+    pub zip_compression: ZipCompression,
+    pub mirror: bool,
+    pub encrypt_names: bool,
+    pub volume_size: Option<u64>,
+    pub padding: Padding,
+    pub parity: Option<u8>,
+    pub signing_key: Option<Arc<SigningKey>>,
+    pub trusted_keys: Option<Arc<TrustedKeys>>,
 }
 
 const APP_NAME: &str = "mkencbox";
 
+impl std::fmt::Debug for OsArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the salt and the signing key are secrets
+        f.debug_struct("OsArgs")
+            .field("process", &self.process)
+            .field("key_file", &self.key_file)
+            .field("inputs", &self.inputs)
+            .field("output", &self.output)
+            .field("output_dir", &self.output_dir)
+            .field("progress", &self.progress)
+            .field("output_policy", &self.output_policy)
+            .field("jobs", &self.jobs)
+            .field("legacy", &self.legacy)
+            .field("symlinks", &self.symlinks)
+            .field("preserve_permissions", &self.preserve_permissions)
+            .field("preserve_mtime", &self.preserve_mtime)
+            .field("preserve_ownership", &self.preserve_ownership)
+            .field("xattrs", &self.xattrs)
+            .field("includes", &self.includes)
+            .field("excludes", &self.excludes)
+            .field("gitignore", &self.gitignore)
+            .field("one_file_system", &self.one_file_system)
+            .field("reproducible", &self.reproducible)
+            .field("deterministic_nonce", &self.deterministic_nonce)
+            .field("keep_root", &self.keep_root)
+            .field("bundle", &self.bundle)
+            .field("max_size", &self.max_size)
+            .field("max_entries", &self.max_entries)
+            .field("pack_format", &self.pack_format)
+            .field("zip_compression", &self.zip_compression)
+            .field("mirror", &self.mirror)
+            .field("encrypt_names", &self.encrypt_names)
+            .field("volume_size", &self.volume_size)
+            .field("padding", &self.padding)
+            .field("parity", &self.parity)
+            .field("trusted_keys", &self.trusted_keys)
+            .finish_non_exhaustive()
+    }
+}
+
 impl OsArgs {
     pub fn parse() -> Self {
         const ID_SALT: &str = "SALT";
         const ID_PROCESS: &str = "PROCESS";
         const ID_KEY_FILE: &str = "KEY_FILE";
         const ID_INFILE: &str = "INPUT";
-        const ID_OUTFILE: &str = "OUTPUT";
         const ID_PROGRESS: &str = "PROGRESS";
+        const ID_OUTPUT: &str = "OUTPUT";
+        const ID_OUTPUT_DIR: &str = "OUTPUT_DIR";
+        const ID_FORCE: &str = "FORCE";
+        const ID_MERGE: &str = "MERGE";
+        const ID_ON_CONFLICT: &str = "ON_CONFLICT";
+        const ID_JOBS: &str = "JOBS";
+        const ID_LEGACY: &str = "LEGACY";
+        const ID_SYMLINKS: &str = "SYMLINKS";
+        const ID_PERMISSIONS: &str = "PERMISSIONS";
+        const ID_NO_MTIME: &str = "NO_MTIME";
+        const ID_NO_OWNER: &str = "NO_OWNER";
+        const ID_XATTRS: &str = "XATTRS";
+        const ID_INCLUDE: &str = "INCLUDE";
+        const ID_EXCLUDE: &str = "EXCLUDE";
+        const ID_GITIGNORE: &str = "GITIGNORE";
+        const ID_ONE_FILE_SYSTEM: &str = "ONE_FILE_SYSTEM";
+        const ID_REPRODUCIBLE: &str = "REPRODUCIBLE";
+        const ID_DETERMINISTIC_NONCE: &str = "DETERMINISTIC_NONCE";
+        const ID_KEEP_ROOT: &str = "KEEP_ROOT";
+        const ID_BUNDLE: &str = "BUNDLE";
+        const ID_MAX_SIZE: &str = "MAX_SIZE";
+        const ID_MAX_ENTRIES: &str = "MAX_ENTRIES";
+        const ID_PACK: &str = "PACK";
+        const ID_ZIP_STORE: &str = "ZIP_STORE";
+        const ID_MIRROR: &str = "MIRROR";
+        const ID_ENCRYPT_NAMES: &str = "ENCRYPT_NAMES";
+        const ID_VOLUME_SIZE: &str = "VOLUME_SIZE";
+        const ID_PAD: &str = "PAD";
+        const ID_PARITY: &str = "PARITY";
+        const ID_SIGN: &str = "SIGN";
+        const ID_TRUSTED_KEYS: &str = "TRUSTED_KEYS";
 
         let command = Command::new(APP_NAME)
             .version(crate_version!())
@@ -43,48 +141,263 @@ impl OsArgs {
                     .long("progress")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new(ID_OUTPUT)
+                    .help(
+                        "Output name of a single input [default: INPUT.enc, the original name \
+                         when decrypting or checking, or INPUT itself when upgrading or \
+                         repairing]. When appending, the archive appended to",
+                    )
+                    .long("output")
+                    .short('o')
+                    .conflicts_with(ID_OUTPUT_DIR),
+            )
+            .arg(
+                Arg::new(ID_OUTPUT_DIR)
+                    .help("Directory for the outputs, named after their inputs")
+                    .long("output-dir"),
+            )
+            .arg(
+                Arg::new(ID_JOBS)
+                    .help("Number of inputs processed concurrently [default: number of CPUs]")
+                    .long("jobs")
+                    .short('j')
+                    .value_parser(clap::value_parser!(usize)),
+            )
+            .arg(
+                Arg::new(ID_FORCE)
+                    .help("Replace the output if it exists")
+                    .long("force")
+                    .short('f')
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with(ID_MERGE),
+            )
+            .arg(
+                Arg::new(ID_MERGE)
+                    .help("Decrypt into an existing output directory")
+                    .long("merge")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_ON_CONFLICT)
+                    .help("What to do with files that already exist when merging")
+                    .long("on-conflict")
+                    .requires(ID_MERGE)
+                    .value_parser(["skip", "overwrite", "overwrite-if-newer", "rename"])
+                    .default_value("skip"),
+            )
+            .arg(
+                Arg::new(ID_LEGACY)
+                    .help("Read inputs as headerless mkencbox 2.0 files")
+                    .long("legacy")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_PACK)
+                    .help("Archive format for directories, recorded so that decrypting needs no option")
+                    .long("pack")
+                    .value_parser(["tar", "zip"])
+                    .default_value("tar"),
+            )
+            .arg(
+                Arg::new(ID_ZIP_STORE)
+                    .help("Store files in zip archives without compressing them")
+                    .long("zip-store")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_SYMLINKS)
+                    .help("What to do with symbolic links in directories")
+                    .long("symlinks")
+                    .value_parser(["store", "follow", "skip"])
+                    .default_value("store"),
+            )
+            .arg(
+                Arg::new(ID_PERMISSIONS)
+                    .help("Also restore setuid, setgid and sticky bits")
+                    .long("permissions")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_NO_MTIME)
+                    .help("Do not restore modification times")
+                    .long("no-mtime")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_NO_OWNER)
+                    .help("Do not restore owners when running as root")
+                    .long("no-owner")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_XATTRS)
+                    .help("Store and restore extended attributes, ACLs and SELinux labels")
+                    .long("xattrs")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_INCLUDE)
+                    .help("Only store files in directories matching a glob, may be repeated")
+                    .long("include")
+                    .value_name("GLOB")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new(ID_EXCLUDE)
+                    .help("Leave out entries of directories matching a glob, may be repeated")
+                    .long("exclude")
+                    .value_name("GLOB")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new(ID_GITIGNORE)
+                    .help("Also leave out entries listed in .gitignore files")
+                    .long("gitignore")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_ONE_FILE_SYSTEM)
+                    .help("Do not descend into directories on other file systems")
+                    .long("one-file-system")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_REPRODUCIBLE)
+                    .help("Pack directories in name order without modification times, owners or exact modes, so that the same tree gives the same archive")
+                    .long("reproducible")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_DETERMINISTIC_NONCE)
+                    .help("Derive the nonce from the key and the input, so that the same input gives the same encrypted file. Reveals which encrypted files are equal")
+                    .long("deterministic-nonce")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_KEEP_ROOT)
+                    .help("Store directories under their own name, so that decrypting recreates them inside the output directory")
+                    .long("keep-root")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(ID_BUNDLE)
+                    .help("Encrypt all inputs side by side into one archive, as with --keep-root, or append them to it")
+                    .long("bundle")
+                    .value_name("OUTPUT")
+                    .conflicts_with_all([ID_OUTPUT, ID_OUTPUT_DIR]),
+            )
+            .arg(
+                Arg::new(ID_MIRROR)
+                    .help("Encrypt directories file by file into a directory, updating only changed files")
+                    .long("mirror")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all([ID_KEEP_ROOT, ID_BUNDLE]),
+            )
+            .arg(
+                Arg::new(ID_ENCRYPT_NAMES)
+                    .help("Also encrypt file and directory names in mirrors")
+                    .long("encrypt-names")
+                    .action(ArgAction::SetTrue)
+                    .requires(ID_MIRROR),
+            )
+            .arg(
+                Arg::new(ID_VOLUME_SIZE)
+                    .help("Split encrypted files into volumes of this size, e.g. 4G, named OUTPUT.001, OUTPUT.002, ...")
+                    .long("volume-size")
+                    .value_name("SIZE")
+                    .value_parser(parse_volume_size)
+                    .conflicts_with(ID_MIRROR),
+            )
+            .arg(
+                Arg::new(ID_PAD)
+                    .help("Pad encrypted files to hide their exact size: padme, or a size to round up to, e.g. 1M")
+                    .long("pad")
+                    .value_name("PADDING")
+                    .value_parser(parse_padding),
+            )
+            .arg(
+                Arg::new(ID_PARITY)
+                    .help("Write Reed-Solomon parity of this percent of the size to OUTPUT.par, for repair")
+                    .long("parity")
+                    .value_name("PERCENT")
+                    .value_parser(clap::value_parser!(u8).range(1..=100))
+                    .conflicts_with(ID_VOLUME_SIZE),
+            )
+            .arg(
+                Arg::new(ID_SIGN)
+                    .help("Sign encrypted files with the Ed25519 key in this file, 64 hex digits, into OUTPUT.sig")
+                    .long("sign")
+                    .value_name("KEY_FILE")
+                    .conflicts_with(ID_MIRROR),
+            )
+            .arg(
+                Arg::new(ID_TRUSTED_KEYS)
+                    .help("Only decrypt files signed by one of the public keys in this file, one per line")
+                    .long("trusted-keys")
+                    .value_name("FILE")
+                    .conflicts_with(ID_MIRROR),
+            )
+            .arg(
+                Arg::new(ID_MAX_SIZE)
+                    .help("Refuse to unpack archives with more data, e.g. 500G, or none")
+                    .long("max-size")
+                    .value_name("SIZE")
+                    .value_parser(|v: &str| parse_limit(v, parse_size))
+                    .default_value("1T"),
+            )
+            .arg(
+                Arg::new(ID_MAX_ENTRIES)
+                    .help("Refuse to unpack archives with more entries, or none")
+                    .long("max-entries")
+                    .value_name("COUNT")
+                    .value_parser(|v: &str| parse_limit(v, |v| v.parse().map_err(|e| format!("{e}"))))
+                    .default_value("10000000"),
+            )
             .arg(
                 Arg::new(ID_PROCESS)
-                    .help("Encrypt or decrypt process")
+                    .help("Encrypt or decrypt process, upgrade to the current format, repair with INPUT.par, verify the signature in INPUT.sig, check restored files against INPUT, or append INPUT to an encrypted archive")
                     .required(true)
-                    .value_parser(["enc", "dec"]),
+                    .value_parser(["enc", "dec", "upgrade", "repair", "verify", "check", "append"]),
             )
             .arg(Arg::new(ID_KEY_FILE).help("Key file path").required(true))
-            .arg(Arg::new(ID_INFILE).help("Input name").required(true))
-            .arg(Arg::new(ID_OUTFILE).help("Output name"))
+            .arg(
+                Arg::new(ID_INFILE)
+                    .value_name("INPUT")
+                    .help("Input names, see --output for their outputs")
+                    .required(true)
+                    .num_args(1..),
+            )
             .get_matches();
 
-        let salt = command.get_one::<String>(ID_SALT).map(String::from);
+        let salt = command
+            .get_one::<String>(ID_SALT)
+            .map(|s| Zeroizing::new(s.clone()));
 
-        let input_file = PathBuf::from(command.get_one::<String>(ID_INFILE).unwrap());
+        let output_dir = command.get_one::<String>(ID_OUTPUT_DIR).map(PathBuf::from);
+        let inputs: Vec<PathBuf> = command
+            .get_many::<String>(ID_INFILE)
+            .unwrap()
+            .map(PathBuf::from)
+            .collect();
+        let bundle = command.get_one::<String>(ID_BUNDLE).map(PathBuf::from);
+        let output_file = match &bundle {
+            Some(_) => bundle.clone(),
+            None => command.get_one::<String>(ID_OUTPUT).map(PathBuf::from),
+        };
+        if bundle.is_none() && output_file.is_some() && inputs.len() > 1 {
+            eprintln!("{APP_NAME}: --output takes a single input, use --output-dir or --bundle");
+            exit(2);
+        }
         let process = match command.get_one::<String>(ID_PROCESS) {
             Some(v) => match v.as_str() {
                 "enc" => Target::Enc,
                 "dec" => Target::Dec,
-                "auto" => {
-                    if input_file.is_file() {
-                        let file = std::fs::File::open(&input_file).unwrap();
-                        let mut reader = BufReader::new(file);
-                        let mut buffer = [0u8; 8];
-                        match reader.read(&mut buffer) {
-                            Ok(read) => {
-                                if read == 8 {
-                                    let salt_str = "Salted__".as_bytes();
-                                    if buffer.to_vec() == salt_str.to_vec() {
-                                        Target::Dec
-                                    } else {
-                                        Target::Enc
-                                    }
-                                } else {
-                                    Target::Enc
-                                }
-                            }
-                            Err(_) => Target::Enc,
-                        }
-                    } else {
-                        Target::Enc
-                    }
-                }
+                "upgrade" => Target::Upgrade,
+                "repair" => Target::Repair,
+                "verify" => Target::Verify,
+                "check" => Target::Check,
+                "append" => Target::Append,
                 _ => {
                     exit(1);
                 }
@@ -94,38 +407,163 @@ impl OsArgs {
             }
         };
 
+        if bundle.is_some() && !matches!(process, Target::Enc | Target::Append) {
+            eprintln!("{APP_NAME}: --bundle only applies to enc and append");
+            exit(2);
+        }
+
         let key_file = command.get_one::<String>(ID_KEY_FILE).unwrap();
-        let output_file = match command.get_one::<String>(ID_OUTFILE) {
-            Some(s) => PathBuf::from(s.clone()),
-            None => match process {
-                Target::Enc => {
-                    let mut s = input_file.clone();
-                    let path_str = s.to_str().unwrap();
-                    let mut path_str = path_str.trim_end_matches("/").to_string();
-                    path_str.push_str(".enc");
-                    s = PathBuf::from(path_str);
-                    s
-                }
-                Target::Dec => {
-                    let mut s = input_file.clone();
-                    let path_str = s.to_str().unwrap();
-                    let mut path_str = path_str.trim_end_matches("/").to_string();
-                    path_str.push_str(".dec");
-                    s = PathBuf::from(path_str);
-                    s
-                }
-            },
-        };
 
         let progress = command.get_flag(ID_PROGRESS);
 
+        let output_policy = if command.get_flag(ID_FORCE) {
+            OutputPolicy::Overwrite
+        } else if command.get_flag(ID_MERGE) {
+            let conflict = match command
+                .get_one::<String>(ID_ON_CONFLICT)
+                .map(String::as_str)
+            {
+                Some("overwrite") => ConflictPolicy::Overwrite,
+                Some("overwrite-if-newer") => ConflictPolicy::OverwriteIfNewer,
+                Some("rename") => ConflictPolicy::Rename,
+                _ => ConflictPolicy::Skip,
+            };
+            OutputPolicy::Merge(conflict)
+        } else {
+            OutputPolicy::Fail
+        };
+
+        let jobs = command
+            .get_one::<usize>(ID_JOBS)
+            .copied()
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+
+        let symlinks = match command.get_one::<String>(ID_SYMLINKS).map(String::as_str) {
+            Some("follow") => SymlinkPolicy::Follow,
+            Some("skip") => SymlinkPolicy::Skip,
+            _ => SymlinkPolicy::Store,
+        };
+
+        let pack_format = match command.get_one::<String>(ID_PACK).map(String::as_str) {
+            Some("zip") => PackFormat::Zip,
+            _ => PackFormat::Tar,
+        };
+        let zip_compression = match command.get_flag(ID_ZIP_STORE) {
+            true => ZipCompression::Stored,
+            false => ZipCompression::Deflated,
+        };
+
+        let globs = |id: &str| -> Vec<String> {
+            command
+                .get_many::<String>(id)
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default()
+        };
+
         OsArgs {
             salt,
             process,
             key_file: PathBuf::from(key_file),
-            input: input_file,
+            inputs,
             output: output_file,
+            output_dir,
             progress,
+            output_policy,
+            jobs,
+            legacy: command.get_flag(ID_LEGACY),
+            symlinks,
+            preserve_permissions: command.get_flag(ID_PERMISSIONS),
+            preserve_mtime: !command.get_flag(ID_NO_MTIME),
+            preserve_ownership: !command.get_flag(ID_NO_OWNER),
+            xattrs: command.get_flag(ID_XATTRS),
+            includes: globs(ID_INCLUDE),
+            excludes: globs(ID_EXCLUDE),
+            keep_root: command.get_flag(ID_KEEP_ROOT),
+            bundle: bundle.is_some(),
+            max_size: *command.get_one::<Option<u64>>(ID_MAX_SIZE).unwrap(),
+            max_entries: *command.get_one::<Option<u64>>(ID_MAX_ENTRIES).unwrap(),
+            gitignore: command.get_flag(ID_GITIGNORE),
+            one_file_system: command.get_flag(ID_ONE_FILE_SYSTEM),
+            reproducible: command.get_flag(ID_REPRODUCIBLE),
+            deterministic_nonce: command.get_flag(ID_DETERMINISTIC_NONCE),
+            pack_format,
+            zip_compression,
+            mirror: command.get_flag(ID_MIRROR),
+            encrypt_names: command.get_flag(ID_ENCRYPT_NAMES),
+            volume_size: command.get_one::<u64>(ID_VOLUME_SIZE).copied(),
+            padding: command
+                .get_one::<Padding>(ID_PAD)
+                .copied()
+                .unwrap_or_default(),
+            parity: command.get_one::<u8>(ID_PARITY).copied(),
+            signing_key: command
+                .get_one::<String>(ID_SIGN)
+                .map(|path| Arc::new(or_exit(SigningKey::from_file(path)))),
+            trusted_keys: command
+                .get_one::<String>(ID_TRUSTED_KEYS)
+                .map(|path| Arc::new(or_exit(TrustedKeys::from_file(path)))),
         }
     }
 }
+
+/// Reports a failure to read a file named by an option.
+fn or_exit<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{APP_NAME}: {e}");
+        exit(e.kind().exit_code())
+    })
+}
+
+/// Parses a number of bytes with an optional binary suffix, e.g. `1500`, `64K`, `700M` or `2G`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix {c:?}, use K, M, G or T")),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{e}"))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("{value} is too large"))
+}
+
+/// Like `parse_size`, but at least 1K so that volumes hold more than their header.
+fn parse_volume_size(value: &str) -> Result<u64, String> {
+    match parse_size(value)? {
+        size if size < 1 << 10 => Err(format!(
+            "volumes of {size} bytes are too small, use 1K or more"
+        )),
+        size => Ok(size),
+    }
+}
+
+/// `padme`, or a size for `Padding::Bucket`.
+fn parse_padding(value: &str) -> Result<Padding, String> {
+    match value {
+        "padme" => Ok(Padding::Padme),
+        v => match parse_size(v)? {
+            0 => Err("padding to multiples of 0 bytes".into()),
+            size => Ok(Padding::Bucket(size)),
+        },
+    }
+}
+
+/// `none` for no limit, otherwise parsed by `parse`.
+fn parse_limit(value: &str, parse: fn(&str) -> Result<u64, String>) -> Result<Option<u64>, String> {
+    match value {
+        "none" => Ok(None),
+        v => parse(v).map(Some),
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    ffi::OsString,
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, symlink_metadata},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{error::PathContext, Error, ErrorKind, Target};
+
+/// What to do when the output path of a `Process` already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputPolicy {
+    /// Fail with `ErrorKind::OutputExists`.
+    #[default]
+    Fail,
+    /// Replace the existing output.
+    Overwrite,
+    /// Move decrypted entries into the existing directory, resolving each conflicting file
+    /// with the given policy. Behaves like `Fail` when encrypting.
+    Merge(ConflictPolicy),
+}
+
+/// How `OutputPolicy::Merge` resolves an entry that already exists in the output directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the existing file.
+    #[default]
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Replace the existing file only if the unpacked one has a later modification time.
+    OverwriteIfNewer,
+    /// Keep both, storing the unpacked file as `name (1).ext`, `name (2).ext`, ...
+    Rename,
+}
+
+/// Output path used when none is given.
+///
+/// Encrypting `INPUT` gives `INPUT.enc`. Decrypting restores `original_name` as stored at
+/// encryption time, or strips a trailing `.enc` from `INPUT`. The output is placed in
+/// `output_dir` if given, otherwise next to `INPUT`. A name that would replace `INPUT`
+/// itself gets a `.dec` suffix instead, except for upgrades and repairs which keep the name of `INPUT`.
+pub fn default_output_path(
+    target: Target,
+    input: &Path,
+    original_name: Option<&str>,
+    output_dir: Option<&Path>,
+) -> PathBuf {
+    let input_name = input.file_name().unwrap_or(input.as_os_str());
+    let name = match target {
+        Target::Enc | Target::Append => {
+            let mut name = input_name.to_os_string();
+            name.push(".enc");
+            name
+        }
+        Target::Dec | Target::Check => match original_name.and_then(|n| Path::new(n).file_name()) {
+            Some(name) => name.to_os_string(),
+            None => {
+                let name = input_name.to_string_lossy();
+                match name.strip_suffix(".enc") {
+                    Some(stem) if !stem.is_empty() => OsString::from(stem),
+                    _ => {
+                        let mut name = input_name.to_os_string();
+                        name.push(".dec");
+                        name
+                    }
+                }
+            }
+        },
+        Target::Upgrade | Target::Repair | Target::Verify => input_name.to_os_string(),
+    };
+
+    let dir = match output_dir {
+        Some(dir) => dir,
+        None => input.parent().unwrap_or(Path::new("")),
+    };
+    let output = dir.join(&name);
+    // upgrades and repairs replace their input
+    if !matches!(target, Target::Upgrade | Target::Repair) && is_same_path(&output, input) {
+        let mut name = name;
+        name.push(".dec");
+        return dir.join(name);
+    }
+    output
+}
+
+pub(crate) fn is_same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Asks every running `Process` in this program to stop.
+/// They fail with `ErrorKind::Cancelled` and remove their partial outputs while unwinding.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Removes all partial outputs and temporary files that are still registered.
+/// Call this right before exiting when running processes could not unwind in time.
+pub fn remove_partial_outputs() {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    for path in pending.drain(..) {
+        remove_path(&path);
+    }
+}
+
+pub(crate) fn cancelled_io_error() -> std::io::Error {
+    std::io::Error::other(Error::from(ErrorKind::Cancelled))
+}
+
+/// A file or directory that is removed on drop unless it is persisted.
+pub(crate) struct Partial {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl Partial {
+    pub(crate) fn register(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        PENDING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(path.clone());
+        Self {
+            path,
+            persisted: false,
+        }
+    }
+
+    /// Reserves a hidden path next to `target` so that it can be renamed to `target` atomically.
+    pub(crate) fn sibling(target: &Path) -> Self {
+        let mut name = OsString::from(".");
+        name.push(target.file_name().unwrap_or_default());
+        name.push(format!(".{:08x}.partial", rand::random::<u32>()));
+        let parent = match target.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        Self::register(parent.join(name))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn persist(mut self, to: &Path) -> std::io::Result<()> {
+        rename(&self.path, to)?;
+        self.persisted = true;
+        Ok(())
+    }
+
+    /// Moves the partial output to `to`, applying `policy` if `to` already exists.
+    pub(crate) fn persist_with(self, to: &Path, policy: OutputPolicy) -> Result<(), Error> {
+        if symlink_metadata(to).is_err() {
+            return self.persist(to).with_path(to);
+        }
+        match policy {
+            OutputPolicy::Fail => Err(Error::from(ErrorKind::OutputExists).with_path(to)),
+            OutputPolicy::Overwrite => self.replace(to),
+            OutputPolicy::Merge(conflict) => merge(&self.path, to, conflict),
+        }
+    }
+
+    /// Moves each entry of the partial directory into the directory `to`, applying `policy` per entry.
+    pub(crate) fn persist_children(self, to: &Path, policy: OutputPolicy) -> Result<(), Error> {
+        for entry in read_dir(&self.path).with_path(&self.path)? {
+            let entry = entry.with_path(&self.path)?;
+            Partial::register(entry.path()).persist_with(&to.join(entry.file_name()), policy)?;
+        }
+        Ok(())
+    }
+
+    fn replace(self, to: &Path) -> Result<(), Error> {
+        if self.path.is_file() && to.is_file() {
+            return self.persist(to).with_path(to);
+        }
+        // directories cannot be swapped atomically, so keep the old one aside until the new one is in place
+        let backup = Partial::sibling(to);
+        rename(to, backup.path()).with_path(to)?;
+        if let Err(e) = rename(&self.path, to) {
+            let _ = rename(backup.path(), to);
+            return Err(Error::io(e, to));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = pending.iter().position(|p| p == &self.path) {
+            pending.swap_remove(i);
+        }
+        if !self.persisted {
+            remove_path(&self.path);
+        }
+    }
+}
+
+fn merge(from: &Path, to: &Path, conflict: ConflictPolicy) -> Result<(), Error> {
+    let to_meta = match symlink_metadata(to) {
+        Ok(meta) => meta,
+        Err(_) => {
+            if let Some(parent) = to.parent() {
+                create_dir_all(parent).with_path(parent)?;
+            }
+            return rename(from, to).with_path(to);
+        }
+    };
+
+    if from.is_dir() && to_meta.is_dir() {
+        for entry in read_dir(from).with_path(from)? {
+            let entry = entry.with_path(from)?;
+            merge(&entry.path(), &to.join(entry.file_name()), conflict)?;
+        }
+        return Ok(());
+    }
+
+    match conflict {
+        ConflictPolicy::Skip => Ok(()),
+        ConflictPolicy::Overwrite => replace_path(from, to),
+        ConflictPolicy::OverwriteIfNewer => {
+            let from_modified = symlink_metadata(from).and_then(|m| m.modified());
+            let to_modified = to_meta.modified();
+            match (from_modified, to_modified) {
+                (Ok(f), Ok(t)) if f > t => replace_path(from, to),
+                _ => Ok(()),
+            }
+        }
+        ConflictPolicy::Rename => {
+            let renamed = free_name(to);
+            rename(from, &renamed).with_path(renamed)
+        }
+    }
+}
+
+fn replace_path(from: &Path, to: &Path) -> Result<(), Error> {
+    if to.is_dir() {
+        remove_dir_all(to).with_path(to)?;
+    }
+    rename(from, to).with_path(to)
+}
+
+/// Returns the first of `stem (1).ext`, `stem (2).ext`, ... that does not exist yet.
+fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|i| path.with_file_name(format!("{stem} ({i}){ext}")))
+        .find(|p| symlink_metadata(p).is_err())
+        .unwrap()
+}
+
+fn remove_path(path: &Path) {
+    if path.is_dir() {
+        let _ = remove_dir_all(path);
+    } else {
+        let _ = remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{create_dir, read_to_string, write, File},
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use tempfile::tempdir;
+
+    use crate::Target;
+
+    use super::{default_output_path, ConflictPolicy, OutputPolicy, Partial};
+
+    #[test]
+    fn default_output_path_test() {
+        let input = Path::new("backup/report.pdf");
+        assert_eq!(
+            Path::new("backup/report.pdf.enc"),
+            default_output_path(Target::Enc, input, None, None)
+        );
+        assert_eq!(
+            Path::new("out/report.pdf.enc"),
+            default_output_path(
+                Target::Enc,
+                Path::new("backup/report.pdf/"),
+                None,
+                Some(Path::new("out"))
+            )
+        );
+
+        let input = Path::new("backup/report.pdf.enc");
+        assert_eq!(
+            Path::new("backup/report.pdf"),
+            default_output_path(Target::Dec, input, None, None)
+        );
+        assert_eq!(
+            Path::new("backup/2026.pdf"),
+            default_output_path(Target::Dec, input, Some("2026.pdf"), None)
+        );
+        assert_eq!(
+            Path::new("out/passwd"),
+            default_output_path(
+                Target::Dec,
+                input,
+                Some("/etc/passwd"),
+                Some(Path::new("out"))
+            )
+        );
+        assert_eq!(
+            input,
+            default_output_path(Target::Upgrade, input, None, None)
+        );
+        assert_eq!(
+            Path::new("backup/report.dec"),
+            default_output_path(Target::Dec, Path::new("backup/report"), None, None)
+        );
+        assert_eq!(
+            Path::new("backup/report.pdf.dec"),
+            default_output_path(
+                Target::Dec,
+                Path::new("backup/report.pdf"),
+                Some("report.pdf"),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn partial_test() {
+        let td = tempdir().unwrap();
+        let target = td.path().join("out.enc");
+
+        let partial = Partial::sibling(&target);
+        assert_eq!(Some(td.path()), partial.path().parent());
+        File::create(partial.path()).unwrap();
+        let partial_path = partial.path().to_path_buf();
+        drop(partial);
+        assert!(!partial_path.exists());
+        assert!(!target.exists());
+
+        let partial = Partial::sibling(&target);
+        create_dir(partial.path()).unwrap();
+        File::create(partial.path().join("a.txt")).unwrap();
+        partial.persist(&target).unwrap();
+        assert!(target.join("a.txt").is_file());
+    }
+
+    fn set_modified(path: &Path, secs_ago: u64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs_ago))
+            .unwrap();
+    }
+
+    fn prepare_merge(target: &Path) -> Partial {
+        create_dir(target).unwrap();
+        write(target.join("a.txt"), "old").unwrap();
+        set_modified(&target.join("a.txt"), 3600);
+        write(target.join("keep.txt"), "keep").unwrap();
+
+        let partial = Partial::sibling(target);
+        create_dir(partial.path()).unwrap();
+        create_dir(partial.path().join("child")).unwrap();
+        write(partial.path().join("a.txt"), "new").unwrap();
+        write(partial.path().join("child").join("b.txt"), "b").unwrap();
+        partial
+    }
+
+    #[test]
+    fn persist_with_test() {
+        let td = tempdir().unwrap();
+        let target = td.path().join("out");
+
+        let partial = prepare_merge(&target);
+        assert!(partial.persist_with(&target, OutputPolicy::Fail).is_err());
+
+        let partial = Partial::sibling(&target);
+        create_dir(partial.path()).unwrap();
+        write(partial.path().join("b.txt"), "b").unwrap();
+        partial
+            .persist_with(&target, OutputPolicy::Overwrite)
+            .unwrap();
+        assert!(!target.join("a.txt").exists());
+        assert!(target.join("b.txt").is_file());
+    }
+
+    #[test]
+    fn persist_children_test() {
+        let td = tempdir().unwrap();
+        write(td.path().join("notes.txt"), "old").unwrap();
+
+        let partial = Partial::sibling(&td.path().join("project"));
+        create_dir(partial.path()).unwrap();
+        create_dir(partial.path().join("project")).unwrap();
+        write(partial.path().join("project").join("a.txt"), "a").unwrap();
+        write(partial.path().join("notes.txt"), "new").unwrap();
+        let partial_path = partial.path().to_path_buf();
+        partial
+            .persist_children(td.path(), OutputPolicy::Overwrite)
+            .unwrap();
+
+        assert_eq!(
+            "a",
+            read_to_string(td.path().join("project").join("a.txt")).unwrap()
+        );
+        assert_eq!("new", read_to_string(td.path().join("notes.txt")).unwrap());
+        assert!(!partial_path.exists());
+    }
+
+    #[test]
+    fn merge_test() {
+        let policies = [
+            (ConflictPolicy::Skip, "old"),
+            (ConflictPolicy::Overwrite, "new"),
+            (ConflictPolicy::OverwriteIfNewer, "new"),
+            (ConflictPolicy::Rename, "old"),
+        ];
+        for (conflict, expected) in policies {
+            let td = tempdir().unwrap();
+            let target = td.path().join("out");
+            let partial = prepare_merge(&target);
+            partial
+                .persist_with(&target, OutputPolicy::Merge(conflict))
+                .unwrap();
+
+            assert_eq!(expected, read_to_string(target.join("a.txt")).unwrap());
+            assert_eq!("keep", read_to_string(target.join("keep.txt")).unwrap());
+            assert_eq!(
+                "b",
+                read_to_string(target.join("child").join("b.txt")).unwrap()
+            );
+            if conflict == ConflictPolicy::Rename {
+                assert_eq!("new", read_to_string(target.join("a (1).txt")).unwrap());
+            }
+        }
+
+        // the existing file is newer than the unpacked one
+        let td = tempdir().unwrap();
+        let target = td.path().join("out");
+        let partial = prepare_merge(&target);
+        set_modified(&partial.path().join("a.txt"), 7200);
+        partial
+            .persist_with(
+                &target,
+                OutputPolicy::Merge(ConflictPolicy::OverwriteIfNewer),
+            )
+            .unwrap();
+        assert_eq!("old", read_to_string(target.join("a.txt")).unwrap());
+    }
+}
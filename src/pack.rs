@@ -1,3 +1,13 @@
+mod file;
+mod manifest;
+mod safety;
 mod tar;
+mod walk;
+mod xattrs;
+mod zip;
 
+pub(crate) use file::*;
+pub use safety::*;
 pub use tar::*;
+pub use walk::*;
+pub use zip::*;
@@ -0,0 +1,78 @@
+//! Single files, packed as is, see `Payload::File`.
+
+use std::{
+    fs::File,
+    io::{self, copy, BufReader},
+    path::Path,
+};
+
+use super::manifest::{self, FileDigest};
+use crate::{AlgorithmRead, AlgorithmWrite, Progress};
+
+/// Copies the file `in_path` into `writer`.
+pub(crate) fn pack_file(
+    in_path: &Path,
+    writer: &mut dyn AlgorithmWrite,
+    progress: &Progress,
+) -> io::Result<()> {
+    progress.entry(Path::new(in_path.file_name().unwrap_or_default()));
+    copy(&mut BufReader::new(File::open(in_path)?), writer)?;
+    Ok(())
+}
+
+/// Writes the file packed by `pack_file` to `out_path`.
+pub(crate) fn unpack_file(reader: &mut dyn AlgorithmRead, out_path: &Path) -> io::Result<()> {
+    copy(reader, &mut File::create(out_path)?)?;
+    Ok(())
+}
+
+/// Compares the file packed by `pack_file` with the file `path`, reporting a difference as a
+/// warning. Returns how many files differ, one or none.
+pub(crate) fn check_file(
+    reader: &mut dyn AlgorithmRead,
+    path: &Path,
+    progress: &Progress,
+) -> io::Result<u64> {
+    let expected = FileDigest::of_reader(reader)?;
+    let name = Path::new(path.file_name().unwrap_or_default());
+    progress.entry(name);
+    Ok(manifest::compare(path, name, &expected, progress)? as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Cursor};
+
+    use tempfile::TempDir;
+
+    use super::{check_file, pack_file, unpack_file};
+    use crate::Progress;
+
+    #[test]
+    fn file_test() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a"), "alpha").unwrap();
+        fs::write(dir.path().join("b"), "beta").unwrap();
+
+        let mut packed = Cursor::new(Vec::new());
+        pack_file(&dir.path().join("a"), &mut packed, &Progress::none()).unwrap();
+        assert_eq!(b"alpha", packed.get_ref().as_slice());
+
+        let out = dir.path().join("out");
+        unpack_file(&mut Cursor::new(packed.get_ref()), &out).unwrap();
+        assert_eq!("alpha", fs::read_to_string(&out).unwrap());
+
+        // a single file is compared as a whole
+        let check = |path: &str| {
+            check_file(
+                &mut Cursor::new(packed.get_ref()),
+                &dir.path().join(path),
+                &Progress::none(),
+            )
+            .unwrap()
+        };
+        assert_eq!(0, check("a"));
+        assert_eq!(1, check("b"));
+        assert_eq!(1, check("missing"));
+    }
+}
@@ -0,0 +1,471 @@
+//! Sizes and SHA-256 digests of the files of a tar archive, appended as a last PAX global
+//! header, which tar readers skip. It holds one record per file:
+//!
+//! ```text
+//! MKENCBOX.manifest=1
+//! MKENCBOX.file=<digest as hex> <size> <path>
+//! ```
+//!
+//! with `%` and newlines of paths escaped as `%25` and `%0A`, which readers split records at.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use sha2::{Digest, Sha256};
+use tar::{EntryType, Header};
+
+use super::safety;
+use crate::{output::is_cancelled, Error, ErrorKind, Progress};
+
+const VERSION_KEY: &str = "MKENCBOX.manifest";
+const VERSION: &str = "1";
+const FILE_KEY: &str = "MKENCBOX.file";
+/// Name of the header entry, as GNU tar names global headers.
+const HEADER_NAME: &str = "pax_global_header";
+const BLOCK_SIZE: u64 = 512;
+
+/// Size and SHA-256 digest of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct FileDigest {
+    size: u64,
+    sha256: [u8; 32],
+}
+
+impl FileDigest {
+    pub(super) fn of_reader(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut reader = Hashing::new(reader);
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(reader.digest())
+    }
+
+    pub(super) fn of_file(path: &Path) -> io::Result<Self> {
+        Self::of_reader(&mut File::open(path)?)
+    }
+}
+
+/// Hashes what is read through it.
+pub(super) struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R> Hashing<R> {
+    pub(super) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub(super) fn digest(&self) -> FileDigest {
+        FileDigest {
+            size: self.size,
+            sha256: self.hasher.clone().finalize().into(),
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reader of an archive that hashes the data of one entry as the archive reads it,
+/// so that unpacked files are checked without reading them again.
+pub(super) struct Tap<R> {
+    inner: R,
+    position: u64,
+    region: Rc<RefCell<Region>>,
+}
+
+/// Part of the archive a `Tap` hashes, shared with whoever unpacks the archive.
+#[derive(Clone)]
+pub(super) struct TapRegion(Rc<RefCell<Region>>);
+
+struct Region {
+    start: u64,
+    end: u64,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R> Tap<R> {
+    pub(super) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            position: 0,
+            region: Rc::new(RefCell::new(Region {
+                start: 0,
+                end: 0,
+                hasher: Sha256::new(),
+                size: 0,
+            })),
+        }
+    }
+
+    pub(super) fn region(&self) -> TapRegion {
+        TapRegion(self.region.clone())
+    }
+
+    /// Bytes read so far, where the next archive starts once the last one ended.
+    pub(super) fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: Read + Seek> Tap<R> {
+    /// Skips the zero blocks that end an archive. Returns whether another archive follows,
+    /// as when entries were appended to an encrypted file, see `Target::Append`.
+    pub(super) fn next_archive(&mut self) -> io::Result<bool> {
+        let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+        loop {
+            block.clear();
+            Read::take(&mut *self, BLOCK_SIZE).read_to_end(&mut block)?;
+            if block.is_empty() {
+                return Ok(false);
+            }
+            if block.iter().any(|b| *b != 0) {
+                self.inner.seek(SeekFrom::Current(-(block.len() as i64)))?;
+                self.position -= block.len() as u64;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let (from, to) = (self.position, self.position + read as u64);
+        let mut region = self.region.borrow_mut();
+        let (start, end) = (from.max(region.start), to.min(region.end));
+        if start < end {
+            region
+                .hasher
+                .update(&buf[(start - from) as usize..(end - from) as usize]);
+            region.size += end - start;
+        }
+        self.position = to;
+        Ok(read)
+    }
+}
+
+impl TapRegion {
+    /// Hashes the `size` bytes at `start` of the archive from now on, e.g. of the next entry.
+    pub(super) fn expect(&self, start: u64, size: u64) {
+        let mut region = self.0.borrow_mut();
+        region.start = start;
+        region.end = start + size;
+        region.hasher = Sha256::new();
+        region.size = 0;
+    }
+
+    /// Digest of what was read of the region.
+    pub(super) fn digest(&self) -> FileDigest {
+        let region = self.0.borrow();
+        FileDigest {
+            size: region.size,
+            sha256: region.hasher.clone().finalize().into(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Manifest {
+    files: HashMap<PathBuf, FileDigest>,
+}
+
+impl Manifest {
+    pub(super) fn insert(&mut self, name: &Path, digest: FileDigest) {
+        self.files.insert(name.to_path_buf(), digest);
+    }
+
+    /// Adds the files of `later`, the manifest of an archive appended after this one,
+    /// replacing those of the same name.
+    pub(super) fn extend(&mut self, later: Manifest) {
+        self.files.extend(later.files);
+    }
+
+    /// Fails unless `digest` is the one of `name`.
+    pub(super) fn verify(&self, name: &Path, digest: &FileDigest) -> io::Result<()> {
+        match self.files.get(name) {
+            Some(expected) if expected == digest => Ok(()),
+            Some(_) => Err(mismatch(format!(
+                "{} does not match the manifest of the archive",
+                name.display()
+            ))),
+            None => Err(mismatch(format!(
+                "{} is not in the manifest of the archive",
+                name.display()
+            ))),
+        }
+    }
+
+    /// Appends the manifest to `tar` as a PAX global header.
+    pub(super) fn append<W: Write>(&self, tar: &mut tar::Builder<W>) -> io::Result<()> {
+        let mut data = Vec::new();
+        push_record(&mut data, VERSION_KEY, VERSION.as_bytes());
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_key(|(name, _)| *name);
+        for (name, digest) in files {
+            let mut value = format!("{} {} ", hex::encode(digest.sha256), digest.size).into_bytes();
+            value.extend_from_slice(&escape(&path_bytes(name)));
+            push_record(&mut data, FILE_KEY, &value);
+        }
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XGlobalHeader);
+        header.set_path(HEADER_NAME)?;
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        tar.append(&header, data.as_slice())
+    }
+
+    /// Reads the manifest from `entry`. `None` for entries that are no manifest.
+    pub(super) fn read<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<Self>> {
+        if !entry.header().entry_type().is_pax_global_extensions() {
+            return Ok(None);
+        }
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(None);
+        };
+        let mut manifest = None;
+        for extension in extensions {
+            let extension = match (extension, &manifest) {
+                (Ok(extension), _) => extension,
+                // a global header of another tool
+                (Err(_), None) => return Ok(None),
+                (Err(_), Some(_)) => return Err(mismatch("invalid manifest".into())),
+            };
+            match extension.key() {
+                Ok(VERSION_KEY) if extension.value_bytes() == VERSION.as_bytes() => {
+                    manifest = Some(Self::default());
+                }
+                Ok(VERSION_KEY) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        Error::new(ErrorKind::UnsupportedVersion, "manifest version"),
+                    ))
+                }
+                Ok(FILE_KEY) => {
+                    let Some(manifest) = manifest.as_mut() else {
+                        continue;
+                    };
+                    let (name, digest) = parse_file(extension.value_bytes())
+                        .ok_or_else(|| mismatch("invalid manifest".into()))?;
+                    manifest.files.insert(name, digest);
+                }
+                _ => {}
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Compares the files of the manifest with those in `dir`, see `Pack::check`.
+    pub(super) fn check(&self, dir: &Path, progress: &Progress) -> io::Result<u64> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_key(|(name, _)| *name);
+        let mut differences = 0;
+        for (name, expected) in files {
+            if is_cancelled() {
+                return Err(crate::output::cancelled_io_error());
+            }
+            if let Some(reason) = safety::path_rejection(name) {
+                progress.warning(format!("skipped {}: {reason}", name.display()));
+                continue;
+            }
+            progress.entry(name);
+            differences += compare(&dir.join(name), name, expected, progress)? as u64;
+        }
+        Ok(differences)
+    }
+}
+
+/// Compares the file `path` with `expected`, reporting a difference under `name` as a warning.
+/// Returns whether they differ.
+pub(super) fn compare(
+    path: &Path,
+    name: &Path,
+    expected: &FileDigest,
+    progress: &Progress,
+) -> io::Result<bool> {
+    let reason = match FileDigest::of_file(path) {
+        Ok(digest) if digest == *expected => return Ok(false),
+        Ok(digest) if digest.size != expected.size => "differs in size",
+        Ok(_) => "differs",
+        Err(e) if e.kind() == io::ErrorKind::NotFound => "is missing",
+        Err(e) => return Err(e),
+    };
+    progress.warning(format!("{} {reason}", name.display()));
+    Ok(true)
+}
+
+/// Appends the PAX record `key=value`, prefixed with its own length.
+fn push_record(data: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // a space, "=" and a newline
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest;
+    loop {
+        let next = rest + len.to_string().len();
+        if next == len {
+            break;
+        }
+        len = next;
+    }
+    data.extend_from_slice(format!("{len} {key}=").as_bytes());
+    data.extend_from_slice(value);
+    data.push(b'\n');
+}
+
+fn parse_file(value: &[u8]) -> Option<(PathBuf, FileDigest)> {
+    let mut fields = value.splitn(3, |b| *b == b' ');
+    let mut sha256 = [0u8; 32];
+    hex::decode_to_slice(fields.next()?, &mut sha256).ok()?;
+    let size = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    let name = bytes_path(&unescape(fields.next()?)?);
+    Some((name, FileDigest { size, sha256 }))
+}
+
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        match b {
+            b'%' => escaped.extend_from_slice(b"%25"),
+            b'\n' => escaped.extend_from_slice(b"%0A"),
+            _ => escaped.push(*b),
+        }
+    }
+    escaped
+}
+
+fn unescape(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    while let Some((b, tail)) = rest.split_first() {
+        match b {
+            b'%' if tail.starts_with(b"25") => unescaped.push(b'%'),
+            b'%' if tail.starts_with(b"0A") => unescaped.push(b'\n'),
+            b'%' => return None,
+            _ => {
+                unescaped.push(*b);
+                rest = tail;
+                continue;
+            }
+        }
+        rest = &tail[2..];
+    }
+    Some(unescaped)
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Not `ErrorKind::Other`, which the unpackers take for an input that is no archive.
+fn mismatch(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Error::new(ErrorKind::Corrupted, message),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Cursor, Read},
+        path::Path,
+    };
+
+    use super::{push_record, FileDigest, Manifest, Tap};
+
+    #[test]
+    fn manifest_test() {
+        // records whose length prefix gains a digit
+        for len in 0..120 {
+            let mut data = Vec::new();
+            push_record(&mut data, "k", &vec![b'v'; len]);
+            let prefix = data.iter().position(|b| *b == b' ').unwrap();
+            let declared: usize = std::str::from_utf8(&data[..prefix])
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(data.len(), declared);
+        }
+
+        let mut manifest = Manifest::default();
+        let digest = |data: &[u8]| FileDigest::of_reader(&mut Cursor::new(data)).unwrap();
+        manifest.insert(
+            Path::new("dir/a file\nwith a newline and 100%"),
+            digest(b"a"),
+        );
+        manifest.insert(Path::new("b"), digest(b""));
+        let mut tar = tar::Builder::new(Vec::new());
+        manifest.append(&mut tar).unwrap();
+        let archive = tar.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let read = Manifest::read(&mut entry).unwrap().unwrap();
+        assert_eq!(manifest.files, read.files);
+        read.verify(Path::new("b"), &digest(b"")).unwrap();
+        assert!(read.verify(Path::new("b"), &digest(b"x")).is_err());
+        assert!(read.verify(Path::new("c"), &digest(b"")).is_err());
+    }
+
+    #[test]
+    fn tap_test() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut tap = Tap::new(data.as_slice());
+        let region = tap.region();
+        let mut buf = [0u8; 7];
+        tap.read_exact(&mut buf).unwrap();
+        region.expect(10, 100);
+        std::io::copy(&mut tap, &mut std::io::sink()).unwrap();
+        let expected = FileDigest::of_reader(&mut &data[10..110]).unwrap();
+        assert_eq!(expected, region.digest());
+
+        // zero blocks between archives
+        let mut data = vec![0u8; 1024 + 100];
+        data[1024] = 1;
+        let mut tap = Tap::new(Cursor::new(data));
+        assert!(tap.next_archive().unwrap());
+        assert_eq!(1024, tap.position());
+        let mut rest = Vec::new();
+        tap.read_to_end(&mut rest).unwrap();
+        assert_eq!(1, rest[0]);
+        assert!(!Tap::new(Cursor::new(vec![0u8; 1500]))
+            .next_archive()
+            .unwrap());
+    }
+}
@@ -0,0 +1,115 @@
+//! Checks shared by the unpackers, for archives of unknown provenance.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::{Error, ErrorKind};
+
+/// Default of `max_size` of the packers.
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 40;
+/// Default of `max_entries` of the packers.
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000_000;
+
+pub(super) const ESCAPING_LINK: &str = "symbolic link pointing outside the output directory";
+
+/// Why an entry named `path` must not be unpacked, if so.
+pub(super) fn path_rejection(path: &Path) -> Option<&'static str> {
+    if path.as_os_str().is_empty() {
+        return Some("empty path");
+    }
+    path.components().find_map(|c| match c {
+        Component::Prefix(_) | Component::RootDir => Some("absolute path"),
+        Component::ParentDir => Some("path with a `..` component"),
+        _ => None,
+    })
+}
+
+/// Whether the symbolic link `name` to `target` resolves outside of `root`.
+/// `..` is only accepted at the start of `target`, where it is resolved against
+/// the real location of the link, so that links already unpacked cannot be used to escape.
+pub(super) fn escapes(root: &Path, name: &Path, target: &Path) -> bool {
+    let Ok(root) = root.canonicalize() else {
+        return true;
+    };
+    let mut dir = real_path(&root.join(name.parent().unwrap_or(Path::new(""))));
+    let mut leading = true;
+    for c in target.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir if leading => {
+                if !dir.pop() || !dir.starts_with(&root) {
+                    return true;
+                }
+            }
+            Component::Normal(_) => leading = false,
+            _ => return true,
+        }
+    }
+    !dir.starts_with(&root)
+}
+
+/// `path` with its longest existing ancestor canonicalized.
+fn real_path(path: &Path) -> PathBuf {
+    let mut rest = Vec::new();
+    let mut existing = path;
+    loop {
+        if let Ok(real) = existing.canonicalize() {
+            return rest.iter().rev().fold(real, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// Caps on what one archive may unpack, against archives crafted to fill the disk.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Limits {
+    pub(super) max_size: Option<u64>,
+    pub(super) max_entries: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_size: Some(DEFAULT_MAX_SIZE),
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+        }
+    }
+}
+
+/// What an archive unpacked so far, checked against `Limits`.
+#[derive(Default)]
+pub(super) struct Usage {
+    entries: u64,
+    size: u64,
+}
+
+impl Usage {
+    pub(super) fn add_entry(&mut self, limits: &Limits) -> std::io::Result<()> {
+        self.entries += 1;
+        match limits.max_entries.filter(|max| self.entries > *max) {
+            Some(max) => Err(limit_exceeded(format!("more than {max} entries to unpack"))),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn add_size(&mut self, size: u64, limits: &Limits) -> std::io::Result<()> {
+        self.size = self.size.saturating_add(size);
+        match limits.max_size.filter(|max| self.size > *max) {
+            Some(max) => Err(limit_exceeded(format!("more than {max} bytes to unpack"))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Not `ErrorKind::Other`, which the unpackers take for an input that is no archive.
+fn limit_exceeded(message: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        Error::new(ErrorKind::DecryptionError, message),
+    )
+}
@@ -1,18 +1,313 @@
 use std::{
-    fs::{read_dir, remove_dir, File},
-    io::{copy, BufReader},
-    path::Path,
+    fs::{self, create_dir_all, File},
+    io::{empty, Read},
+    path::{Path, PathBuf},
 };
 
 use anyhow::Result;
+use filetime::FileTime;
+use tar::{EntryType, HeaderMode};
 
-use crate::algorithm::{self, AlgorithmRead, AlgorithmWrite};
+use super::{
+    manifest::{FileDigest, Hashing, Manifest, Tap, TapRegion},
+    safety::{self, Limits, Usage},
+    walk::Filters,
+    xattrs::{self, Restorer},
+};
+use crate::{
+    algorithm::{self, AlgorithmRead, AlgorithmWrite},
+    root_names, Error, ErrorKind, PackFormat, Progress,
+};
 
-pub struct Tar;
+/// Packs directories as tar archives.
+///
+/// Archives always record mode, mtime and ownership. The `preserve_*` options decide
+/// which of them are restored when unpacking.
+///
+/// Entries listed in a [`IGNORE_FILENAME`](crate::IGNORE_FILENAME) file are left out of directories.
+///
+/// Unpacking skips entries with absolute paths or `..` components, and symbolic links pointing
+/// outside of the output directory, reporting each of them as a warning.
+///
+/// Directory archives end with a manifest of the sizes and SHA-256 digests of their files,
+/// which unpacking checks every file against.
+pub struct Tar {
+    filters: Filters,
+    limits: Limits,
+    preserve_permissions: bool,
+    preserve_mtime: bool,
+    preserve_ownership: bool,
+    xattrs: bool,
+    reproducible: bool,
+}
 
 impl Tar {
     pub fn new() -> Self {
-        Self
+        Self {
+            filters: Filters::default(),
+            limits: Limits::default(),
+            preserve_permissions: false,
+            preserve_mtime: true,
+            preserve_ownership: true,
+            xattrs: false,
+            reproducible: false,
+        }
+    }
+
+    /// Which entries of a directory are packed, see `Filters`.
+    pub fn with_filters(self, filters: Filters) -> Self {
+        Self {
+            filters: Filters {
+                sorted: self.filters.sorted,
+                ..filters
+            },
+            ..self
+        }
+    }
+
+    /// Restore setuid, setgid and sticky bits too, which are stripped by default.
+    /// Read, write and execute bits are always restored.
+    pub fn preserve_permissions(self, preserve_permissions: bool) -> Self {
+        Self {
+            preserve_permissions,
+            ..self
+        }
+    }
+
+    pub fn preserve_mtime(self, preserve_mtime: bool) -> Self {
+        Self {
+            preserve_mtime,
+            ..self
+        }
+    }
+
+    /// Restore uid and gid. Only takes effect when running as root.
+    pub fn preserve_ownership(self, preserve_ownership: bool) -> Self {
+        Self {
+            preserve_ownership,
+            ..self
+        }
+    }
+
+    /// Store extended attributes, ACLs and SELinux labels included, and restore them when unpacking.
+    /// Attributes that cannot be read or restored are reported as warnings. Unix only.
+    pub fn xattrs(self, xattrs: bool) -> Self {
+        Self { xattrs, ..self }
+    }
+
+    /// Pack entries in order of their names, with a fixed modification time, no owners and
+    /// modes of 0o644 or 0o755, so that packing the same tree always gives the same archive.
+    pub fn reproducible(self, reproducible: bool) -> Self {
+        Self {
+            filters: Filters {
+                sorted: reproducible,
+                ..self.filters
+            },
+            reproducible,
+            ..self
+        }
+    }
+
+    /// Fail unpacking archives whose files add up to more than `max_size` bytes.
+    pub fn max_size(self, max_size: Option<u64>) -> Self {
+        Self {
+            limits: Limits {
+                max_size,
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    /// Fail unpacking archives with more than `max_entries` entries.
+    pub fn max_entries(self, max_entries: Option<u64>) -> Self {
+        Self {
+            limits: Limits {
+                max_entries,
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    fn append_xattrs(
+        &self,
+        tar: &mut tar::Builder<&mut dyn AlgorithmWrite>,
+        path: &Path,
+        follow: bool,
+        name: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        if !self.xattrs {
+            return Ok(());
+        }
+        let records = xattrs::read(path, follow, name, progress);
+        if !records.is_empty() {
+            tar.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        }
+        Ok(())
+    }
+
+    /// Appends the entries of `in_path`, named relative to it, and records its files in `manifest`.
+    /// With a `root`, `in_path` itself is appended as `root` and its entries below it.
+    fn append_tree(
+        &self,
+        tar: &mut tar::Builder<&mut dyn AlgorithmWrite>,
+        manifest: &mut Manifest,
+        in_path: &Path,
+        root: Option<&Path>,
+        progress: &Progress,
+    ) -> Result<()> {
+        for entry in self.filters.walk(in_path, root)? {
+            let entry = entry?;
+            let (path, name) = (entry.path.as_path(), entry.name.as_path());
+            let metadata = &entry.metadata;
+
+            if metadata.file_type().is_symlink() {
+                progress.entry(name);
+                self.append_xattrs(tar, path, false, name, progress)?;
+                let mut header = self.header(metadata);
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                tar.append_link(&mut header, name, fs::read_link(path)?)?;
+            } else if metadata.is_file() {
+                progress.entry(name);
+                self.append_xattrs(tar, path, entry.followed, name, progress)?;
+                let mut header = self.header(metadata);
+                let mut reader = Hashing::new(File::open(path)?);
+                tar.append_data(&mut header, name, &mut reader)?;
+                manifest.insert(name, reader.digest());
+            } else if metadata.is_dir() {
+                self.append_xattrs(tar, path, entry.followed, name, progress)?;
+                let mut header = self.header(metadata);
+                header.set_size(0);
+                tar.append_data(&mut header, name, empty())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn header(&self, metadata: &fs::Metadata) -> tar::Header {
+        let mode = match self.reproducible {
+            true => HeaderMode::Deterministic,
+            false => HeaderMode::Complete,
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(metadata, mode);
+        header
+    }
+
+    /// Unpacks the archive of `reader` and those appended after it, see `Pack::decompression`.
+    fn unpack_archives(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+        require_manifest: bool,
+    ) -> Result<()> {
+        let mut tap = Tap::new(reader);
+        let region = tap.region();
+        // archives appended later follow the first one, and replace its entries of the same name
+        loop {
+            let start = tap.position();
+            let mut tar = tar::Archive::new(&mut tap);
+            tar.set_preserve_permissions(self.preserve_permissions);
+            tar.set_preserve_mtime(self.preserve_mtime);
+            tar.set_preserve_ownerships(self.preserve_ownership && is_root());
+            self.unpack(
+                &mut tar,
+                &region,
+                start,
+                out_path,
+                progress,
+                require_manifest,
+            )?;
+            if !tap.next_archive()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Unpacks the entries of one archive, verifying each file against the manifest as soon as
+    /// both are known, and before any directory is restored.
+    fn unpack(
+        &self,
+        tar: &mut tar::Archive<&mut Tap<&mut dyn AlgorithmRead>>,
+        tap: &TapRegion,
+        start: u64,
+        out_path: &Path,
+        progress: &Progress,
+        require_manifest: bool,
+    ) -> std::io::Result<()> {
+        create_dir_all(out_path)?;
+        let mut restorer = Restorer::default();
+        // directories are unpacked last so their permissions and mtimes are not changed by their children
+        let mut directories = Vec::new();
+        let mut usage = Usage::default();
+        // files are hashed as they are unpacked, and checked once the manifest at the end is read
+        let mut unpacked: Vec<(PathBuf, FileDigest)> = Vec::new();
+        let mut manifest = None;
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if let Some(read) = Manifest::read(&mut entry)? {
+                for (name, digest) in unpacked.drain(..) {
+                    read.verify(&name, &digest)?;
+                }
+                manifest = Some(read);
+                continue;
+            }
+            usage.add_entry(&self.limits)?;
+            let records = match self.xattrs {
+                true => xattrs::records(&mut entry)?,
+                false => Vec::new(),
+            };
+            let name = entry.path()?.into_owned();
+            if let Some(reason) = rejection(&entry, &name, out_path)? {
+                progress.warning(format!("skipped {}: {reason}", name.display()));
+                continue;
+            }
+            if entry.header().entry_type().is_dir() {
+                directories.push((entry, records));
+                continue;
+            }
+            usage.add_size(entry.header().size()?, &self.limits)?;
+            progress.entry(&name);
+            let is_file = entry.header().entry_type().is_file();
+            if is_file {
+                tap.expect(start + entry.raw_file_position(), entry.size());
+            }
+            if entry.unpack_in(out_path)? {
+                restorer.restore(&out_path.join(&name), &records, &name, progress);
+                match (is_file, &manifest) {
+                    (true, Some(manifest)) => manifest.verify(&name, &tap.digest())?,
+                    (true, None) => unpacked.push((name, tap.digest())),
+                    (false, _) => {}
+                }
+            }
+        }
+        if manifest.is_none() {
+            if require_manifest {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    Error::new(ErrorKind::Corrupted, "the archive has no manifest"),
+                ));
+            }
+            progress.warning("the archive has no manifest, its files were not verified");
+        }
+        // children before their parents
+        for (mut dir, records) in directories.into_iter().rev() {
+            if !dir.unpack_in(out_path)? {
+                continue;
+            }
+            let name = dir.path()?.into_owned();
+            restorer.restore(&out_path.join(&name), &records, &name, progress);
+            // tar restores the mtime of files only
+            if let (true, Ok(mtime)) = (self.preserve_mtime, dir.header().mtime()) {
+                let mtime = FileTime::from_unix_time(mtime as i64, 0);
+                filetime::set_file_mtime(out_path.join(&name), mtime)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -23,51 +318,128 @@ impl Default for Tar {
 }
 
 impl algorithm::Pack for Tar {
-    fn compression(&self, in_path: &Path, writer: &mut dyn AlgorithmWrite) -> Result<()> {
-        if in_path.is_file() {
-            let f = File::open(in_path)?;
-            let mut buf_reader = BufReader::new(f);
-            let _ = copy(&mut buf_reader, writer)?;
-            return Ok(());
-        }
+    fn format(&self) -> PackFormat {
+        PackFormat::Tar
+    }
 
+    fn compression(
+        &self,
+        in_path: &Path,
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()> {
         let mut tar = tar::Builder::new(writer);
-        for entry in read_dir(in_path)? {
-            let entry_path = entry?.path();
-            if entry_path.is_file() {
-                let mut file = File::open(&entry_path)?;
-                tar.append_file(entry_path.file_name().unwrap(), &mut file)?;
-            } else if entry_path.is_dir() {
-                tar.append_dir_all(entry_path.file_name().unwrap(), &entry_path)?;
-            }
+        let mut manifest = Manifest::default();
+        self.append_tree(&mut tar, &mut manifest, in_path, None, progress)?;
+        manifest.append(&mut tar)?;
+        tar.finish()?;
+        Ok(())
+    }
+
+    fn compression_roots(
+        &self,
+        in_paths: &[PathBuf],
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()> {
+        let mut tar = tar::Builder::new(writer);
+        let mut manifest = Manifest::default();
+        for (in_path, root) in in_paths.iter().zip(root_names(in_paths)?) {
+            let root = Some(Path::new(&root));
+            self.append_tree(&mut tar, &mut manifest, in_path, root, progress)?;
         }
+        manifest.append(&mut tar)?;
         tar.finish()?;
         Ok(())
     }
 
-    fn decompression(&self, reader: &mut dyn AlgorithmRead, out_path: &Path) -> Result<()> {
-        let mut tar = tar::Archive::new(reader);
-        match tar.unpack(out_path) {
-            Ok(()) => Ok(()),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::Other {
-                    remove_dir(out_path)?;
-                    let mut file = File::create(out_path)?;
-                    let reader = tar.into_inner();
-                    reader.rewind()?;
-                    copy(reader, &mut file)?;
-                    Ok(())
-                } else {
-                    Err(e.into())
+    fn decompression(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        self.unpack_archives(reader, out_path, progress, false)
+    }
+
+    fn decompression_verified(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        self.unpack_archives(reader, out_path, progress, true)
+    }
+
+    fn check(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        path: &Path,
+        progress: &Progress,
+    ) -> Result<u64> {
+        let mut tap = Tap::new(reader);
+        let mut manifest = Manifest::default();
+        let mut complete = true;
+        loop {
+            let mut tar = tar::Archive::new(&mut tap);
+            let mut read_manifest = None;
+            for entry in tar.entries()? {
+                if let Some(read) = Manifest::read(&mut entry?)? {
+                    read_manifest = Some(read);
                 }
             }
+            match read_manifest {
+                Some(read) => manifest.extend(read),
+                None => complete = false,
+            }
+            if !tap.next_archive()? {
+                break;
+            }
+        }
+        match complete {
+            true => Ok(manifest.check(path, progress)?),
+            false => Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                "the archive has no manifest, it was written by an older version",
+            )
+            .into()),
         }
     }
 }
 
+/// Why an entry must not be unpacked into `root`, if so.
+fn rejection<R: Read>(
+    entry: &tar::Entry<R>,
+    name: &Path,
+    root: &Path,
+) -> std::io::Result<Option<&'static str>> {
+    if let Some(reason) = safety::path_rejection(name) {
+        return Ok(Some(reason));
+    }
+    let Some(target) = entry.link_name()? else {
+        return Ok(None);
+    };
+    Ok(match entry.header().entry_type() {
+        EntryType::Link => safety::path_rejection(&target),
+        EntryType::Symlink if safety::escapes(root, name, &target) => Some(safety::ESCAPING_LINK),
+        _ => None,
+    })
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
 #[cfg(test)]
 mod test {
-    use crate::Pack;
+    use crate::{Filters, Pack, Progress, SymlinkPolicy};
 
     use super::Tar;
     use std::fs::{self, create_dir, File};
@@ -77,7 +449,7 @@ mod test {
 
     #[test]
     fn dir_compression_and_decompression_test() {
-        let packer = Tar;
+        let packer = Tar::new();
 
         let origin_dir = TempDir::new().unwrap();
         let dir_path = origin_dir.path();
@@ -95,16 +467,437 @@ mod test {
 
         let mut comp_to = NamedTempFile::new().unwrap();
 
-        packer.compression(dir_path, &mut comp_to).unwrap();
+        packer
+            .compression(dir_path, &mut comp_to, &Progress::none())
+            .unwrap();
 
-        let packer = Tar;
+        let packer = Tar::new();
         let mut reader = File::open(comp_to.path()).unwrap();
         let out_dir = TempDir::new().unwrap();
-        let _ = packer.decompression(&mut reader, out_dir.path());
+        let _ = packer.decompression(&mut reader, out_dir.path(), &Progress::none());
 
         assert!(compare_dirs(origin_dir.path(), out_dir.path()))
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn metadata_test() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        use std::time::{Duration, SystemTime};
+
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::write(dir_path.join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir_path.join("run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        File::options()
+            .write(true)
+            .open(dir_path.join("run.sh"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        create_dir(dir_path.join("bin")).unwrap();
+        symlink("../run.sh", dir_path.join("bin/run")).unwrap();
+        symlink("bin", dir_path.join("bin_link")).unwrap();
+        File::open(dir_path.join("bin"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let pack_unpack = |packer: Tar| {
+            let mut packed = NamedTempFile::new().unwrap();
+            packer
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            let out_dir = TempDir::new().unwrap();
+            let mut reader = File::open(packed.path()).unwrap();
+            packer
+                .decompression(&mut reader, out_dir.path(), &Progress::none())
+                .unwrap();
+            out_dir
+        };
+
+        let out_dir = pack_unpack(Tar::new());
+        let out = out_dir.path();
+        let script = fs::symlink_metadata(out.join("run.sh")).unwrap();
+        assert_eq!(0o750, script.permissions().mode() & 0o7777);
+        assert_eq!(mtime, script.modified().unwrap());
+        assert_eq!(
+            mtime,
+            fs::metadata(out.join("bin")).unwrap().modified().unwrap()
+        );
+        assert_eq!(
+            Path::new("../run.sh"),
+            fs::read_link(out.join("bin/run")).unwrap()
+        );
+        assert_eq!(
+            Path::new("bin"),
+            fs::read_link(out.join("bin_link")).unwrap()
+        );
+
+        let out_dir = pack_unpack(
+            Tar::new()
+                .with_filters(Filters::new().symlinks(SymlinkPolicy::Skip))
+                .preserve_mtime(false),
+        );
+        let out = out_dir.path();
+        assert!(!out.join("bin/run").exists());
+        assert!(!out.join("bin_link").exists());
+        assert_ne!(
+            mtime,
+            fs::metadata(out.join("run.sh"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+
+        let out_dir =
+            pack_unpack(Tar::new().with_filters(Filters::new().symlinks(SymlinkPolicy::Follow)));
+        let out = out_dir.path();
+        let followed = fs::symlink_metadata(out.join("bin_link/run")).unwrap();
+        assert!(followed.is_file());
+        assert_eq!(0o750, followed.permissions().mode() & 0o7777);
+
+        // following a link to an ancestor never ends
+        symlink("..", dir_path.join("bin/up")).unwrap();
+        let mut packed = NamedTempFile::new().unwrap();
+        assert!(Tar::new()
+            .with_filters(Filters::new().symlinks(SymlinkPolicy::Follow))
+            .compression(dir_path, &mut packed, &Progress::none())
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn xattrs_test() {
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        fs::write(dir_path.join("labelled"), "x").unwrap();
+        create_dir(dir_path.join("dir")).unwrap();
+        if xattr::set(dir_path.join("labelled"), "user.mkencbox", b"label").is_err() {
+            // the file system of the temporary directory has no user attributes
+            return;
+        }
+        xattr::set(dir_path.join("dir"), "user.mkencbox", b"dir label").unwrap();
+
+        for enabled in [true, false] {
+            let packer = Tar::new().xattrs(enabled);
+            let mut packed = NamedTempFile::new().unwrap();
+            packer
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            let out_dir = TempDir::new().unwrap();
+            let mut reader = File::open(packed.path()).unwrap();
+            packer
+                .decompression(&mut reader, out_dir.path(), &Progress::none())
+                .unwrap();
+
+            let label = xattr::get(out_dir.path().join("labelled"), "user.mkencbox").unwrap();
+            let dir_label = xattr::get(out_dir.path().join("dir"), "user.mkencbox").unwrap();
+            if enabled {
+                assert_eq!(Some(b"label".to_vec()), label);
+                assert_eq!(Some(b"dir label".to_vec()), dir_label);
+            } else {
+                assert_eq!(None, label);
+                assert_eq!(None, dir_label);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_test() {
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        for dir in ["build", "src"] {
+            create_dir(dir_path.join(dir)).unwrap();
+        }
+        for file in [
+            "keep.txt",
+            "notes.log",
+            "build/out.bin",
+            "src/a.rs",
+            "src/b.tmp",
+        ] {
+            fs::write(dir_path.join(file), file).unwrap();
+        }
+        fs::write(dir_path.join(crate::IGNORE_FILENAME), "*.tmp\n").unwrap();
+        fs::write(dir_path.join(".gitignore"), "build/\n").unwrap();
+
+        let files = |packer: Tar| {
+            let mut packed = NamedTempFile::new().unwrap();
+            packer
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            let out_dir = TempDir::new().unwrap();
+            let mut reader = File::open(packed.path()).unwrap();
+            packer
+                .decompression(&mut reader, out_dir.path(), &Progress::none())
+                .unwrap();
+            let mut files: Vec<String> = walkdir::WalkDir::new(out_dir.path())
+                .into_iter()
+                .map(Result::unwrap)
+                .filter(|e| e.file_type().is_file())
+                .map(|e| {
+                    let name = e.path().strip_prefix(out_dir.path()).unwrap();
+                    name.to_string_lossy().into_owned()
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        assert_eq!(
+            vec![
+                ".gitignore",
+                ".mkencboxignore",
+                "build/out.bin",
+                "keep.txt",
+                "src/a.rs"
+            ],
+            files(Tar::new().with_filters(Filters::new().exclude("*.log")))
+        );
+        assert_eq!(
+            vec![
+                ".gitignore",
+                ".mkencboxignore",
+                "keep.txt",
+                "notes.log",
+                "src/a.rs"
+            ],
+            files(Tar::new().with_filters(Filters::new().gitignore(true)))
+        );
+        assert_eq!(
+            vec!["src/a.rs", "src/b.tmp"],
+            files(Tar::new().with_filters(Filters::new().include("src/*").exclude("*.log")))
+        );
+        assert_eq!(
+            vec!["keep.txt"],
+            files(
+                Tar::new().with_filters(
+                    Filters::new()
+                        .include("*.txt")
+                        .include("*.rs")
+                        .exclude("src")
+                )
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extraction_policy_test() {
+        use crate::ProgressEvent;
+        use std::os::unix::fs::PermissionsExt;
+        use tar::{EntryType, Header};
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |name: &[u8], kind: EntryType, link: Option<&str>, mode: u32| {
+            let mut header = Header::new_gnu();
+            // written raw, `set_path` refuses the names under test
+            header.as_old_mut().name[..name.len()].copy_from_slice(name);
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_size(0);
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            if let Some(link) = link {
+                header.set_link_name(link).unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, std::io::empty()).unwrap();
+        };
+        append(b"../evil", EntryType::Regular, None, 0o644);
+        append(b"/abs.txt", EntryType::Regular, None, 0o644);
+        append(b"ok.txt", EntryType::Regular, None, 0o644);
+        append(b"suid", EntryType::Regular, None, 0o4755);
+        append(b"up", EntryType::Symlink, Some("../outside"), 0o777);
+        append(b"abs", EntryType::Symlink, Some("/etc/passwd"), 0o777);
+        append(b"sub/", EntryType::Directory, None, 0o755);
+        append(b"sub/ok", EntryType::Symlink, Some("../ok.txt"), 0o777);
+        append(b"sub/sneaky", EntryType::Symlink, Some("x/../../.."), 0o777);
+        let archive = builder.into_inner().unwrap();
+
+        let unpack = |packer: Tar| {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+            let out_dir = TempDir::new().unwrap();
+            let result = packer.decompression(
+                &mut std::io::Cursor::new(&archive),
+                &out_dir.path().join("out"),
+                &Progress::new(tx),
+            );
+            let mut warnings = 0;
+            while let Ok(event) = rx.try_recv() {
+                warnings += matches!(event, ProgressEvent::Warning(_)) as usize;
+            }
+            (result, warnings, out_dir)
+        };
+
+        let (result, warnings, out_dir) = unpack(Tar::new());
+        result.unwrap();
+        // the five entries skipped, and the missing manifest
+        assert_eq!(6, warnings);
+        let out = out_dir.path().join("out");
+        let mut names: Vec<String> = walkdir::WalkDir::new(out_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .map(|e| {
+                e.unwrap()
+                    .path()
+                    .strip_prefix(&out)
+                    .unwrap_or(Path::new("?"))
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(vec!["", "ok.txt", "sub", "sub/ok", "suid"], names);
+        let mode = fs::metadata(out.join("suid")).unwrap().permissions().mode();
+        assert_eq!(0o755, mode & 0o7777);
+
+        let (result, _, out_dir) = unpack(Tar::new().preserve_permissions(true));
+        result.unwrap();
+        let mode = fs::metadata(out_dir.path().join("out/suid"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o4755, mode & 0o7777);
+
+        assert!(unpack(Tar::new().max_entries(Some(3))).0.is_err());
+        let (result, _, _) = unpack(Tar::new().max_entries(Some(9)).max_size(Some(0)));
+        result.unwrap();
+    }
+
+    #[test]
+    fn manifest_test() {
+        use crate::ProgressEvent;
+
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        fs::write(dir_path.join("a"), "alpha").unwrap();
+        create_dir(dir_path.join("sub")).unwrap();
+        fs::write(dir_path.join("sub/b"), "beta").unwrap();
+
+        let mut packed = std::io::Cursor::new(Vec::new());
+        Tar::new()
+            .compression(dir_path, &mut packed, &Progress::none())
+            .unwrap();
+        let packed = packed.into_inner();
+        let out_dir = TempDir::new().unwrap();
+        let out = out_dir.path().join("out");
+        let check = |archive: &[u8], path: &Path| {
+            Tar::new()
+                .check(&mut std::io::Cursor::new(archive), path, &Progress::none())
+                .unwrap()
+        };
+        Tar::new()
+            .decompression(&mut std::io::Cursor::new(&packed), &out, &Progress::none())
+            .unwrap();
+        assert_eq!(0, check(&packed, &out));
+        fs::write(out.join("a"), "alphx").unwrap();
+        fs::remove_file(out.join("sub/b")).unwrap();
+        assert_eq!(2, check(&packed, &out));
+
+        // data changed after packing
+        let mut tampered = packed.clone();
+        let at = tampered.windows(4).position(|w| w == b"beta").unwrap();
+        tampered[at + 3] = b'x';
+        let err = Tar::new()
+            .decompression(
+                &mut std::io::Cursor::new(&tampered),
+                &out_dir.path().join("tampered"),
+                &Progress::none(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match the manifest"));
+
+        // manifest stripped, only accepted with a warning from archives of older versions
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_dir_all(".", dir_path).unwrap();
+        let stripped = builder.into_inner().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        Tar::new()
+            .decompression(
+                &mut std::io::Cursor::new(&stripped),
+                &out_dir.path().join("older"),
+                &Progress::new(tx),
+            )
+            .unwrap();
+        let mut warnings = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let ProgressEvent::Warning(warning) = event {
+                warnings.push(warning);
+            }
+        }
+        assert!(warnings.iter().any(|w| w.contains("has no manifest")));
+        let err = Tar::new()
+            .decompression_verified(
+                &mut std::io::Cursor::new(&stripped),
+                &out_dir.path().join("stripped"),
+                &Progress::none(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("has no manifest"));
+
+        // single files are packed as is by `Process`, and never guessed from the payload
+        let plain = [b'x'; 1024];
+        assert!(Tar::new()
+            .check(
+                &mut std::io::Cursor::new(&plain),
+                &dir_path.join("a"),
+                &Progress::none()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn reproducible_test() {
+        use std::time::{Duration, SystemTime};
+
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        for name in ["b", "a", "c"] {
+            create_dir(dir_path.join(name)).unwrap();
+            fs::write(dir_path.join(name).join("file"), name).unwrap();
+        }
+        let pack = |packer: Tar| {
+            let mut packed = std::io::Cursor::new(Vec::new());
+            packer
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            packed.into_inner()
+        };
+        let touch = || {
+            let mtime = SystemTime::now() - Duration::from_secs(rand::random::<u32>() as u64);
+            File::options()
+                .write(true)
+                .open(dir_path.join("a/file"))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+
+        touch();
+        let packed = pack(Tar::new().reproducible(true));
+        touch();
+        assert_eq!(packed, pack(Tar::new().reproducible(true)));
+        assert_ne!(packed, pack(Tar::new()));
+
+        let mut archive = tar::Archive::new(packed.as_slice());
+        let mut names = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            if header.entry_type().is_pax_global_extensions() {
+                continue;
+            }
+            assert_eq!(0, header.uid().unwrap());
+            assert_eq!(None, header.username().unwrap().filter(|u| !u.is_empty()));
+            names.push(entry.path().unwrap().display().to_string());
+        }
+        assert_eq!(vec!["a", "a/file", "b", "b/file", "c", "c/file"], names);
+    }
+
     fn compare_dirs(dir1: &Path, dir2: &Path) -> bool {
         let entries1 = get_dir_entries(dir1);
         let entries2 = get_dir_entries(dir2);
@@ -0,0 +1,162 @@
+//! Directory walk shared by the packers.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ignore::{overrides::OverrideBuilder, DirEntry, WalkBuilder};
+
+/// What packers do with symbolic links found in a directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Store the link itself.
+    #[default]
+    Store,
+    /// Store what the link points to. Fails on broken links and loops.
+    Follow,
+    /// Leave links out.
+    Skip,
+}
+
+/// Ignore file honored in every directory of the packed tree, with the syntax of `.gitignore`.
+pub const IGNORE_FILENAME: &str = ".mkencboxignore";
+
+/// Walk configuration: which entries of a directory are packed, or mirrored, see `Process::filters`.
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    pub(super) symlinks: SymlinkPolicy,
+    pub(super) includes: Vec<String>,
+    pub(super) excludes: Vec<String>,
+    pub(super) gitignore: bool,
+    pub(super) one_file_system: bool,
+    /// Walk the entries of each directory in order of their names.
+    pub(super) sorted: bool,
+}
+
+/// A file, directory or symbolic link to pack.
+pub(crate) struct Entry {
+    pub(crate) path: PathBuf,
+    /// Name in the archive.
+    pub(crate) name: PathBuf,
+    /// Of what a followed link points to.
+    pub(crate) metadata: fs::Metadata,
+    /// Whether `path` is a symbolic link that is followed.
+    pub(crate) followed: bool,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What to do with symbolic links, see `SymlinkPolicy`.
+    pub fn symlinks(self, symlinks: SymlinkPolicy) -> Self {
+        Self { symlinks, ..self }
+    }
+
+    /// Only pack files matching `glob`, relative to the packed directory. Can be given several times.
+    /// Directories are still walked, and ignore files are overridden for matching files.
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.includes.push(glob.into());
+        self
+    }
+
+    /// Leave out entries matching `glob`, relative to the packed directory. Can be given several times.
+    /// Takes precedence over `include` and ignore files.
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.excludes.push(glob.into());
+        self
+    }
+
+    /// Also honor `.gitignore` files, outside of git repositories too.
+    pub fn gitignore(self, gitignore: bool) -> Self {
+        Self { gitignore, ..self }
+    }
+
+    /// Do not descend into directories on other file systems, e.g. mount points.
+    pub fn one_file_system(self, one_file_system: bool) -> Self {
+        Self {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Entries of `in_path`, parents first, named relative to it.
+    /// With a `root`, `in_path` itself comes first as `root` and its entries are named below it.
+    pub(crate) fn walk<'a>(
+        &'a self,
+        in_path: &'a Path,
+        root: Option<&'a Path>,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<Entry>> + 'a> {
+        Ok(self
+            .walker(in_path)?
+            .filter_map(move |entry| self.entry(entry, in_path, root).transpose()))
+    }
+
+    fn walker(&self, in_path: &Path) -> std::io::Result<ignore::Walk> {
+        let mut overrides = OverrideBuilder::new(in_path);
+        // later globs win, so an exclude beats an include matching the same path
+        for glob in &self.includes {
+            overrides.add(glob).map_err(invalid_input)?;
+        }
+        for glob in &self.excludes {
+            overrides.add(&format!("!{glob}")).map_err(invalid_input)?;
+        }
+        let mut builder = WalkBuilder::new(in_path);
+        builder
+            .standard_filters(false)
+            .git_ignore(self.gitignore)
+            .require_git(false)
+            .add_custom_ignore_filename(IGNORE_FILENAME)
+            .overrides(overrides.build().map_err(invalid_input)?)
+            .follow_links(self.symlinks == SymlinkPolicy::Follow)
+            .same_file_system(self.one_file_system);
+        if self.sorted {
+            builder.sort_by_file_name(|a, b| a.cmp(b));
+        }
+        Ok(builder.build())
+    }
+
+    fn entry(
+        &self,
+        entry: Result<DirEntry, ignore::Error>,
+        in_path: &Path,
+        root: Option<&Path>,
+    ) -> std::io::Result<Option<Entry>> {
+        let entry = entry.map_err(walk_error)?;
+        let relative = entry.path().strip_prefix(in_path).unwrap_or(entry.path());
+        let name = match (root, entry.depth()) {
+            (None, 0) => return Ok(None),
+            (None, _) => relative.to_path_buf(),
+            (Some(root), 0) => root.to_path_buf(),
+            (Some(root), _) => root.join(relative),
+        };
+        let followed = entry.path_is_symlink() && self.symlinks == SymlinkPolicy::Follow;
+        let metadata = match followed {
+            true => fs::metadata(entry.path())?,
+            false => fs::symlink_metadata(entry.path())?,
+        };
+        if metadata.file_type().is_symlink() && self.symlinks == SymlinkPolicy::Skip {
+            return Ok(None);
+        }
+        Ok(Some(Entry {
+            path: entry.into_path(),
+            name,
+            metadata,
+            followed,
+        }))
+    }
+}
+
+fn invalid_input(e: ignore::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+}
+
+/// Keeps i/o errors as such, loops and the like become invalid input.
+fn walk_error(e: ignore::Error) -> std::io::Error {
+    match e.io_error().is_some() {
+        true => e.into_io_error().unwrap(),
+        false => invalid_input(e),
+    }
+}
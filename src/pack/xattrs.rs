@@ -0,0 +1,143 @@
+//! Extended attributes as PAX records, the way GNU tar and bsdtar store them.
+//! POSIX ACLs and SELinux labels are extended attributes too.
+
+use std::{collections::HashSet, path::Path};
+
+use crate::Progress;
+
+const PAX_PREFIX: &str = "SCHILY.xattr.";
+
+/// PAX record key and value.
+pub(crate) type Record = (String, Vec<u8>);
+
+/// Reads the extended attributes of `path`, following a symlink only if `follow`.
+/// Unreadable attributes are reported as warnings under `name`.
+#[cfg(unix)]
+pub(crate) fn read(path: &Path, follow: bool, name: &Path, progress: &Progress) -> Vec<Record> {
+    let names = match if follow {
+        xattr::list_deref(path)
+    } else {
+        xattr::list(path)
+    } {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Vec::new(),
+        Err(e) => {
+            progress.warning(format!(
+                "{}: cannot read extended attributes: {e}",
+                name.display()
+            ));
+            return Vec::new();
+        }
+    };
+
+    let mut records = Vec::new();
+    for attr in names {
+        let Some(key) = attr.to_str() else {
+            progress.warning(format!(
+                "{}: skipped extended attribute {attr:?} with a non UTF-8 name",
+                name.display()
+            ));
+            continue;
+        };
+        let value = if follow {
+            xattr::get_deref(path, &attr)
+        } else {
+            xattr::get(path, &attr)
+        };
+        match value {
+            Ok(Some(value)) => records.push((format!("{PAX_PREFIX}{key}"), value)),
+            Ok(None) => {}
+            Err(e) => progress.warning(format!(
+                "{}: cannot read extended attribute {key}: {e}",
+                name.display()
+            )),
+        }
+    }
+    // in a fixed order, for reproducible archives
+    records.sort();
+    records
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read(_: &Path, _: bool, _: &Path, _: &Progress) -> Vec<Record> {
+    Vec::new()
+}
+
+/// Extended attribute records of a tar entry.
+pub(crate) fn records<R: std::io::Read>(entry: &mut tar::Entry<R>) -> std::io::Result<Vec<Record>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+    let mut records = Vec::new();
+    for extension in extensions {
+        let extension = extension?;
+        if let Ok(key) = extension.key() {
+            if key.starts_with(PAX_PREFIX) {
+                records.push((key.to_string(), extension.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Applies extended attributes while unpacking. Failures become warnings,
+/// each kind of failure reported once per archive.
+#[derive(Default)]
+pub(crate) struct Restorer {
+    unsupported: bool,
+    failed: HashSet<String>,
+}
+
+impl Restorer {
+    pub(crate) fn restore(
+        &mut self,
+        path: &Path,
+        records: &[Record],
+        name: &Path,
+        progress: &Progress,
+    ) {
+        for (key, value) in records {
+            if self.unsupported {
+                return;
+            }
+            let attr = &key[PAX_PREFIX.len()..];
+            match set(path, attr, value) {
+                Ok(()) => {}
+                Err(e) if is_unsupported(&e) => {
+                    self.unsupported = true;
+                    progress.warning(format!(
+                        "{}: extended attributes are not supported here, none are restored",
+                        name.display()
+                    ));
+                }
+                Err(e) => {
+                    if self.failed.insert(attr.to_string()) {
+                        progress.warning(format!(
+                            "{}: cannot restore extended attribute {attr}: {e}",
+                            name.display()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set(path: &Path, attr: &str, value: &[u8]) -> std::io::Result<()> {
+    // does not follow symlinks
+    xattr::set(path, attr, value)
+}
+
+#[cfg(not(unix))]
+fn set(_: &Path, _: &str, _: &[u8]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+fn is_unsupported(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOTSUP) {
+        return true;
+    }
+    e.kind() == std::io::ErrorKind::Unsupported
+}
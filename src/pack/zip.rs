@@ -0,0 +1,672 @@
+use std::{
+    fs::{self, create_dir_all, File},
+    io::{copy, BufRead, BufReader, Read, Seek},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use filetime::FileTime;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{
+    safety::{self, Limits, Usage},
+    walk::Filters,
+};
+use crate::{
+    algorithm::{self, AlgorithmRead, AlgorithmWrite},
+    output::{cancelled_io_error, is_cancelled},
+    root_names, PackFormat, Progress,
+};
+
+/// How `Zip` stores file contents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZipCompression {
+    Stored,
+    #[default]
+    Deflated,
+}
+
+/// Packs directories as zip archives, with the same filters and extraction checks as `Tar`.
+///
+/// Zip archives keep read, write and execute bits, and modification times in UTC
+/// with a resolution of two seconds. Owners and extended attributes are not stored.
+pub struct Zip {
+    filters: Filters,
+    limits: Limits,
+    compression: ZipCompression,
+    preserve_mtime: bool,
+    reproducible: bool,
+}
+
+impl Zip {
+    pub fn new() -> Self {
+        Self {
+            filters: Filters::default(),
+            limits: Limits::default(),
+            compression: ZipCompression::default(),
+            preserve_mtime: true,
+            reproducible: false,
+        }
+    }
+
+    pub fn compression_method(self, compression: ZipCompression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// See `Tar::with_filters`.
+    pub fn with_filters(self, filters: Filters) -> Self {
+        Self {
+            filters: Filters {
+                sorted: self.filters.sorted,
+                ..filters
+            },
+            ..self
+        }
+    }
+
+    pub fn preserve_mtime(self, preserve_mtime: bool) -> Self {
+        Self {
+            preserve_mtime,
+            ..self
+        }
+    }
+
+    /// See `Tar::reproducible`. Modification times are 1980-01-01, the earliest zip can store.
+    pub fn reproducible(self, reproducible: bool) -> Self {
+        Self {
+            filters: Filters {
+                sorted: reproducible,
+                ..self.filters
+            },
+            reproducible,
+            ..self
+        }
+    }
+
+    pub fn max_size(self, max_size: Option<u64>) -> Self {
+        Self {
+            limits: Limits {
+                max_size,
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    pub fn max_entries(self, max_entries: Option<u64>) -> Self {
+        Self {
+            limits: Limits {
+                max_entries,
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    fn options(&self, metadata: &fs::Metadata) -> SimpleFileOptions {
+        let method = match self.compression {
+            ZipCompression::Stored => CompressionMethod::Stored,
+            ZipCompression::Deflated => CompressionMethod::Deflated,
+        };
+        let mut options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(metadata.len() > u32::MAX as u64);
+        if self.reproducible {
+            let executable = metadata.is_dir() || is_executable(metadata);
+            return options
+                .unix_permissions(if executable { 0o755 } else { 0o644 })
+                .last_modified_time(zip::DateTime::default());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            options = options.unix_permissions(metadata.permissions().mode());
+        }
+        if let Some(mtime) = metadata.modified().ok().and_then(to_zip_time) {
+            options = options.last_modified_time(mtime);
+        }
+        options
+    }
+
+    /// Appends the entries of `in_path`, named relative to it.
+    /// With a `root`, `in_path` itself is appended as `root` and its entries below it.
+    fn append_tree(
+        &self,
+        zip: &mut ZipWriter<&mut dyn AlgorithmWrite>,
+        in_path: &Path,
+        root: Option<&Path>,
+        progress: &Progress,
+    ) -> Result<()> {
+        for entry in self.filters.walk(in_path, root)? {
+            let entry = entry?;
+            let name = zip_name(&entry.name);
+            let options = self.options(&entry.metadata);
+
+            if entry.metadata.file_type().is_symlink() {
+                progress.entry(&entry.name);
+                let target = fs::read_link(&entry.path)?;
+                zip.add_symlink(name, zip_name(&target), options)?;
+            } else if entry.metadata.is_file() {
+                progress.entry(&entry.name);
+                zip.start_file(name, options)?;
+                copy(&mut BufReader::new(File::open(&entry.path)?), zip)?;
+            } else if entry.metadata.is_dir() {
+                zip.add_directory(name, options)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack<R: Read + Seek>(
+        &self,
+        archive: &mut ZipArchive<R>,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> std::io::Result<()> {
+        create_dir_all(out_path)?;
+        let root = out_path.canonicalize()?;
+        // directories get their permissions and mtimes last, children before their parents
+        let mut directories = Vec::new();
+        let mut usage = Usage::default();
+        for i in 0..archive.len() {
+            usage.add_entry(&self.limits)?;
+            let mut file = archive.by_index(i)?;
+            let name = PathBuf::from(file.name());
+            let path = out_path.join(&name);
+            let mode = file.unix_mode();
+            let mtime = file.last_modified().and_then(from_zip_time);
+            let reject = |reason: &str| {
+                progress.warning(format!("skipped {}: {reason}", name.display()));
+            };
+            if let Some(reason) = safety::path_rejection(&name) {
+                reject(reason);
+                continue;
+            }
+            // like tar, never write through a link out of the output directory
+            let parent = path.parent().unwrap_or(out_path);
+            create_dir_all(parent)?;
+            if !parent.canonicalize()?.starts_with(&root) {
+                reject(safety::ESCAPING_LINK);
+                continue;
+            }
+
+            if file.is_dir() {
+                create_dir_all(&path)?;
+                directories.push((path, mode, mtime));
+                continue;
+            }
+            if file.is_symlink() {
+                let mut target = String::new();
+                file.by_ref().take(4096).read_to_string(&mut target)?;
+                if safety::escapes(out_path, &name, Path::new(&target)) {
+                    reject(safety::ESCAPING_LINK);
+                    continue;
+                }
+                progress.entry(&name);
+                remove_existing(&path)?;
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(&target, &path)?;
+                #[cfg(not(unix))]
+                reject("symbolic links are not supported on this platform");
+                continue;
+            }
+
+            let size = file.size();
+            usage.add_size(size, &self.limits)?;
+            progress.entry(&name);
+            remove_existing(&path)?;
+            let mut out = File::create(&path)?;
+            // the declared size is what the limits were checked against
+            if copy(&mut file.by_ref().take(size + 1), &mut out)? > size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} is larger than declared", name.display()),
+                ));
+            }
+            drop(out);
+            self.restore(&path, mode, mtime)?;
+        }
+        for (path, mode, mtime) in directories.into_iter().rev() {
+            self.restore(&path, mode, mtime)?;
+        }
+        Ok(())
+    }
+
+    fn restore(
+        &self,
+        path: &Path,
+        mode: Option<u32>,
+        mtime: Option<SystemTime>,
+    ) -> std::io::Result<()> {
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        if let (true, Some(mtime)) = (self.preserve_mtime, mtime) {
+            filetime::set_file_mtime(path, FileTime::from_system_time(mtime))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Zip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl algorithm::Pack for Zip {
+    fn format(&self) -> PackFormat {
+        PackFormat::Zip
+    }
+
+    fn compression(
+        &self,
+        in_path: &Path,
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()> {
+        let mut zip = ZipWriter::new(writer);
+        self.append_tree(&mut zip, in_path, None, progress)?;
+        zip.finish()?;
+        Ok(())
+    }
+
+    fn compression_roots(
+        &self,
+        in_paths: &[PathBuf],
+        writer: &mut dyn AlgorithmWrite,
+        progress: &Progress,
+    ) -> Result<()> {
+        let mut zip = ZipWriter::new(writer);
+        for (in_path, root) in in_paths.iter().zip(root_names(in_paths)?) {
+            self.append_tree(&mut zip, in_path, Some(Path::new(&root)), progress)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    fn decompression(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        let mut archive = ZipArchive::new(reader)?;
+        Ok(self.unpack(&mut archive, out_path, progress)?)
+    }
+
+    fn check(
+        &self,
+        reader: &mut dyn AlgorithmRead,
+        path: &Path,
+        progress: &Progress,
+    ) -> Result<u64> {
+        let mut archive = ZipArchive::new(reader)?;
+        Ok(check_entries(&mut archive, path, progress)?)
+    }
+}
+
+/// Compares the files of `archive` with those in `dir` by their sizes and CRC-32s,
+/// which zip archives record instead of a manifest.
+fn check_entries<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    dir: &Path,
+    progress: &Progress,
+) -> std::io::Result<u64> {
+    let mut differences = 0;
+    for i in 0..archive.len() {
+        if is_cancelled() {
+            return Err(cancelled_io_error());
+        }
+        let file = archive.by_index_raw(i)?;
+        if file.is_dir() || file.is_symlink() {
+            continue;
+        }
+        let name = PathBuf::from(file.name());
+        if let Some(reason) = safety::path_rejection(&name) {
+            progress.warning(format!("skipped {}: {reason}", name.display()));
+            continue;
+        }
+        progress.entry(&name);
+        let reason = match crc32(&dir.join(&name)) {
+            Ok((size, _)) if size != file.size() => "differs in size",
+            Ok((_, crc)) if crc != file.crc32() => "differs",
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => "is missing",
+            Err(e) => return Err(e),
+        };
+        progress.warning(format!("{} {reason}", name.display()));
+        differences += 1;
+    }
+    Ok(differences)
+}
+
+/// Size and CRC-32 of the file `path`.
+fn crc32(path: &Path) -> std::io::Result<(u64, u32)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0;
+    loop {
+        let buf = file.fill_buf()?;
+        if buf.is_empty() {
+            return Ok((size, hasher.finalize()));
+        }
+        hasher.update(buf);
+        let len = buf.len();
+        size += len as u64;
+        file.consume(len);
+    }
+}
+
+/// `name` with `/` separators, as zip archives name their entries.
+fn zip_name(name: &Path) -> String {
+    let components: Vec<_> = name
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    components.join("/")
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    false
+}
+
+fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// `time` as a zip timestamp in UTC. `None` outside of the years 1980 to 2107 zip can store.
+fn to_zip_time(time: SystemTime) -> Option<zip::DateTime> {
+    let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let (year, month, day) = civil_from_days(secs / DAY);
+    let secs = secs % DAY;
+    zip::DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        month,
+        day,
+        (secs / 3600) as u8,
+        (secs / 60 % 60) as u8,
+        (secs % 60) as u8,
+    )
+    .ok()
+}
+
+fn from_zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    let days = days_from_civil(time.year() as u64, time.month() as u64, time.day() as u64);
+    let secs =
+        days * DAY + time.hour() as u64 * 3600 + time.minute() as u64 * 60 + time.second() as u64;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Howard Hinnant's algorithms, for days since 1970-01-01, which zip timestamps never precede.
+
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - (month <= 2) as u64;
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Filters, Pack, Progress, SymlinkPolicy};
+
+    use super::{Zip, ZipCompression};
+    use std::fs::{self, create_dir, File};
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn zip_time_test() {
+        for secs in [315_532_800, 951_782_400, 1_600_000_000, 4_354_819_198] {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            let zip_time = super::to_zip_time(time).unwrap();
+            assert_eq!(Some(time), super::from_zip_time(zip_time));
+        }
+        // before 1980
+        assert!(super::to_zip_time(SystemTime::UNIX_EPOCH).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dir_compression_and_decompression_test() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::write(dir_path.join("run.sh"), "#!/bin/sh\n".repeat(100)).unwrap();
+        fs::set_permissions(dir_path.join("run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        File::options()
+            .write(true)
+            .open(dir_path.join("run.sh"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        create_dir(dir_path.join("bin")).unwrap();
+        fs::write(dir_path.join("bin/empty"), "").unwrap();
+        symlink("../run.sh", dir_path.join("bin/run")).unwrap();
+        File::open(dir_path.join("bin"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        for compression in [ZipCompression::Stored, ZipCompression::Deflated] {
+            let packer = Zip::new().compression_method(compression);
+            let mut packed = NamedTempFile::new().unwrap();
+            packer
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            let out_dir = TempDir::new().unwrap();
+            let out = out_dir.path();
+            let mut reader = File::open(packed.path()).unwrap();
+            packer
+                .decompression(&mut reader, out, &Progress::none())
+                .unwrap();
+
+            assert_eq!(
+                fs::read(dir_path.join("run.sh")).unwrap(),
+                fs::read(out.join("run.sh")).unwrap()
+            );
+            let script = fs::metadata(out.join("run.sh")).unwrap();
+            assert_eq!(0o750, script.permissions().mode() & 0o7777);
+            assert_eq!(mtime, script.modified().unwrap());
+            assert_eq!(
+                mtime,
+                fs::metadata(out.join("bin")).unwrap().modified().unwrap()
+            );
+            assert_eq!(0, fs::metadata(out.join("bin/empty")).unwrap().len());
+            assert_eq!(
+                Path::new("../run.sh"),
+                fs::read_link(out.join("bin/run")).unwrap()
+            );
+        }
+
+        let packer = Zip::new().with_filters(Filters::new().symlinks(SymlinkPolicy::Skip));
+        let mut packed = NamedTempFile::new().unwrap();
+        packer
+            .compression(dir_path, &mut packed, &Progress::none())
+            .unwrap();
+        let out_dir = TempDir::new().unwrap();
+        let mut reader = File::open(packed.path()).unwrap();
+        packer
+            .decompression(&mut reader, out_dir.path(), &Progress::none())
+            .unwrap();
+        assert!(fs::symlink_metadata(out_dir.path().join("bin/run")).is_err());
+    }
+
+    #[test]
+    fn not_an_archive_test() {
+        // single files are packed as is by `Process`, and never guessed from the payload
+        let out_dir = TempDir::new().unwrap();
+        let out = out_dir.path().join("plain.txt");
+        let plain = b"not a zip archive".as_slice();
+        assert!(Zip::new()
+            .decompression(&mut Cursor::new(plain), &out, &Progress::none())
+            .is_err());
+        assert!(Zip::new()
+            .check(&mut Cursor::new(plain), &out, &Progress::none())
+            .is_err());
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn reproducible_test() {
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        for name in ["b", "a"] {
+            fs::write(dir_path.join(name), name).unwrap();
+        }
+        let pack = || {
+            let mut packed = Cursor::new(Vec::new());
+            Zip::new()
+                .reproducible(true)
+                .compression(dir_path, &mut packed, &Progress::none())
+                .unwrap();
+            packed.into_inner()
+        };
+        let packed = pack();
+        File::options()
+            .write(true)
+            .open(dir_path.join("a"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+            .unwrap();
+        assert_eq!(packed, pack());
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(packed)).unwrap();
+        assert_eq!(vec!["a", "b"], archive.file_names().collect::<Vec<_>>());
+        assert_eq!(
+            Some(zip::DateTime::default()),
+            archive.by_name("a").unwrap().last_modified()
+        );
+    }
+
+    #[test]
+    fn check_test() {
+        let origin_dir = TempDir::new().unwrap();
+        let dir_path = origin_dir.path();
+        fs::write(dir_path.join("a"), "alpha").unwrap();
+        create_dir(dir_path.join("sub")).unwrap();
+        fs::write(dir_path.join("sub/b"), "beta").unwrap();
+
+        let mut packed = NamedTempFile::new().unwrap();
+        Zip::new()
+            .compression(dir_path, &mut packed, &Progress::none())
+            .unwrap();
+        let check = |path: &Path| {
+            let mut reader = File::open(packed.path()).unwrap();
+            Zip::new()
+                .check(&mut reader, path, &Progress::none())
+                .unwrap()
+        };
+        assert_eq!(0, check(dir_path));
+        fs::write(dir_path.join("a"), "alphx").unwrap();
+        fs::write(dir_path.join("sub/b"), "beta!").unwrap();
+        assert_eq!(2, check(dir_path));
+        fs::remove_file(dir_path.join("a")).unwrap();
+        fs::write(dir_path.join("sub/b"), "beta").unwrap();
+        assert_eq!(1, check(dir_path));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extraction_policy_test() {
+        use crate::ProgressEvent;
+        use std::io::Write;
+        use zip::{write::SimpleFileOptions, ZipWriter};
+
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for name in ["../evil", "/abs.txt", "ok.txt"] {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(b"data").unwrap();
+        }
+        writer.add_symlink("up", "../outside", options).unwrap();
+        writer.add_symlink("abs", "/etc/passwd", options).unwrap();
+        writer.add_directory("sub", options).unwrap();
+        writer.add_symlink("sub/ok", "../ok.txt", options).unwrap();
+        writer
+            .add_symlink("sub/sneaky", "x/../../..", options)
+            .unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let unpack = |packer: Zip| {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+            let out_dir = TempDir::new().unwrap();
+            let result = packer.decompression(
+                &mut Cursor::new(&archive),
+                &out_dir.path().join("out"),
+                &Progress::new(tx),
+            );
+            let mut warnings = 0;
+            while let Ok(event) = rx.try_recv() {
+                warnings += matches!(event, ProgressEvent::Warning(_)) as usize;
+            }
+            (result, warnings, out_dir)
+        };
+
+        let (result, warnings, out_dir) = unpack(Zip::new());
+        result.unwrap();
+        assert_eq!(5, warnings);
+        let out = out_dir.path().join("out");
+        let mut names: Vec<String> = walkdir::WalkDir::new(out_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .map(|e| {
+                e.unwrap()
+                    .path()
+                    .strip_prefix(&out)
+                    .unwrap_or(Path::new("?"))
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(vec!["", "ok.txt", "sub", "sub/ok"], names);
+
+        assert!(unpack(Zip::new().max_entries(Some(3))).0.is_err());
+        assert!(unpack(Zip::new().max_size(Some(3))).0.is_err());
+        unpack(Zip::new().max_entries(Some(8)).max_size(Some(4)))
+            .0
+            .unwrap();
+    }
+}
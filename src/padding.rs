@@ -0,0 +1,132 @@
+//! Padding of the encrypted region, so that the size of an encrypted file tells little about
+//! the size of its input. Padded files end their encrypted region with
+//!
+//! ```text
+//! zeros | length of the padding, trailer included: u64
+//! ```
+//!
+//! which `Metadata::padded` announces.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::{Error, ErrorKind};
+
+const TRAILER_SIZE: u64 = 8;
+
+/// How far the encrypted region is padded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Padmé: rounds up to a number with as many significant bits as the length has bits in
+    /// its bit length, which costs at most 12.5% and leaks `O(log log n)` bits of the length.
+    Padme,
+    /// Rounds up to a multiple of a size, e.g. 1 MiB.
+    Bucket(u64),
+}
+
+impl Padding {
+    /// Length of a region of `len` bytes once padded.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match *self {
+            Padding::None => len,
+            Padding::Padme if len < 2 => len,
+            Padding::Padme => {
+                let exponent = u64::BITS - 1 - len.leading_zeros();
+                let mantissa_bits = u32::BITS - exponent.leading_zeros();
+                let mask = (1u64 << (exponent - mantissa_bits)) - 1;
+                len.saturating_add(mask) & !mask
+            }
+            Padding::Bucket(0) => len,
+            Padding::Bucket(size) => len.div_ceil(size).saturating_mul(size),
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Padding::None
+    }
+
+    /// Pads the region written to `writer` so far, from its start up to its current position.
+    pub(crate) fn pad<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        let len = writer.stream_position()? + TRAILER_SIZE;
+        let padding = self.padded_len(len) - len + TRAILER_SIZE;
+        std::io::copy(&mut std::io::repeat(0).take(padding - TRAILER_SIZE), writer)?;
+        writer.write_all(&padding.to_le_bytes())
+    }
+}
+
+/// Cuts the padding off the decrypted region in `file`, which must be positioned after the
+/// metadata. The position is kept.
+pub(crate) fn strip(file: &mut File) -> Result<(), Error> {
+    let invalid = |e| Error::new(ErrorKind::Corrupted, e);
+    let position = file.stream_position().map_err(invalid)?;
+    let len = file.seek(SeekFrom::End(0)).map_err(invalid)?;
+    if len < position + TRAILER_SIZE {
+        return Err(Error::new(ErrorKind::Corrupted, "truncated padding"));
+    }
+    let mut trailer = [0u8; TRAILER_SIZE as usize];
+    file.seek(SeekFrom::End(-(TRAILER_SIZE as i64)))
+        .and_then(|_| file.read_exact(&mut trailer))
+        .map_err(invalid)?;
+    let padding = u64::from_le_bytes(trailer);
+    if padding < TRAILER_SIZE || padding > len - position {
+        return Err(Error::new(ErrorKind::Corrupted, "invalid padding"));
+    }
+    file.set_len(len - padding)
+        .and_then(|_| file.seek(SeekFrom::Start(position)))
+        .map_err(invalid)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{strip, Padding};
+
+    #[test]
+    fn padded_len_test() {
+        assert_eq!(5, Padding::None.padded_len(5));
+        assert_eq!(0, Padding::Padme.padded_len(0));
+        assert_eq!(10, Padding::Padme.padded_len(9));
+        assert_eq!(1024, Padding::Padme.padded_len(1000));
+        assert_eq!(1_015_808, Padding::Padme.padded_len(1_000_000));
+        assert_eq!(4096, Padding::Bucket(4096).padded_len(1));
+        assert_eq!(8192, Padding::Bucket(4096).padded_len(4097));
+        assert_eq!(4096, Padding::Bucket(4096).padded_len(4096));
+        // Padmé costs at most 12.5%
+        for len in (2..1_000_000u64).step_by(997) {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded >= len && padded - len <= len / 8, "{len}");
+        }
+    }
+
+    #[test]
+    fn pad_and_strip_test() {
+        for padding in [Padding::Padme, Padding::Bucket(100)] {
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(b"meta").unwrap();
+            file.write_all(&[7u8; 50]).unwrap();
+            padding.pad(&mut file).unwrap();
+            let len = file.stream_position().unwrap();
+            assert_eq!(padding.padded_len(len), len);
+            assert!(len > 54);
+
+            file.seek(SeekFrom::Start(4)).unwrap();
+            strip(&mut file).unwrap();
+            let mut payload = Vec::new();
+            file.read_to_end(&mut payload).unwrap();
+            assert_eq!(vec![7u8; 50], payload);
+        }
+
+        // a trailer claiming more than the region
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0u8; 4]).unwrap();
+        file.write_all(&100u64.to_le_bytes()).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        assert!(strip(&mut file).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Reed-Solomon parity of encrypted files, kept next to them in `NAME.par`:
+//!
+//! ```text
+//! "MKENCPAR" | version: u8 | redundancy: u8 | shard size: u32 | file length: u64
+//!     | SHA-256 of each data shard, then of each parity shard | SHA-256 of all of the above
+//!     | parity shards
+//! ```
+//!
+//! The file is cut into shards of `shard size` bytes, the last one padded with zeros.
+//! Shard `i` goes into group `i % groups`, so that damage to neighbouring shards is spread
+//! over groups, and each group gets `redundancy` percent of parity shards. Damaged shards are
+//! found by their hash, and a group with no more damaged shards than parity shards is repaired.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+use crate::{error::PathContext, output::is_cancelled, Error, ErrorKind, Progress};
+
+pub const PARITY_MAGIC: &[u8; 8] = b"MKENCPAR";
+const PARITY_VERSION: u8 = 1;
+const SHARD_SIZE: u64 = 64 * 1024;
+/// Largest shard size accepted from a parity file.
+const MAX_SHARD_SIZE: u64 = 16 * 1024 * 1024;
+/// Shards per group, data and parity, that GF(2^8) codes allow.
+const MAX_GROUP_SHARDS: usize = 256;
+const HASH_SIZE: usize = 32;
+const PREFIX_SIZE: usize = 8 + 1 + 1 + 4 + 8;
+
+/// Path of the parity file of `path`, e.g. `backup.enc.par`.
+pub fn parity_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".par");
+    PathBuf::from(name)
+}
+
+/// What `repair` found.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Repair {
+    /// Redundancy of the parity file, in percent.
+    pub(crate) redundancy: u8,
+    /// Damaged shards of the file, all repaired.
+    pub(crate) repaired: u64,
+    /// Whether shards of the parity file itself were damaged.
+    pub(crate) parity_damaged: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Layout {
+    redundancy: u8,
+    shard_size: u64,
+    len: u64,
+    /// Data shards of the whole file.
+    shards: u64,
+    groups: u64,
+    /// Data shards per group.
+    data: usize,
+    /// Parity shards per group.
+    parity: usize,
+}
+
+impl Layout {
+    fn new(len: u64, shard_size: u64, redundancy: u8) -> Self {
+        let parity_of = |data: usize| (data * redundancy as usize).div_ceil(100).max(1);
+        let max_data = (1..MAX_GROUP_SHARDS)
+            .take_while(|data| data + parity_of(*data) <= MAX_GROUP_SHARDS)
+            .last()
+            .unwrap_or(1) as u64;
+        let shards = len.div_ceil(shard_size).max(1);
+        let groups = shards.div_ceil(max_data);
+        let data = shards.div_ceil(groups) as usize;
+        Self {
+            redundancy,
+            shard_size,
+            len,
+            shards,
+            groups,
+            data,
+            parity: parity_of(data),
+        }
+    }
+
+    /// Number of the `index`th data shard of `group`, if the file has it.
+    fn data_shard(&self, group: u64, index: usize) -> Option<u64> {
+        Some(group + index as u64 * self.groups).filter(|shard| *shard < self.shards)
+    }
+
+    fn parity_shards(&self) -> u64 {
+        self.groups * self.parity as u64
+    }
+
+    fn index_len(&self) -> u64 {
+        PREFIX_SIZE as u64 + (self.shards + self.parity_shards() + 1) * HASH_SIZE as u64
+    }
+
+    fn parity_offset(&self, group: u64, index: usize) -> u64 {
+        self.index_len() + (group * self.parity as u64 + index as u64) * self.shard_size
+    }
+
+    fn prefix(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PREFIX_SIZE);
+        bytes.extend_from_slice(PARITY_MAGIC);
+        bytes.push(PARITY_VERSION);
+        bytes.push(self.redundancy);
+        bytes.extend_from_slice(&(self.shard_size as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+}
+
+/// Hashes of the data shards, then of the parity shards.
+struct Index {
+    layout: Layout,
+    hashes: Vec<[u8; HASH_SIZE]>,
+}
+
+impl Index {
+    fn read(file: &mut File) -> Result<Self, Error> {
+        let mut prefix = [0u8; PREFIX_SIZE];
+        file.read_exact(&mut prefix).map_err(corrupted)?;
+        if &prefix[..8] != PARITY_MAGIC {
+            return Err(Error::new(ErrorKind::Corrupted, "not a parity file"));
+        }
+        if prefix[8] != PARITY_VERSION {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                format!("parity version {}", prefix[8]),
+            ));
+        }
+        let redundancy = prefix[9];
+        let shard_size = u32::from_le_bytes(prefix[10..14].try_into().unwrap()) as u64;
+        let len = u64::from_le_bytes(prefix[14..22].try_into().unwrap());
+        if !(1..=100).contains(&redundancy) || !(1..=MAX_SHARD_SIZE).contains(&shard_size) {
+            return Err(Error::new(ErrorKind::Corrupted, "invalid parity file"));
+        }
+        let layout = Layout::new(len, shard_size, redundancy);
+        let file_len = file.metadata().map_err(corrupted)?.len();
+        if layout.parity_offset(layout.groups, 0) != file_len {
+            return Err(Error::new(
+                ErrorKind::Corrupted,
+                "truncated or damaged parity file",
+            ));
+        }
+
+        let count = (layout.shards + layout.parity_shards()) as usize;
+        let mut bytes = vec![0u8; (count + 1) * HASH_SIZE];
+        file.read_exact(&mut bytes).map_err(corrupted)?;
+        let (hashes, checksum) = bytes.split_at(count * HASH_SIZE);
+        let mut hasher = Sha256::new();
+        hasher.update(prefix);
+        hasher.update(hashes);
+        if hasher.finalize().as_slice() != checksum {
+            return Err(Error::new(
+                ErrorKind::Corrupted,
+                "damaged index of the parity file",
+            ));
+        }
+        Ok(Self {
+            layout,
+            hashes: hashes
+                .chunks_exact(HASH_SIZE)
+                .map(|h| h.try_into().unwrap())
+                .collect(),
+        })
+    }
+
+    fn write(&self, file: &mut File) -> std::io::Result<()> {
+        let mut bytes = self.layout.prefix();
+        bytes.extend(self.hashes.iter().flatten());
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&bytes)
+    }
+
+    fn parity_hash(&self, group: u64, index: usize) -> &[u8; HASH_SIZE] {
+        &self.hashes[(self.layout.shards + group * self.layout.parity as u64) as usize + index]
+    }
+}
+
+/// Writes the parity file of `input` to `output`, with `redundancy` percent of parity shards.
+pub(crate) fn write_parity(
+    input: &Path,
+    output: &Path,
+    redundancy: u8,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut src = File::open(input).with_path(input)?;
+    let len = src.metadata().with_path(input)?.len();
+    let layout = Layout::new(len, SHARD_SIZE, redundancy.clamp(1, 100));
+    let codec = codec(&layout)?;
+    let mut dst = File::create(output).with_path(output)?;
+    dst.seek(SeekFrom::Start(layout.index_len()))
+        .with_path(output)?;
+
+    let mut index = Index {
+        layout,
+        hashes: vec![[0u8; HASH_SIZE]; (layout.shards + layout.parity_shards()) as usize],
+    };
+    for group in 0..layout.groups {
+        if is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        let mut shards = Vec::with_capacity(layout.data + layout.parity);
+        for i in 0..layout.data {
+            let shard = match layout.data_shard(group, i) {
+                Some(number) => {
+                    let shard = read_shard(&mut src, &layout, number).with_path(input)?;
+                    index.hashes[number as usize] = hash(&shard);
+                    shard
+                }
+                None => vec![0u8; layout.shard_size as usize],
+            };
+            shards.push(shard);
+        }
+        shards.resize(
+            layout.data + layout.parity,
+            vec![0u8; layout.shard_size as usize],
+        );
+        codec
+            .encode(&mut shards)
+            .map_err(|e| Error::new(ErrorKind::EncryptionError, format!("{e:?}")))?;
+        for (i, shard) in shards[layout.data..].iter().enumerate() {
+            let number = (layout.shards + group * layout.parity as u64) as usize + i;
+            index.hashes[number] = hash(shard);
+            dst.write_all(shard).with_path(output)?;
+        }
+        progress.position((group + 1) * layout.data as u64 * layout.shard_size);
+    }
+    index.write(&mut dst).with_path(output)?;
+    dst.sync_all().with_path(output)
+}
+
+/// Copies `input` into `output`, repairing damaged shards with `parity`.
+/// Fails if some group has more damaged shards than it can repair.
+pub(crate) fn repair(
+    input: &mut File,
+    parity: &mut File,
+    output: &mut File,
+    progress: &Progress,
+) -> Result<Repair, Error> {
+    let index = Index::read(parity)?;
+    let layout = index.layout;
+    let codec = codec(&layout)?;
+    input.rewind().map_err(io)?;
+    std::io::copy(&mut input.take(layout.len), output).map_err(io)?;
+    // a truncated input is padded with zeros, which then fail their hash
+    output.set_len(layout.len).map_err(io)?;
+
+    let mut report = Repair {
+        redundancy: layout.redundancy,
+        ..Repair::default()
+    };
+    for group in 0..layout.groups {
+        if is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        let mut shards = Vec::with_capacity(layout.data + layout.parity);
+        let mut damaged_data = Vec::new();
+        for i in 0..layout.data {
+            let shard = match layout.data_shard(group, i) {
+                Some(number) => {
+                    let shard = read_shard(output, &layout, number).map_err(io)?;
+                    match hash(&shard) == index.hashes[number as usize] {
+                        true => Some(shard),
+                        false => {
+                            damaged_data.push((i, number));
+                            None
+                        }
+                    }
+                }
+                None => Some(vec![0u8; layout.shard_size as usize]),
+            };
+            shards.push(shard);
+        }
+        for i in 0..layout.parity {
+            let mut shard = vec![0u8; layout.shard_size as usize];
+            parity
+                .seek(SeekFrom::Start(layout.parity_offset(group, i)))
+                .and_then(|_| parity.read_exact(&mut shard))
+                .map_err(io)?;
+            match hash(&shard) == *index.parity_hash(group, i) {
+                true => shards.push(Some(shard)),
+                false => {
+                    report.parity_damaged = true;
+                    shards.push(None);
+                }
+            }
+        }
+        progress.position((group + 1) * layout.data as u64 * layout.shard_size);
+        if damaged_data.is_empty() {
+            continue;
+        }
+        let damaged = shards.iter().filter(|s| s.is_none()).count();
+        if damaged > layout.parity {
+            return Err(Error::new(
+                ErrorKind::Corrupted,
+                format!(
+                    "{damaged} damaged chunks in a group that can repair at most {}",
+                    layout.parity
+                ),
+            ));
+        }
+        codec
+            .reconstruct_data(&mut shards)
+            .map_err(|e| Error::new(ErrorKind::Corrupted, format!("{e:?}")))?;
+        for (i, number) in damaged_data {
+            let offset = number * layout.shard_size;
+            let end = (offset + layout.shard_size).min(layout.len);
+            let shard = shards[i].as_ref().unwrap();
+            output
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| output.write_all(&shard[..(end - offset) as usize]))
+                .map_err(io)?;
+            report.repaired += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Shard `number` of `file`, padded with zeros.
+fn read_shard(file: &mut File, layout: &Layout, number: u64) -> std::io::Result<Vec<u8>> {
+    let mut shard = Vec::with_capacity(layout.shard_size as usize);
+    file.seek(SeekFrom::Start(number * layout.shard_size))?;
+    file.take(layout.shard_size).read_to_end(&mut shard)?;
+    shard.resize(layout.shard_size as usize, 0);
+    Ok(shard)
+}
+
+fn codec(layout: &Layout) -> Result<ReedSolomon, Error> {
+    ReedSolomon::new(layout.data, layout.parity)
+        .map_err(|e| Error::new(ErrorKind::Corrupted, format!("{e:?}")))
+}
+
+fn hash(shard: &[u8]) -> [u8; HASH_SIZE] {
+    Sha256::digest(shard).into()
+}
+
+fn corrupted(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Corrupted, e)
+}
+
+fn io(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Io, e)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{read, write, File, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+    };
+
+    use tempfile::tempdir;
+
+    use super::{repair, write_parity, Layout, SHARD_SIZE};
+    use crate::{ErrorKind, Progress};
+
+    #[test]
+    fn layout_test() {
+        let layout = Layout::new(5, SHARD_SIZE, 10);
+        assert_eq!(
+            (1, 1, 1, 1),
+            (layout.shards, layout.groups, layout.data, layout.parity)
+        );
+
+        // 1000 shards in groups of at most 232 data and 24 parity shards
+        let layout = Layout::new(1000 * SHARD_SIZE - 1, SHARD_SIZE, 10);
+        assert_eq!(
+            (1000, 5, 200, 20),
+            (layout.shards, layout.groups, layout.data, layout.parity)
+        );
+        assert_eq!(Some(999), layout.data_shard(4, 199));
+        assert!(layout.data + layout.parity <= 256);
+
+        let layout = Layout::new(1000 * SHARD_SIZE, SHARD_SIZE, 100);
+        assert_eq!((8, 125, 125), (layout.groups, layout.data, layout.parity));
+    }
+
+    #[test]
+    fn repair_test() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("data.enc");
+        let parity = dir.path().join("data.enc.par");
+        let data: Vec<u8> = (0..20 * SHARD_SIZE as usize + 123)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        write(&input, &data).unwrap();
+        write_parity(&input, &parity, 10, &Progress::none()).unwrap();
+
+        let repaired = |input_file: &mut File| {
+            let out = dir.path().join("out");
+            let mut output = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&out)
+                .unwrap();
+            let mut parity = File::open(&parity).unwrap();
+            repair(input_file, &mut parity, &mut output, &Progress::none())
+                .map(|report| (report, read(&out).unwrap()))
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&input)
+            .unwrap();
+        let (report, out) = repaired(&mut file).unwrap();
+        assert_eq!((0, false), (report.repaired, report.parity_damaged));
+        assert_eq!(data, out);
+
+        // a damaged sector, and a truncated end
+        file.seek(SeekFrom::Start(3 * SHARD_SIZE + 100)).unwrap();
+        file.write_all(&[0xff; 4096]).unwrap();
+        file.set_len(data.len() as u64 - 10).unwrap();
+        let (report, out) = repaired(&mut file).unwrap();
+        assert_eq!(2, report.repaired);
+        assert_eq!(data, out);
+
+        // more damage than parity: 21 shards in 1 group with 3 parity shards
+        for shard in [0, 5, 9, 14] {
+            file.seek(SeekFrom::Start(shard * SHARD_SIZE)).unwrap();
+            file.write_all(b"bad").unwrap();
+        }
+        let e = repaired(&mut file).unwrap_err();
+        assert_eq!(ErrorKind::Corrupted, e.kind());
+    }
+}
@@ -0,0 +1,160 @@
+//! In-memory pipe between two threads, so that the output of one `Crypto` call
+//! can be the input of another without a temporary file.
+
+use std::{
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+};
+
+/// Chunks in flight before the writer blocks.
+const DEPTH: usize = 16;
+
+pub(crate) fn pipe() -> (PipeWriter, PipeReader) {
+    let (tx, rx) = sync_channel(DEPTH);
+    (
+        PipeWriter { tx, position: 0 },
+        PipeReader {
+            rx,
+            current: Cursor::new(Vec::new()),
+            position: 0,
+        },
+    )
+}
+
+/// Fails with `BrokenPipe` once the reader is dropped.
+pub(crate) struct PipeWriter {
+    tx: SyncSender<Vec<u8>>,
+    position: u64,
+}
+
+/// Reaches the end once the writer is dropped.
+pub(crate) struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+    position: u64,
+}
+
+impl PipeReader {
+    /// Makes `bytes` the next bytes to be read.
+    pub(crate) fn unread(&mut self, bytes: &[u8]) {
+        let rest = &self.current.get_ref()[self.current.position() as usize..];
+        let mut current = bytes.to_vec();
+        current.extend_from_slice(rest);
+        self.current = Cursor::new(current);
+        self.position -= bytes.len().min(self.position as usize) as u64;
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            match self.rx.recv() {
+                Ok(chunk) => self.current = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Pipe to nowhere, for checking that a file decrypts without keeping the result.
+#[derive(Default)]
+pub(crate) struct Discard {
+    position: u64,
+}
+
+impl Write for Discard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Discard {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        tell(self.position, pos)
+    }
+}
+
+/// Pipes only tell their position, they cannot seek.
+impl Seek for PipeWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        tell(self.position, pos)
+    }
+}
+
+impl Seek for PipeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        tell(self.position, pos)
+    }
+}
+
+fn tell(position: u64, pos: SeekFrom) -> io::Result<u64> {
+    match pos {
+        SeekFrom::Current(0) => Ok(position),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cannot seek in a pipe",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::pipe;
+
+    #[test]
+    fn pipe_test() {
+        let (mut writer, mut reader) = pipe();
+        let handle = std::thread::spawn(move || {
+            for i in 0..100u8 {
+                writer.write_all(&[i; 1000]).unwrap();
+            }
+        });
+
+        let mut head = [0u8; 1500];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(1500, reader.stream_position().unwrap());
+        reader.unread(b"xy");
+        assert!(reader.seek(SeekFrom::Start(0)).is_err());
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        handle.join().unwrap();
+        assert_eq!(b"xy", &rest[..2]);
+        assert_eq!(100 * 1000 - 1500, rest.len() - 2);
+        assert_eq!([1u8; 500], rest[2..502]);
+
+        // writing fails once the reader is gone
+        let (mut writer, reader) = pipe();
+        drop(reader);
+        assert!(writer.write_all(b"lost").is_err());
+    }
+}
@@ -1,20 +1,35 @@
 use std::{
-    cmp::max,
-    fs::{self, File},
-    io::{BufReader, BufWriter, Seek},
+    env,
+    fs::{self, create_dir_all, File},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::Arc,
+    time::Instant,
 };
 
 use anyhow::Result;
 use tempfile::NamedTempFile;
-use tokio::{
-    fs::metadata,
-    sync::mpsc::{channel, Receiver, Sender},
-    time::sleep,
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    default_output_path,
+    error::PathContext,
+    format::{self, Section},
+    output::{is_cancelled, is_same_path, Partial},
+    pack::{check_file, pack_file, unpack_file},
+    padding, parity, parity_path,
+    pipe::{pipe, Discard, PipeReader},
+    progress::{ProgressReader, ProgressWriter},
+    root_names,
+    segment::{self, Index, Segment},
+    signature::{self, signature_path, Digesting, SigningKey, TrustedKeys},
+    volume::{Output, VolumeReader},
+    volume_path, AlgorithmRead, AlgorithmWrite, Crypto, Error, ErrorKind, Filters, Header,
+    Metadata, OutputPolicy, Pack, PackFormat, Padding, Payload, Phase, Progress, ProgressEvent,
+    Summary,
 };
 
-use crate::{Crypto, Pack};
+mod mirror;
 
 const CAPACITY: usize = 8 * 1024 * 1024; // 8MiB
 
@@ -22,13 +37,21 @@ const CAPACITY: usize = 8 * 1024 * 1024; // 8MiB
 pub enum Target {
     Enc,
     Dec,
-}
-
-#[derive(Clone, Debug)]
-struct ProgressMessage {
-    // TODO: replace trait
-    process_target: PathBuf,
-    process_target_size: usize,
+    /// Re-encrypts a file of an older format, including headerless mkencbox 2.0 files,
+    /// in the current format.
+    Upgrade,
+    /// Repairs damaged parts of an encrypted file with its parity file, see `Process::parity`.
+    Repair,
+    /// Checks the signature of an encrypted file, see `Process::trusted_keys`,
+    /// and that it decrypts. Writes nothing.
+    Verify,
+    /// Compares the files of an encrypted file with those on disk, where `Target::Dec` would
+    /// unpack them, see `Pack::check`. Writes nothing.
+    Check,
+    /// Packs the input into a new segment at the end of an existing encrypted archive, the output,
+    /// without decrypting what it holds, see `segment`. Decrypting unpacks the segments in order,
+    /// so that appended entries replace earlier ones of the same name.
+    Append,
 }
 
 pub struct Process {
@@ -37,9 +60,23 @@ pub struct Process {
     crypto_algorithm: Box<dyn Crypto>,
 
     from_path: PathBuf,
-    to_path: PathBuf,
+    to_path: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
 
-    bypass_progress: Option<Sender<u8>>,
+    progress: Progress,
+    output_policy: OutputPolicy,
+    legacy: bool,
+    keep_root: bool,
+    siblings: Vec<PathBuf>,
+    unpackers: Vec<Box<dyn Pack>>,
+    mirror: bool,
+    filters: Filters,
+    encrypt_names: bool,
+    volume_size: Option<u64>,
+    padding: Padding,
+    parity: Option<u8>,
+    signing_key: Option<Arc<SigningKey>>,
+    trusted_keys: Option<Arc<TrustedKeys>>,
 }
 
 impl Process {
@@ -55,173 +92,1311 @@ impl Process {
             pack_algorithm,
             crypto_algorithm,
             from_path: from_path.into(),
-            to_path: to_path.into(),
-            bypass_progress: None,
+            to_path: Some(to_path.into()),
+            output_dir: None,
+            progress: Progress::none(),
+            output_policy: OutputPolicy::default(),
+            legacy: false,
+            keep_root: false,
+            siblings: Vec::new(),
+            unpackers: Vec::new(),
+            mirror: false,
+            filters: Filters::default(),
+            encrypt_names: false,
+            volume_size: None,
+            padding: Padding::None,
+            parity: None,
+            signing_key: None,
+            trusted_keys: None,
+        }
+    }
+
+    /// Like `new`, but the output path is chosen by `default_output_path`
+    /// once the stored original name is known.
+    pub fn with_default_output(
+        target: Target,
+        pack_algorithm: Box<dyn Pack>,
+        crypto_algorithm: Box<dyn Crypto>,
+        from_path: impl Into<PathBuf>,
+        output_dir: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            to_path: None,
+            output_dir,
+            ..Self::new(target, pack_algorithm, crypto_algorithm, from_path, "")
         }
     }
 
-    pub fn bypass_progress(self, tx: Sender<u8>) -> Self {
+    pub fn bypass_progress(self, tx: Sender<ProgressEvent>) -> Self {
         Self {
-            bypass_progress: Some(tx),
+            progress: Progress::new(tx),
             ..self
         }
     }
 
-    pub async fn execute(self) -> Result<(), Box<dyn std::error::Error>> {
-        if self.to_path.exists() {
-            let e = std::io::Error::new(
-                std::io::ErrorKind::AlreadyExists,
-                format!("{:?} already exists", self.to_path),
-            );
-            return Err(Box::new(e));
+    pub fn output_policy(self, output_policy: OutputPolicy) -> Self {
+        Self {
+            output_policy,
+            ..self
+        }
+    }
+
+    /// Reads the input as a headerless mkencbox 2.0 file even if it starts like a header.
+    /// Without this, inputs without a header are read that way anyway.
+    pub fn legacy(self, legacy: bool) -> Self {
+        Self { legacy, ..self }
+    }
+
+    /// Stores the input under its own name when encrypting, so that decrypting recreates it
+    /// inside the output directory, e.g. `project/` rather than the contents of `project/`.
+    pub fn keep_root(self, keep_root: bool) -> Self {
+        Self { keep_root, ..self }
+    }
+
+    /// Encrypts `siblings` into the same archive as the input, each under its own name.
+    /// Implies `keep_root`.
+    pub fn siblings(self, siblings: Vec<PathBuf>) -> Self {
+        Self { siblings, ..self }
+    }
+
+    /// Adds a packer for decrypting files packed in another format than `pack_algorithm`'s.
+    /// The format is recorded in each file.
+    pub fn unpacker(mut self, unpacker: Box<dyn Pack>) -> Self {
+        self.unpackers.push(unpacker);
+        self
+    }
+
+    /// Encrypts an input directory file by file into an output directory of the same shape,
+    /// so that only changed files differ from a previous run. An existing output directory is
+    /// only updated if it was mirrored with the same key and settings, otherwise `output_policy`
+    /// applies. Decrypting such a directory needs no option.
+    pub fn mirror(self, mirror: bool) -> Self {
+        Self { mirror, ..self }
+    }
+
+    /// Which entries of the input directory `mirror` encrypts. Archives are packed with the
+    /// filters of their `Pack` instead.
+    pub fn filters(self, filters: Filters) -> Self {
+        Self { filters, ..self }
+    }
+
+    /// With `mirror`, also encrypts file and directory names, so that the output only reveals
+    /// the shape of the tree and the sizes of the files. Decrypting needs no option.
+    pub fn encrypt_names(self, encrypt_names: bool) -> Self {
+        Self {
+            encrypt_names,
+            ..self
         }
-        match self.target {
-            Target::Enc => {
-                self.enc().await?;
+    }
+
+    /// Splits the encrypted output into volumes of `volume_size` bytes, named like the output
+    /// with `.001`, `.002`, ... appended. Decrypting takes any of the volumes, or the name
+    /// without number, and needs no option.
+    pub fn volume_size(self, volume_size: Option<u64>) -> Self {
+        Self {
+            volume_size,
+            ..self
+        }
+    }
+
+    /// Pads the encrypted region so that the size of the output hides the exact size of the
+    /// input. Decrypting strips the padding and needs no option.
+    pub fn padding(self, padding: Padding) -> Self {
+        Self { padding, ..self }
+    }
+
+    /// Writes Reed-Solomon parity of the encrypted output to `OUTPUT.par`, `redundancy` percent
+    /// of its size, so that `Target::Repair` can reconstruct damaged parts. Not for volumes.
+    pub fn parity(self, redundancy: Option<u8>) -> Self {
+        Self {
+            parity: redundancy,
+            ..self
+        }
+    }
+
+    /// Signs the encrypted output into `OUTPUT.sig`. Not for mirrors.
+    pub fn signing_key(self, signing_key: Option<Arc<SigningKey>>) -> Self {
+        Self {
+            signing_key,
+            ..self
+        }
+    }
+
+    /// Only decrypts inputs with a signature by one of `trusted_keys` in `INPUT.sig`,
+    /// checked before anything is unpacked. `Target::Verify` fails without them.
+    pub fn trusted_keys(self, trusted_keys: Option<Arc<TrustedKeys>>) -> Self {
+        Self {
+            trusted_keys,
+            ..self
+        }
+    }
+
+    pub fn from_path(&self) -> &Path {
+        &self.from_path
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Runs the process and returns the path of the written output.
+    pub async fn execute(self) -> Result<PathBuf, Error> {
+        // an existing directory may be where the roots of an archive go, which `dec` checks
+        let check_early = match (self.target, &self.to_path) {
+            (Target::Dec, Some(to_path)) => !to_path.is_dir(),
+            (Target::Dec, None) => false,
+            // appends need an existing output
+            (Target::Verify | Target::Check | Target::Append, _) => false,
+            // mirrors are updated in place
+            (Target::Enc, _) => !self.is_mirror(),
+            _ => true,
+        };
+        if check_early {
+            let to_path = self.resolve_to_path(None);
+            match self.volume_size {
+                Some(_) if self.target != Target::Dec => {
+                    self.check_output(&volume_path(&to_path, 1))?
+                }
+                _ => self.check_output(&to_path)?,
+            }
+            if self.parity.is_some() && self.target != Target::Dec {
+                self.check_output(&parity_path(&to_path))?;
+            }
+            if self.signing_key.is_some() && self.target != Target::Dec {
+                self.check_output(&signature_path(&to_path))?;
             }
-            Target::Dec => {
-                self.dec().await?;
+        }
+        let mirrored = self.is_mirror() || (self.target == Target::Dec && self.from_path.is_dir());
+        if mirrored && (self.signing_key.is_some() || self.trusted_keys.is_some()) {
+            return Err(
+                Error::new(ErrorKind::BadSignature, "mirrors are not signed")
+                    .with_path(&self.from_path),
+            );
+        }
+        let fallback = match self.target {
+            Target::Enc | Target::Append => ErrorKind::EncryptionError,
+            Target::Dec | Target::Upgrade | Target::Repair | Target::Verify | Target::Check => {
+                ErrorKind::DecryptionError
             }
         };
+        tokio::task::spawn_blocking(move || match self.target {
+            Target::Enc if self.is_mirror() => self.mirror_enc(),
+            Target::Enc => self.enc(),
+            Target::Dec if self.from_path.is_dir() => self.mirror_dec(),
+            Target::Dec => self.dec(),
+            Target::Upgrade => self.upgrade(),
+            Target::Repair => self.repair(),
+            Target::Verify => self.verify(),
+            Target::Check => self.check(),
+            Target::Append => self.append(),
+        })
+        .await
+        .map_err(|e| Error::new(fallback, e))?
+    }
+
+    fn resolve_to_path(&self, metadata: Option<&Metadata>) -> PathBuf {
+        match &self.to_path {
+            Some(to_path) => to_path.clone(),
+            // roots keep their names, so they go straight into the directory
+            None if metadata.is_some_and(|m| !m.roots.is_empty()) => match &self.output_dir {
+                Some(dir) => dir.clone(),
+                None => match self.from_path.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                    _ => PathBuf::from("."),
+                },
+            },
+            None => default_output_path(
+                self.target,
+                &self.from_path,
+                metadata.and_then(|m| m.original_name.as_deref()),
+                self.output_dir.as_deref(),
+            ),
+        }
+    }
+
+    fn create_output_dir(&self) -> Result<(), Error> {
+        match &self.output_dir {
+            Some(dir) if self.to_path.is_none() => create_dir_all(dir).with_path(dir),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_output(&self, to_path: &Path) -> Result<(), Error> {
+        let keep_existing = match self.output_policy {
+            OutputPolicy::Fail => true,
+            OutputPolicy::Overwrite => false,
+            OutputPolicy::Merge(_) => self.target != Target::Dec,
+        };
+        if keep_existing && to_path.exists() && !self.is_in_place(to_path) {
+            return Err(Error::from(ErrorKind::OutputExists).with_path(to_path));
+        }
         Ok(())
     }
 
-    async fn enc(self) -> Result<(), Box<dyn std::error::Error>> {
-        let (mtx, mrx) = channel::<ProgressMessage>(4);
-        let tx = self.bypass_progress.clone();
-        if let Some(tx) = tx {
-            self.spawn_progress(mrx, tx).await;
+    /// Whether an upgrade or a repair replaces its input.
+    fn is_in_place(&self, to_path: &Path) -> bool {
+        matches!(self.target, Target::Upgrade | Target::Repair)
+            && is_same_path(to_path, &self.from_path)
+    }
+
+    /// Policy for moving a single output file into place.
+    fn file_output_policy(&self, to_path: &Path) -> OutputPolicy {
+        match self.output_policy {
+            _ if self.is_in_place(to_path) => OutputPolicy::Overwrite,
+            OutputPolicy::Merge(_) => OutputPolicy::Fail,
+            policy => policy,
+        }
+    }
+
+    fn read_header<R: Read + Seek>(&self, reader: &mut R) -> Result<Header, Error> {
+        if self.legacy {
+            return Ok(Header::legacy());
+        }
+        Ok(format::read_header(reader)
+            .map_err(|e| e.with_path(&self.from_path))?
+            .unwrap_or_else(Header::legacy))
+    }
+
+    fn unpacker_for(&self, format: PackFormat) -> Result<&dyn Pack, Error> {
+        std::iter::once(&self.pack_algorithm)
+            .chain(&self.unpackers)
+            .find(|p| p.format() == format)
+            .map(|p| p.as_ref())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnsupportedVersion,
+                    format!("no unpacker for {format:?} archives"),
+                )
+                .with_path(&self.from_path)
+            })
+    }
+
+    /// Opens the input, which may be a set of volumes. Returns it with its size.
+    fn open_input(&self) -> Result<(Box<dyn AlgorithmRead + Send>, u64), Error> {
+        if let Some(volumes) = VolumeReader::open(&self.from_path)? {
+            let len = volumes.volumes_len();
+            return Ok((Box::new(volumes), len));
+        }
+        let src = File::open(&self.from_path).with_path(&self.from_path)?;
+        let len = src.metadata().with_path(&self.from_path)?.len();
+        Ok((Box::new(src), len))
+    }
+
+    /// Opens the input and hands it to `read` with its size, checking the signature of the input
+    /// if `trusted_keys` are given. The input is hashed as `read` reads it, and the signature is
+    /// checked once `read` is done, so its result must not be used before. A bad signature is
+    /// reported over the errors of `read`, which a tampered input causes as well.
+    fn read_input<T>(
+        &self,
+        read: impl FnOnce(Box<dyn AlgorithmRead + Send + '_>, u64) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let (src, bytes_in) = self.open_input()?;
+        let Some(trusted_keys) = &self.trusted_keys else {
+            return read(src, bytes_in);
+        };
+        // volumes share one signature
+        let base = match VolumeReader::open(&self.from_path)? {
+            Some(volumes) => volumes.base(),
+            None => self.from_path.clone(),
+        };
+        let path = signature_path(&base);
+        let signature = fs::read(&path).map_err(|_| {
+            Error::new(ErrorKind::BadSignature, "no signature").with_path(&self.from_path)
+        })?;
+        let mut input = Digesting::new(src);
+        let read = read(Box::new(&mut input), bytes_in);
+        let digest = input.finish().map_err(|e| {
+            Error::from_anyhow(e.into(), ErrorKind::Io, &self.from_path).with_path(&self.from_path)
+        })?;
+        trusted_keys
+            .verify(&signature, &digest)
+            .map_err(|e| e.with_path(&self.from_path))?;
+        read
+    }
+
+    /// Decrypts `reader` to nowhere, which checks it entirely if it is authenticated.
+    fn check_decryption<R: Read + Seek + Send>(&self, reader: &mut R) -> Result<(), Error> {
+        let header = self.read_header(reader)?;
+        let segments = match self.read_index(reader, &header, &self.from_path)? {
+            Some(index) => index.segments.into_iter().map(Some).collect(),
+            None if header.is_authenticated() => {
+                let metadata = self.read_metadata(&header, reader, None, &self.from_path)?;
+                check_indexed(&metadata, false, &self.from_path)?;
+                vec![None]
+            }
+            None => vec![None],
         };
+        for segment in &segments {
+            self.decrypt_segment(&header, reader, segment.as_ref(), &mut Discard::default())
+                .map_err(|e| Error::from_anyhow(e, ErrorKind::Corrupted, &self.from_path))?;
+        }
+        Ok(())
+    }
 
-        let r: anyhow::Result<()> = tokio::task::spawn_blocking(move || {
-            let tmp = NamedTempFile::new()?;
-            let dst = File::create(&self.to_path)?;
+    /// Reads the index of a file that entries can be appended to, see `segment`,
+    /// or `None` if there is none. The position of `reader` is kept.
+    fn read_index<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        header: &Header,
+        path: &Path,
+    ) -> Result<Option<Index>, Error> {
+        if !header.is_authenticated() {
+            return Ok(None);
+        }
+        let Some(range) = segment::find_index(reader).with_path(path)? else {
+            return Ok(None);
+        };
+        let start = reader.stream_position().with_path(path)?;
+        reader.seek(SeekFrom::Start(range.start)).with_path(path)?;
+        let mut bytes = Cursor::new(Vec::new());
+        self.crypto_algorithm
+            .decrypt(
+                header,
+                &mut Section::with_len(&mut *reader, range.end - range.start).with_path(path)?,
+                &mut bytes,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::Corrupted, path))?;
+        let index = Index::from_bytes(bytes.get_ref()).map_err(|e| e.with_path(path))?;
+        index
+            .check(reader, start, range.start)
+            .map_err(|e| e.with_path(path))?;
+        reader.seek(SeekFrom::Start(start)).with_path(path)?;
+        Ok(Some(index))
+    }
 
-            if self.bypass_progress.is_some() {
-                let _ = mtx.blocking_send(ProgressMessage {
-                    process_target: tmp.path().to_path_buf(),
-                    process_target_size: get_fs_size(&self.from_path).unwrap_or(1),
-                });
+    /// Decrypts `segment` of `reader`, or everything after the header without segments.
+    fn decrypt_segment<R: Read + Seek>(
+        &self,
+        header: &Header,
+        reader: &mut R,
+        segment: Option<&Segment>,
+        writer: &mut dyn AlgorithmWrite,
+    ) -> Result<()> {
+        let mut section = match segment {
+            Some(segment) => {
+                reader.seek(SeekFrom::Start(segment.range.start))?;
+                Section::with_len(reader, segment.range.end - segment.range.start)?
             }
+            None => Section::new(reader)?,
+        };
+        self.crypto_algorithm.decrypt(header, &mut section, writer)
+    }
 
-            let mut writer = BufWriter::with_capacity(CAPACITY, tmp);
+    /// Unpacks `reader`, the payload `metadata` describes, into `out_path`.
+    fn unpack(
+        &self,
+        metadata: &Metadata,
+        reader: &mut dyn AlgorithmRead,
+        out_path: &Path,
+        progress: &Progress,
+    ) -> Result<()> {
+        let unpacker = self.unpacker_for(metadata.pack_format)?;
+        match metadata.payload {
+            Some(Payload::File) => Ok(unpack_file(reader, out_path)?),
+            Some(Payload::Archive) => unpacker.decompression_verified(reader, out_path, progress),
+            // payloads of headerless files are single files unless they read as an archive
+            None => match unpacker.decompression(reader, out_path, progress) {
+                Err(e) if is_not_archive(&e) => {
+                    if out_path.is_dir() {
+                        fs::remove_dir(out_path)?;
+                    }
+                    reader.rewind()?;
+                    Ok(unpack_file(reader, out_path)?)
+                }
+                unpacked => unpacked,
+            },
+        }
+    }
+
+    /// Compares the files of `reader`, the payload `metadata` describes, with those in `path`,
+    /// see `Pack::check`.
+    fn check_payload(
+        &self,
+        metadata: &Metadata,
+        reader: &mut dyn AlgorithmRead,
+        path: &Path,
+        progress: &Progress,
+    ) -> Result<u64> {
+        let unpacker = self.unpacker_for(metadata.pack_format)?;
+        match metadata.payload {
+            Some(Payload::File) => Ok(check_file(reader, path, progress)?),
+            Some(Payload::Archive) => unpacker.check(reader, path, progress),
+            None => match unpacker.check(reader, path, progress) {
+                Err(e) if is_not_archive(&e) => {
+                    reader.rewind()?;
+                    Ok(check_file(reader, path, progress)?)
+                }
+                checked => checked,
+            },
+        }
+    }
+
+    fn is_mirror(&self) -> bool {
+        self.mirror && self.from_path.is_dir()
+    }
+
+    fn is_rooted(&self) -> bool {
+        self.keep_root || !self.siblings.is_empty()
+    }
+
+    fn enc(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        let tmp = NamedTempFile::new().with_path(env::temp_dir())?;
+        let tmp_path = tmp.path().to_path_buf();
+        let _tmp_guard = Partial::register(&tmp_path);
+        self.create_output_dir()?;
+        let output = Output::create(&to_path, self.volume_size)?;
 
-            self.pack_algorithm
-                .compression(self.from_path.as_path(), &mut writer)
-                .unwrap();
+        let in_paths: Vec<PathBuf> = std::iter::once(self.from_path.clone())
+            .chain(self.siblings.iter().cloned())
+            .collect();
+        let bytes_in = in_paths
+            .iter()
+            .map(|p| get_fs_size(p).unwrap_or(0) as u64)
+            .sum();
+        progress.phase(Phase::Packing, bytes_in);
 
-            let mut tmp = writer.into_inner()?;
-            tmp.rewind().unwrap();
+        let mut writer = ProgressWriter::new(BufWriter::with_capacity(CAPACITY, tmp), progress);
+
+        let payload = match !self.is_rooted() && self.from_path.is_file() {
+            true => Payload::File,
+            false => Payload::Archive,
+        };
+        let metadata = Metadata {
+            original_name: self
+                .from_path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned()),
+            roots: match self.is_rooted() {
+                true => root_names(&in_paths).with_path(&self.from_path)?,
+                false => Vec::new(),
+            },
+            pack_format: self.pack_algorithm.format(),
+            padded: !self.padding.is_none(),
+            payload: Some(payload),
+            indexed: payload == Payload::Archive
+                && self.pack_algorithm.format() == PackFormat::Tar
+                && self.volume_size.is_none(),
+        };
+        metadata.write(&mut writer).with_path(&tmp_path)?;
 
-            if self.bypass_progress.is_some() {
-                let _ = mtx.blocking_send(ProgressMessage {
-                    process_target: self.to_path.clone(),
-                    process_target_size: get_fs_size(tmp.path()).unwrap_or(1),
-                });
+        let mut section = Section::new(&mut writer).with_path(&tmp_path)?;
+        match payload {
+            Payload::File => pack_file(&self.from_path, &mut section, progress).map_err(Into::into),
+            Payload::Archive if self.is_rooted() => {
+                self.pack_algorithm
+                    .compression_roots(&in_paths, &mut section, progress)
+            }
+            Payload::Archive => {
+                self.pack_algorithm
+                    .compression(&self.from_path, &mut section, progress)
             }
+        }
+        .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, &self.from_path))?;
+
+        let mut tmp = writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&tmp_path)?;
+        if metadata.padded {
+            self.padding.pad(&mut tmp).with_path(&tmp_path)?;
+        }
+        tmp.rewind().with_path(&tmp_path)?;
+
+        progress.phase(
+            Phase::Encrypting,
+            get_fs_size(&tmp_path).unwrap_or(0) as u64,
+        );
 
-            let mut reader = BufReader::with_capacity(CAPACITY, tmp);
-            let mut writer = BufWriter::with_capacity(CAPACITY, dst);
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, tmp), progress);
+        let out_path = output.path().to_path_buf();
+        let mut writer = BufWriter::with_capacity(CAPACITY, output);
+        let header = Header::current();
+        format::write_header(&mut writer, &header).with_path(&out_path)?;
+        let start = writer.stream_position().with_path(&out_path)?;
 
-            self.crypto_algorithm
-                .encrypt(&mut reader, &mut writer)
-                .unwrap();
+        self.crypto_algorithm
+            .encrypt(
+                &header,
+                &mut reader,
+                &mut Section::new(&mut writer).with_path(&out_path)?,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, &to_path))?;
+
+        let mut output = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&out_path)?;
+        if let (true, Output::File(_, file)) = (metadata.indexed, &mut output) {
+            let end = file.stream_position().with_path(&out_path)?;
+            let index = Index {
+                segments: vec![Segment::read(file, start..end).map_err(|e| e.with_path(&out_path))?],
+                roots: Vec::new(),
+            };
+            self.write_index(&header, &index, file, end, &out_path)?;
+        }
+        let (to_path, bytes_out) = self.persist_output(output, &to_path)?;
 
-            Ok(())
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Decrypts the input into a temporary file, positioned after the metadata and without padding.
+    fn decrypt_to_temp(&self) -> Result<Decrypted, Error> {
+        self.read_input(|src, bytes_in| self.decrypt_input(src, bytes_in))
+    }
+
+    /// Decrypts `src`, the input of `bytes_in` bytes, see `decrypt_to_temp`.
+    fn decrypt_input(
+        &self,
+        src: Box<dyn AlgorithmRead + Send + '_>,
+        bytes_in: u64,
+    ) -> Result<Decrypted, Error> {
+        let progress = &self.progress;
+        let tmp = NamedTempFile::new().with_path(env::temp_dir())?;
+        let tmp_path = tmp.path().to_path_buf();
+        let guard = Partial::register(&tmp_path);
+
+        progress.phase(Phase::Decrypting, bytes_in);
+
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, src), progress);
+        let header = self.read_header(&mut reader)?;
+        let index = self.read_index(&mut reader, &header, &self.from_path)?;
+        let mut writer = BufWriter::with_capacity(CAPACITY, tmp);
+
+        let first = index.as_ref().map(|index| &index.segments[0]);
+        self.decrypt_segment(&header, &mut reader, first, &mut writer)
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::DecryptionError, &self.from_path))?;
+
+        let mut tmp = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&tmp_path)?;
+        tmp.rewind().with_path(&tmp_path)?;
+
+        // files without a header have no metadata either
+        let mut metadata = if !header.is_legacy() {
+            Metadata::read(&mut tmp).map_err(|e| e.with_path(&self.from_path))?
+        } else {
+            Metadata::default()
+        };
+        check_indexed(&metadata, index.is_some(), &self.from_path)?;
+        if metadata.padded {
+            padding::strip(tmp.as_file_mut()).map_err(|e| e.with_path(&self.from_path))?;
+        }
+
+        // appended segments hold tar archives that follow the one of the first segment
+        if let Some(index) = index {
+            let position = tmp.stream_position().with_path(&tmp_path)?;
+            tmp.seek(SeekFrom::End(0)).with_path(&tmp_path)?;
+            let mut writer = BufWriter::with_capacity(CAPACITY, tmp);
+            for segment in &index.segments[1..] {
+                self.decrypt_segment(&header, &mut reader, Some(segment), &mut writer)
+                    .map_err(|e| {
+                        Error::from_anyhow(e, ErrorKind::DecryptionError, &self.from_path)
+                    })?;
+            }
+            tmp = writer
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .with_path(&tmp_path)?;
+            tmp.seek(SeekFrom::Start(position)).with_path(&tmp_path)?;
+            for root in index.roots {
+                if !metadata.roots.contains(&root) {
+                    metadata.roots.push(root);
+                }
+            }
+        }
+        drop(reader);
+
+        Ok(Decrypted {
+            file: tmp,
+            metadata,
+            bytes_in,
+            _guard: guard,
         })
-        .await?;
-        r?;
-        Ok(())
     }
 
-    async fn dec(self) -> Result<(), Box<dyn std::error::Error>> {
-        let (mtx, mrx) = channel::<ProgressMessage>(4);
-        let tx = self.bypass_progress.clone();
-        if let Some(tx) = tx {
-            self.spawn_progress(mrx, tx).await;
+    fn dec(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let Decrypted {
+            file: tmp,
+            metadata,
+            bytes_in,
+            _guard,
+        } = self.decrypt_to_temp()?;
+        let tmp_path = tmp.path().to_path_buf();
+
+        progress.phase(Phase::Unpacking, get_fs_size(&tmp_path).unwrap_or(0) as u64);
+
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, tmp), progress);
+        let to_path = self.resolve_to_path(Some(&metadata));
+        let roots = roots(&metadata).map_err(|e| e.with_path(&self.from_path))?;
+        let partial = match roots.first() {
+            None => {
+                self.check_output(&to_path)?;
+                self.create_output_dir()?;
+                Partial::sibling(&to_path)
+            }
+            // roots are unpacked side by side, then moved into the output directory one by one
+            Some(first) => {
+                for root in &roots {
+                    self.check_output(&to_path.join(root))?;
+                }
+                create_dir_all(&to_path).with_path(&to_path)?;
+                Partial::sibling(&to_path.join(first))
+            }
         };
 
-        let r: anyhow::Result<()> = tokio::task::spawn_blocking(move || {
-            let src = File::open(&self.from_path)?;
-            let tmp = NamedTempFile::new()?;
+        self.unpack(
+            &metadata,
+            &mut Section::new(&mut reader).with_path(&tmp_path)?,
+            partial.path(),
+            progress,
+        )
+        .map_err(|e| Error::from_anyhow(e, ErrorKind::DecryptionError, &to_path))?;
+        drop(reader);
+
+        let bytes_out = get_fs_size(partial.path()).unwrap_or(0) as u64;
+        match roots.is_empty() {
+            true => partial.persist_with(&to_path, self.output_policy)?,
+            false => partial.persist_children(&to_path, self.output_policy)?,
+        }
+
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Decrypts and re-encrypts in one pass, through a pipe between two threads.
+    fn upgrade(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        if self.is_in_place(&to_path) && VolumeReader::open(&self.from_path)?.is_some() {
+            return Err(Error::new(
+                ErrorKind::EncryptionError,
+                "volumes cannot be upgraded in place, give an output path",
+            )
+            .with_path(&self.from_path));
+        }
+        let (output, bytes_in) = self.read_input(|src, bytes_in| {
+            Ok((self.upgrade_input(src, bytes_in, &to_path)?, bytes_in))
+        })?;
+        let (to_path, bytes_out) = self.persist_output(output, &to_path)?;
+
+        progress.finish(Summary {
+            files: 0,
+            bytes_in,
+            bytes_out,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Re-encrypts `src`, the input of `bytes_in` bytes, into an output for `to_path`,
+    /// see `upgrade`.
+    fn upgrade_input(
+        &self,
+        src: Box<dyn AlgorithmRead + Send + '_>,
+        bytes_in: u64,
+        to_path: &Path,
+    ) -> Result<Output, Error> {
+        let progress = &self.progress;
+        progress.phase(Phase::Upgrading, bytes_in);
+
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, src), progress);
+        let old_header = self.read_header(&mut reader)?;
+        if self
+            .read_index(&mut reader, &old_header, &self.from_path)?
+            .is_some()
+        {
+            return Err(Error::new(
+                ErrorKind::EncryptionError,
+                "files with a segment index are in the current format already",
+            )
+            .with_path(&self.from_path));
+        }
+        if old_header.is_authenticated() {
+            let metadata = self.read_metadata(&old_header, &mut reader, None, &self.from_path)?;
+            check_indexed(&metadata, false, &self.from_path)?;
+        }
+        self.create_output_dir()?;
+        let output = Output::create(to_path, self.volume_size)?;
+        let out_path = output.path().to_path_buf();
+        let mut writer = BufWriter::with_capacity(CAPACITY, output);
+        let header = Header::current();
+        format::write_header(&mut writer, &header).with_path(&out_path)?;
 
-            if self.bypass_progress.is_some() {
-                let _ = mtx.blocking_send(ProgressMessage {
-                    process_target: tmp.path().into(),
-                    process_target_size: get_fs_size(&self.from_path).unwrap_or(1),
-                });
+        let (mut pipe_writer, pipe_reader) = pipe();
+        let crypto_algorithm = &self.crypto_algorithm;
+        let (decrypted, encrypted) = std::thread::scope(|s| {
+            let decrypt = s.spawn(move || -> Result<()> {
+                let mut reader = Section::new(&mut reader)?;
+                crypto_algorithm.decrypt(&old_header, &mut reader, &mut pipe_writer)
+            });
+            let encrypted =
+                self.reencrypt(&old_header, &header, pipe_reader, &mut writer, &out_path);
+            (decrypt.join(), encrypted)
+        });
+        match decrypted {
+            Ok(Err(e)) if !is_broken_pipe(&e) => {
+                return Err(Error::from_anyhow(
+                    e,
+                    ErrorKind::DecryptionError,
+                    &self.from_path,
+                ))
+            }
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::DecryptionError,
+                    "decryption thread panicked",
+                ))
             }
+            _ => {}
+        }
+        encrypted.map_err(|e| e.with_path(to_path))?;
 
-            let mut reader = BufReader::with_capacity(CAPACITY, src);
-            let mut writer = BufWriter::with_capacity(CAPACITY, tmp);
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&out_path)
+    }
+
+    /// Copies the input into the output, repairing damaged parts with the parity file of the input,
+    /// and checks that the result decrypts.
+    fn repair(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        let mut src = File::open(&self.from_path).with_path(&self.from_path)?;
+        let from_parity = parity_path(&self.from_path);
+        let mut parity = File::open(&from_parity).with_path(&from_parity)?;
 
-            self.crypto_algorithm.decrypt(&mut reader, &mut writer)?;
+        let bytes_in = src.metadata().with_path(&self.from_path)?.len();
+        progress.phase(Phase::Repairing, bytes_in);
 
-            let mut tmp = writer.into_inner()?;
-            tmp.rewind()?;
+        self.create_output_dir()?;
+        let partial = Partial::sibling(&to_path);
+        let mut dst = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(partial.path())
+            .with_path(partial.path())?;
+        let report = parity::repair(&mut src, &mut parity, &mut dst, progress)
+            .map_err(|e| e.with_path(&self.from_path))?;
+        drop(src);
+        if report.repaired > 0 {
+            progress.warning(format!(
+                "repaired {} damaged chunks of {}",
+                report.repaired,
+                self.from_path.display()
+            ));
+        }
+
+        progress.phase(Phase::Decrypting, bytes_in);
+        dst.rewind().with_path(partial.path())?;
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, dst), progress);
+        self.check_decryption(&mut reader)?;
+        drop(reader);
 
-            if self.bypass_progress.is_some() {
-                let _ = mtx.blocking_send(ProgressMessage {
-                    process_target: self.to_path.clone(),
-                    process_target_size: get_fs_size(tmp.path()).unwrap_or(1),
-                });
+        // the parity file still fits the repaired file, unless it was damaged itself
+        let to_parity = parity_path(&to_path);
+        let parity_partial = match report.parity_damaged || !self.is_in_place(&to_path) {
+            true => {
+                let parity_partial = Partial::sibling(&to_parity);
+                parity::write_parity(
+                    partial.path(),
+                    parity_partial.path(),
+                    report.redundancy,
+                    &Progress::none(),
+                )?;
+                Some(parity_partial)
             }
+            false => None,
+        };
+        let policy = self.file_output_policy(&to_path);
+        partial.persist_with(&to_path, policy)?;
+        if let Some(parity_partial) = parity_partial {
+            parity_partial.persist_with(&to_parity, policy)?;
+        }
 
-            let mut reader = BufReader::with_capacity(CAPACITY, tmp);
+        progress.finish(Summary {
+            files: 0,
+            bytes_in,
+            bytes_out: bytes_in,
+            elapsed: started.elapsed(),
+        });
 
-            self.pack_algorithm
-                .decompression(&mut reader, &self.to_path)?;
+        Ok(to_path)
+    }
 
-            Ok(())
-        })
-        .await?;
-        r?;
-        Ok(())
+    /// Checks that the input decrypts, and its signature without trusting any key if none are
+    /// given, so that the error names the key that signed.
+    fn verify(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let verifier = Self {
+            trusted_keys: Some(self.trusted_keys.clone().unwrap_or_default()),
+            ..self
+        };
+        let progress = &verifier.progress;
+        let bytes_in = verifier.read_input(|src, bytes_in| {
+            progress.phase(Phase::Decrypting, bytes_in);
+            let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, src), progress);
+            verifier.check_decryption(&mut reader)?;
+            Ok(bytes_in)
+        })?;
+
+        progress.finish(Summary {
+            files: 0,
+            bytes_in,
+            bytes_out: 0,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(verifier.from_path)
     }
 
-    async fn spawn_progress(&self, mut mrx: Receiver<ProgressMessage>, tx: Sender<u8>) {
-        tokio::spawn(async move {
-            let first_phase = mrx.recv().await.unwrap();
-            loop {
-                sleep(Duration::from_millis(100)).await;
+    /// Compares the files of the input with those where `dec` would unpack them.
+    fn check(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        if self.from_path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                "mirrors have no manifest, decrypt them instead",
+            )
+            .with_path(&self.from_path));
+        }
+        let Decrypted {
+            file: tmp,
+            metadata,
+            bytes_in,
+            _guard,
+        } = self.decrypt_to_temp()?;
+        let tmp_path = tmp.path().to_path_buf();
+        let to_path = self.resolve_to_path(Some(&metadata));
 
-                let meta = metadata(&first_phase.process_target).await.unwrap();
-                let need = max(first_phase.process_target_size, 1) as f64 * 0.98;
-                let curr = meta.len() as f64;
+        progress.phase(Phase::Checking, get_fs_size(&tmp_path).unwrap_or(0) as u64);
 
-                if need <= curr {
-                    break;
-                }
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, tmp), progress);
+        let differences = self
+            .check_payload(
+                &metadata,
+                &mut Section::new(&mut reader).with_path(&tmp_path)?,
+                &to_path,
+                progress,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::DecryptionError, &to_path))?;
+        drop(reader);
+        if differences > 0 {
+            return Err(Error::new(
+                ErrorKind::Mismatch,
+                format!("{differences} files are missing or differ"),
+            )
+            .with_path(&to_path));
+        }
 
-                let progress = ((curr / need) * u8::MAX as f64).round() as u8;
-                let progress = progress / 2;
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out: 0,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Packs the input into a new segment after those of the output, see `segment`, and writes
+    /// the index again after it. Only the start of the output is decrypted, which checks the key.
+    /// The segments of the output are copied next to it, and the copy replaces it once complete.
+    fn append(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        let refuse = |message: &str| {
+            Err(Error::new(ErrorKind::EncryptionError, message.to_string()).with_path(&to_path))
+        };
+        if self.mirror {
+            return refuse("mirrors cannot be appended to, encrypt them again instead");
+        }
+        if !self.padding.is_none() {
+            return refuse("appended entries cannot be padded");
+        }
+        if self.volume_size.is_some() || VolumeReader::open(&to_path)?.is_some() {
+            return refuse("volumes cannot be appended to");
+        }
+        // both would no longer fit the file
+        if self.signing_key.is_none() && signature_path(&to_path).exists() {
+            return Err(Error::new(
+                ErrorKind::BadSignature,
+                "the file is signed, give a signing key to sign it again",
+            )
+            .with_path(&to_path));
+        }
+        if self.parity.is_none() && parity_path(&to_path).exists() {
+            return refuse("the file has a parity file, give a redundancy to write it again");
+        }
 
-                tx.send(progress).await.unwrap();
+        let mut file = File::open(&to_path).with_path(&to_path)?;
+        let header = match format::read_header(&mut file).map_err(|e| e.with_path(&to_path))? {
+            Some(header) if header.is_authenticated() => header,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::UnsupportedVersion,
+                    "only files of the current format can be appended to, upgrade it first",
+                )
+                .with_path(&to_path))
             }
-            let second_phase = mrx.recv().await.unwrap();
-            loop {
-                sleep(Duration::from_millis(100)).await;
-
-                let len = if second_phase.process_target.is_dir() {
-                    get_fs_size(&second_phase.process_target).unwrap() as u64
-                } else {
-                    metadata(&second_phase.process_target).await.unwrap().len()
-                };
-                let need = max(second_phase.process_target_size, 1) as f64 * 0.98;
-                let curr = len as f64;
-
-                if need <= curr {
-                    tx.send(u8::MAX).await.unwrap();
-                    break;
+        };
+        let start = file.stream_position().with_path(&to_path)?;
+        let len = file.metadata().with_path(&to_path)?.len();
+        let index = self.read_index(&mut file, &header, &to_path)?;
+        let first = match &index {
+            Some(index) => index.segments[0].clone(),
+            None => Segment::read(&mut file, start..len).map_err(|e| e.with_path(&to_path))?,
+        };
+        let metadata = self.read_metadata(&header, &mut file, Some(&first), &to_path)?;
+        check_indexed(&metadata, index.is_some(), &to_path)?;
+        if !metadata.indexed {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                "only tar archives of directories written with a segment index can be appended to",
+            )
+            .with_path(&to_path));
+        }
+        // an indexed file always has its index, see `check_indexed`
+        let mut index = index.unwrap_or_default();
+
+        let in_paths: Vec<PathBuf> = std::iter::once(self.from_path.clone())
+            .chain(self.siblings.iter().cloned())
+            .collect();
+        let bytes_in = in_paths
+            .iter()
+            .map(|p| get_fs_size(p).unwrap_or(0) as u64)
+            .sum();
+        progress.phase(Phase::Packing, bytes_in);
+
+        let tmp = NamedTempFile::new().with_path(env::temp_dir())?;
+        let tmp_path = tmp.path().to_path_buf();
+        let _tmp_guard = Partial::register(&tmp_path);
+        let mut writer = ProgressWriter::new(BufWriter::with_capacity(CAPACITY, tmp), progress);
+        // entries go where `dec` unpacks those of the archive: next to its roots if it has any,
+        // otherwise into the directory it restores
+        let rooted = !metadata.roots.is_empty();
+        let packer = self.unpacker_for(PackFormat::Tar)?;
+        let mut section = Section::new(&mut writer).with_path(&tmp_path)?;
+        match rooted || !self.siblings.is_empty() || !self.from_path.is_dir() {
+            true => packer.compression_roots(&in_paths, &mut section, progress),
+            false => packer.compression(&self.from_path, &mut section, progress),
+        }
+        .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, &self.from_path))?;
+        if rooted {
+            for root in root_names(&in_paths).with_path(&self.from_path)? {
+                if !metadata.roots.contains(&root) && !index.roots.contains(&root) {
+                    index.roots.push(root);
                 }
+            }
+        }
+
+        let mut tmp = writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&tmp_path)?;
+        tmp.rewind().with_path(&tmp_path)?;
+
+        progress.phase(
+            Phase::Encrypting,
+            get_fs_size(&tmp_path).unwrap_or(0) as u64,
+        );
 
-                let progress = ((curr / need) * u8::MAX as f64).round() as u8;
-                let progress = u8::MAX / 2 + progress / 2;
+        // the new segment replaces the index in a copy of the output
+        let end = index.segments.last().map_or(len, |s| s.range.end);
+        let partial = Partial::sibling(&to_path);
+        let mut copy = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(partial.path())
+            .with_path(partial.path())?;
+        file.rewind().with_path(&to_path)?;
+        std::io::copy(&mut (&mut file).take(end), &mut copy).with_path(partial.path())?;
+        let permissions = file.metadata().with_path(&to_path)?.permissions();
+        copy.set_permissions(permissions)
+            .with_path(partial.path())?;
+        drop(file);
+        let mut reader = ProgressReader::new(BufReader::with_capacity(CAPACITY, tmp), progress);
+        let bytes_out = self.append_segment(
+            &header,
+            &mut reader,
+            &mut copy,
+            end,
+            &mut index,
+            partial.path(),
+        )? - len;
+        drop(copy);
 
-                tx.send(progress.clamp(u8::MAX / 2, u8::MAX)).await.unwrap();
+        let signature = match &self.signing_key {
+            Some(key) => {
+                let mut file = File::open(partial.path()).with_path(partial.path())?;
+                let digest = signature::digest(&mut file).with_path(partial.path())?;
+                let signature = Partial::sibling(&signature_path(&to_path));
+                fs::write(signature.path(), key.sign(&digest)).with_path(signature.path())?;
+                Some(signature)
             }
+            None => None,
+        };
+        let parity = match self.parity {
+            Some(redundancy) => {
+                let parity = Partial::sibling(&parity_path(&to_path));
+                parity::write_parity(partial.path(), parity.path(), redundancy, &Progress::none())?;
+                Some(parity)
+            }
+            None => None,
+        };
+        partial.persist_with(&to_path, OutputPolicy::Overwrite)?;
+        if let Some(signature) = signature {
+            signature.persist_with(&signature_path(&to_path), OutputPolicy::Overwrite)?;
+        }
+        if let Some(parity) = parity {
+            parity.persist_with(&parity_path(&to_path), OutputPolicy::Overwrite)?;
+        }
+
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out,
+            elapsed: started.elapsed(),
         });
+
+        Ok(to_path)
     }
+
+    /// Decrypts the metadata at the start of `segment` of `reader`, or after the header without
+    /// segments, and returns it. The position of `reader` is kept.
+    fn read_metadata<R: Read + Seek + Send>(
+        &self,
+        header: &Header,
+        reader: &mut R,
+        segment: Option<&Segment>,
+        path: &Path,
+    ) -> Result<Metadata, Error> {
+        let start = reader.stream_position().with_path(path)?;
+        let (mut pipe_writer, mut pipe_reader) = pipe();
+        let (decrypted, metadata) = std::thread::scope(|s| {
+            let reader = &mut *reader;
+            let decrypt =
+                s.spawn(move || self.decrypt_segment(header, reader, segment, &mut pipe_writer));
+            let metadata = Metadata::read(&mut pipe_reader);
+            // stops the decryption
+            drop(pipe_reader);
+            (decrypt.join(), metadata)
+        });
+        match decrypted {
+            Ok(Err(e)) if !is_broken_pipe(&e) => {
+                return Err(Error::from_anyhow(e, ErrorKind::DecryptionError, path))
+            }
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::DecryptionError,
+                    "decryption thread panicked",
+                ))
+            }
+            _ => {}
+        }
+        reader.seek(SeekFrom::Start(start)).with_path(path)?;
+        metadata.map_err(|e| e.with_path(path))
+    }
+
+    /// Encrypts `reader` into a new segment at `offset` of `file`, followed by `index`
+    /// with the segment added. Returns the new length of `file`.
+    fn append_segment(
+        &self,
+        header: &Header,
+        reader: &mut dyn AlgorithmRead,
+        file: &mut File,
+        offset: u64,
+        index: &mut Index,
+        path: &Path,
+    ) -> Result<u64, Error> {
+        file.seek(SeekFrom::Start(offset)).with_path(path)?;
+        let mut writer = BufWriter::with_capacity(CAPACITY, &mut *file);
+        self.crypto_algorithm
+            .encrypt(
+                header,
+                reader,
+                &mut Section::new(&mut writer).with_path(path)?,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, path))?;
+        let end = writer.stream_position().with_path(path)?;
+        drop(writer);
+        index
+            .segments
+            .push(Segment::read(file, offset..end).map_err(|e| e.with_path(path))?);
+        self.write_index(header, index, file, end, path)
+    }
+
+    /// Writes `index` encrypted at `offset` of `file`, followed by the trailer, and ends `file`
+    /// there. Returns the new length of `file`.
+    fn write_index(
+        &self,
+        header: &Header,
+        index: &Index,
+        file: &mut File,
+        offset: u64,
+        path: &Path,
+    ) -> Result<u64, Error> {
+        let mut encrypted = Cursor::new(Vec::new());
+        self.crypto_algorithm
+            .encrypt(header, &mut Cursor::new(index.to_bytes()), &mut encrypted)
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, path))?;
+        let encrypted = encrypted.into_inner();
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&encrypted))
+            .and_then(|_| segment::write_trailer(file, encrypted.len() as u64))
+            .and_then(|_| file.stream_position())
+            .and_then(|len| file.set_len(len).and_then(|_| file.sync_all()).map(|_| len))
+            .with_path(path)
+    }
+
+    /// Moves `output` into place, with its parity file and signature if asked for.
+    fn persist_output(&self, output: Output, to_path: &Path) -> Result<(PathBuf, u64), Error> {
+        let policy = self.file_output_policy(to_path);
+        let signature = match &self.signing_key {
+            Some(key) => {
+                let signature = Partial::sibling(&signature_path(to_path));
+                fs::write(signature.path(), key.sign(&output.digest()?))
+                    .with_path(signature.path())?;
+                Some(signature)
+            }
+            None => None,
+        };
+        let parity = match (self.parity, &output) {
+            (Some(redundancy), Output::File(partial, _)) => {
+                let parity = Partial::sibling(&parity_path(to_path));
+                parity::write_parity(partial.path(), parity.path(), redundancy, &Progress::none())?;
+                Some(parity)
+            }
+            (Some(_), Output::Volumes(_)) => {
+                return Err(Error::new(
+                    ErrorKind::EncryptionError,
+                    "parity is not supported for volumes",
+                ))
+            }
+            (None, _) => None,
+        };
+        let persisted = output.persist(to_path, policy)?;
+        if let Some(parity) = parity {
+            parity.persist_with(&parity_path(to_path), policy)?;
+        }
+        if let Some(signature) = signature {
+            signature.persist_with(&signature_path(to_path), policy)?;
+        }
+        Ok(persisted)
+    }
+
+    /// Encrypts what the decryption thread sends through `reader`.
+    /// Returns once `reader` is exhausted, and drops it so that the decryption thread never blocks.
+    fn reencrypt(
+        &self,
+        old_header: &Header,
+        header: &Header,
+        mut reader: PipeReader,
+        writer: &mut dyn AlgorithmWrite,
+        partial_path: &Path,
+    ) -> Result<(), Error> {
+        // headerless files have no metadata, and checking it catches a wrong key early
+        let metadata = if old_header.is_legacy() {
+            Metadata::default()
+        } else {
+            Metadata::read(&mut reader)?
+        };
+        let mut bytes = Vec::new();
+        metadata.write(&mut bytes).with_path(partial_path)?;
+        reader.unread(&bytes);
+
+        self.crypto_algorithm
+            .encrypt(
+                header,
+                &mut reader,
+                &mut Section::new(writer).with_path(partial_path)?,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, partial_path))
+    }
+}
+
+/// Decrypted contents of an input, see `Process::decrypt_to_temp`.
+struct Decrypted {
+    file: NamedTempFile,
+    metadata: Metadata,
+    bytes_in: u64,
+    /// Removes the file if the program is cancelled.
+    _guard: Partial,
+}
+
+/// Roots of `metadata`, which must be plain file names.
+fn roots(metadata: &Metadata) -> Result<Vec<&Path>, Error> {
+    metadata
+        .roots
+        .iter()
+        .map(|root| {
+            let path = Path::new(root);
+            match path.file_name() == Some(path.as_os_str()) {
+                true => Ok(path),
+                false => Err(Error::new(
+                    ErrorKind::Corrupted,
+                    format!("invalid root name {root:?}"),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Fails if `metadata` says that the file ends with a segment index but none was `found`,
+/// which happens when the file is cut back to its first segment.
+fn check_indexed(metadata: &Metadata, found: bool, path: &Path) -> Result<(), Error> {
+    match metadata.indexed && !found {
+        true => Err(Error::new(
+            ErrorKind::Corrupted,
+            "the segment index is missing, the file was cut short",
+        )
+        .with_path(path)),
+        false => Ok(()),
+    }
+}
+
+/// Whether `e` is how `Pack::decompression` and `Pack::check` fail on a payload that is no archive,
+/// see `Metadata::payload`.
+fn is_not_archive(e: &anyhow::Error) -> bool {
+    !is_cancelled()
+        && e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::Other)
+}
+
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
 }
 
 fn get_fs_size(path: impl AsRef<Path>) -> Result<usize> {
@@ -0,0 +1,671 @@
+//! Directories encrypted file by file, see `Process::mirror`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, create_dir_all, File},
+    io::{BufReader, BufWriter, Read, Seek},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use filetime::FileTime;
+use sha2::{Digest, Sha256};
+
+use super::{get_fs_size, Process};
+use crate::{
+    error::PathContext,
+    format::{self, Section},
+    output::{is_cancelled, Partial},
+    pack::pack_file,
+    padding, Error, ErrorKind, Header, Metadata, OutputPolicy, Payload, Phase, Progress, Summary,
+    MAGIC,
+};
+
+/// Encoding of encrypted names, which case-insensitive file systems keep apart.
+const BASE32: base32::Alphabet = base32::Alphabet::Rfc4648Lower { padding: false };
+/// Longest file name most file systems accept.
+const MAX_NAME_LEN: usize = 255;
+/// Names are padded with zero bytes to a multiple of this before they are encrypted,
+/// so that encrypted names only reveal a range of lengths.
+const NAME_BUCKET: usize = 16;
+/// Encrypted names longer than `MAX_NAME_LEN` are replaced by `long.<base32 of their SHA-256>`,
+/// and stored in a file of that name with `.name` appended.
+const LONG_NAME_PREFIX: &str = "long.";
+const LONG_NAME_SUFFIX: &str = ".name";
+/// File at the top of every mirror, see `MirrorRecord`.
+const MIRROR_FILE: &str = ".mkencbox-mirror";
+
+impl Process {
+    /// Encrypts each file of the input directory into a file of the same name in the output
+    /// directory, or of its encrypted name. Files whose encrypted file has their modification
+    /// time are left alone if the mirror was encrypted with the same key and settings, and
+    /// encrypted files whose input is gone are removed. Any other existing output is only
+    /// replaced with `OutputPolicy::Overwrite`.
+    pub(super) fn mirror_enc(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        let record = MirrorRecord {
+            sealed_names: self.encrypt_names,
+            fingerprint: self.mirror_fingerprint()?,
+            complete: true,
+        };
+        let existing = MirrorRecord::read(&to_path);
+        let same = existing
+            .as_ref()
+            .is_some_and(|e| e.fingerprint == record.fingerprint);
+        if !same && is_occupied(&to_path) {
+            match self.output_policy {
+                // every file is encrypted again
+                OutputPolicy::Overwrite if to_path.is_dir() => {}
+                OutputPolicy::Overwrite => fs::remove_file(&to_path).with_path(&to_path)?,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::OutputExists,
+                        "it is not a mirror encrypted with this key and these settings",
+                    )
+                    .with_path(&to_path))
+                }
+            }
+        }
+        // files are only left alone once every one was encrypted with the same key and settings
+        let updating = same && existing.is_some_and(|e| e.complete);
+        let names = self
+            .mirror_entries(&self.from_path)
+            .with_path(&self.from_path)?;
+        let bytes_in = names
+            .iter()
+            .filter_map(|name| fs::metadata(self.from_path.join(name)).ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum();
+        progress.phase(Phase::Encrypting, bytes_in);
+        self.create_output_dir()?;
+        create_dir_all(&to_path).with_path(&to_path)?;
+
+        if !updating {
+            MirrorRecord {
+                complete: false,
+                ..record.clone()
+            }
+            .write(&to_path)?;
+        }
+
+        // names in the mirror, by name in the input
+        let mut mirrored = HashMap::<&Path, PathBuf>::new();
+        let mut keep = HashSet::from([PathBuf::from(MIRROR_FILE)]);
+        let mut done = 0;
+        for name in &names {
+            if is_cancelled() {
+                return Err(ErrorKind::Cancelled.into());
+            }
+            let parent = name.parent().unwrap_or(Path::new(""));
+            let parent = mirrored.get(parent).cloned().unwrap_or_default();
+            let mirror_name = match self.encrypt_names {
+                true => {
+                    let (sealed, long_name) = self.seal_name(&parent, name)?;
+                    if let Some((holder, encoded)) = long_name {
+                        let path = to_path.join(&holder);
+                        if fs::read_to_string(&path).ok().as_ref() != Some(&encoded) {
+                            fs::write(&path, &encoded).with_path(&path)?;
+                        }
+                        keep.insert(holder);
+                    }
+                    sealed
+                }
+                false => name.clone(),
+            };
+            keep.insert(mirror_name.clone());
+            mirrored.insert(name, mirror_name.clone());
+            let from = self.from_path.join(name);
+            let to = to_path.join(&mirror_name);
+            let metadata = fs::metadata(&from).with_path(&from)?;
+            if metadata.is_dir() {
+                create_dir_all(&to).with_path(&to)?;
+                continue;
+            }
+            let mtime = FileTime::from_last_modification_time(&metadata);
+            let unchanged = updating
+                && fs::metadata(&to).is_ok_and(|m| {
+                    m.is_file() && FileTime::from_last_modification_time(&m) == mtime
+                });
+            if !unchanged {
+                progress.entry(name);
+                self.encrypt_file(&from, &to)?;
+                filetime::set_file_mtime(&to, mtime).with_path(&to)?;
+            }
+            done += metadata.len();
+            progress.position(done);
+        }
+        prune(&to_path, &keep)?;
+        record.write(&to_path)?;
+
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out: get_fs_size(&to_path).unwrap_or(0) as u64,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Decrypts each encrypted file of the input directory into the output directory,
+    /// under its decrypted name if its name is encrypted.
+    pub(super) fn mirror_dec(self) -> Result<PathBuf, Error> {
+        let started = Instant::now();
+        let progress = &self.progress;
+        let to_path = self.resolve_to_path(None);
+        self.check_output(&to_path)?;
+        let entries = tree(&self.from_path)?;
+        let sealed_names = MirrorRecord::read(&self.from_path).is_some_and(|r| r.sealed_names);
+        let bytes_in = get_fs_size(&self.from_path).unwrap_or(0) as u64;
+        progress.phase(Phase::Decrypting, bytes_in);
+        self.create_output_dir()?;
+        let partial = Partial::sibling(&to_path);
+        create_dir_all(partial.path()).with_path(partial.path())?;
+
+        // names in the output, by name in the mirror
+        let mut opened = HashMap::<&Path, PathBuf>::new();
+        let mut done = 0;
+        for (name, is_dir) in &entries {
+            if is_cancelled() {
+                return Err(ErrorKind::Cancelled.into());
+            }
+            let parent = name.parent().unwrap_or(Path::new(""));
+            // parents come first
+            let parent = opened.get(parent).cloned().unwrap_or_default();
+            let from = self.from_path.join(name);
+            if !*is_dir && !is_encrypted(&from).with_path(&from)? {
+                if name != Path::new(MIRROR_FILE) && !(sealed_names && is_long_name_holder(name)) {
+                    progress.warning(format!("skipped {}: not an encrypted file", name.display()));
+                }
+                continue;
+            }
+            let output_name = parent.join(self.open_name(name, sealed_names)?);
+            let to = partial.path().join(&output_name);
+            if *is_dir {
+                create_dir_all(&to).with_path(&to)?;
+                opened.insert(name, output_name);
+                continue;
+            }
+            progress.entry(&output_name);
+            self.decrypt_file(&from, &to)?;
+            let metadata = fs::metadata(&from).with_path(&from)?;
+            filetime::set_file_mtime(&to, FileTime::from_last_modification_time(&metadata))
+                .with_path(&to)?;
+            done += metadata.len();
+            progress.position(done);
+        }
+
+        let bytes_out = get_fs_size(partial.path()).unwrap_or(0) as u64;
+        partial.persist_with(&to_path, self.output_policy)?;
+
+        progress.finish(Summary {
+            files: progress.files(),
+            bytes_in,
+            bytes_out,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(to_path)
+    }
+
+    /// Name in the mirror of the input entry `name`, in the directory mirrored as `parent`.
+    /// Names too long for a file name come with the name of the file to hold them, and its contents.
+    fn seal_name(
+        &self,
+        parent: &Path,
+        name: &Path,
+    ) -> Result<(PathBuf, Option<(PathBuf, String)>), Error> {
+        let Some(mut padded) = name_bytes(name.file_name().unwrap_or_default()) else {
+            return Err(Error::new(
+                ErrorKind::EncryptionError,
+                "names that are not valid Unicode cannot be encrypted on this system",
+            )
+            .with_path(self.from_path.join(name)));
+        };
+        padded.resize(padded.len().next_multiple_of(NAME_BUCKET), 0);
+        let sealed = self
+            .crypto_algorithm
+            .seal_name(context(parent).as_bytes(), &padded)
+            .map_err(|e| {
+                Error::from_anyhow(e, ErrorKind::EncryptionError, &self.from_path.join(name))
+            })?;
+        let encoded = base32::encode(BASE32, &sealed);
+        if encoded.len() <= MAX_NAME_LEN {
+            return Ok((parent.join(encoded), None));
+        }
+        let digest = base32::encode(BASE32, &Sha256::digest(encoded.as_bytes()));
+        let short = format!("{LONG_NAME_PREFIX}{digest}");
+        let holder = parent.join(format!("{short}{LONG_NAME_SUFFIX}"));
+        Ok((parent.join(short), Some((holder, encoded))))
+    }
+
+    /// Name in the output of the mirror entry `name`: decrypted if names are encrypted,
+    /// as is otherwise.
+    fn open_name(&self, name: &Path, sealed_names: bool) -> Result<OsString, Error> {
+        let file_name = name.file_name().unwrap_or_default();
+        if !sealed_names {
+            return Ok(file_name.to_os_string());
+        }
+        let corrupted = |reason: &str| {
+            Err(Error::new(ErrorKind::Corrupted, reason.to_string())
+                .with_path(self.from_path.join(name)))
+        };
+        let Some(file_name) = file_name.to_str() else {
+            return corrupted("invalid encrypted name");
+        };
+        let encoded = match file_name.starts_with(LONG_NAME_PREFIX) {
+            true => {
+                let mut holder = self.from_path.join(name).into_os_string();
+                holder.push(LONG_NAME_SUFFIX);
+                match fs::read_to_string(&holder) {
+                    Ok(encoded) => encoded,
+                    Err(_) => return corrupted("the file holding its name is missing"),
+                }
+            }
+            false => file_name.to_string(),
+        };
+        let parent = name.parent().unwrap_or(Path::new(""));
+        let opened = base32::decode(BASE32, &encoded).and_then(|sealed| {
+            self.crypto_algorithm
+                .open_name(context(parent).as_bytes(), &sealed)
+                .ok()
+        });
+        let opened = opened.map(|mut padded| {
+            let len = padded.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            padded.truncate(len);
+            padded
+        });
+        match opened.map(|opened| (is_file_name(&opened), name_from_bytes(opened))) {
+            Some((true, Some(opened))) => Ok(opened),
+            Some(_) => corrupted("invalid encrypted name"),
+            None => corrupted("its name failed authentication"),
+        }
+    }
+
+    /// Authenticated digest of the key and the settings files are encrypted with, see `MirrorRecord`.
+    fn mirror_fingerprint(&self) -> Result<String, Error> {
+        let settings = format!(
+            "padding {:?}, encrypted names {}, pack format {:?}, crypto {}",
+            self.padding,
+            self.encrypt_names,
+            self.pack_algorithm.format(),
+            hex::encode(self.crypto_algorithm.settings()),
+        );
+        let sealed = self
+            .crypto_algorithm
+            .seal_name(b"mkencbox mirror\0", settings.as_bytes())
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, &self.from_path))?;
+        Ok(base32::encode(BASE32, &sealed))
+    }
+
+    /// Files and directories of `in_path` that `Process::filters` lets through, parents first,
+    /// named relative to it. Symbolic links that are not followed are left out with a warning.
+    fn mirror_entries(&self, in_path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut names = Vec::new();
+        for entry in self.filters.walk(in_path, None)? {
+            let entry = entry?;
+            if entry.name == Path::new(MIRROR_FILE) {
+                self.progress.warning(format!(
+                    "skipped {MIRROR_FILE}: the name is reserved for the record of the mirror"
+                ));
+            } else if entry.metadata.is_file() || entry.metadata.is_dir() {
+                names.push(entry.name);
+            } else if entry.metadata.file_type().is_symlink() {
+                self.progress.warning(format!(
+                    "skipped {}: symbolic links are not mirrored",
+                    entry.name.display()
+                ));
+            }
+        }
+        Ok(names)
+    }
+
+    /// Encrypts the file `from` into `to` the way `enc` encrypts a single file.
+    fn encrypt_file(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let tmp = tempfile::tempfile().with_path(env::temp_dir())?;
+        let mut writer = BufWriter::new(tmp);
+        let metadata = Metadata {
+            original_name: from.file_name().map(|n| n.to_string_lossy().into_owned()),
+            pack_format: self.pack_algorithm.format(),
+            padded: !self.padding.is_none(),
+            payload: Some(Payload::File),
+            ..Metadata::default()
+        };
+        metadata.write(&mut writer).with_path(from)?;
+        pack_file(
+            from,
+            &mut Section::new(&mut writer).with_path(from)?,
+            &Progress::none(),
+        )
+        .with_path(from)?;
+        let mut tmp = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(from)?;
+        if metadata.padded {
+            self.padding.pad(&mut tmp).with_path(from)?;
+        }
+        tmp.rewind().with_path(from)?;
+
+        let partial = Partial::sibling(to);
+        let dst = File::create(partial.path()).with_path(partial.path())?;
+        let mut writer = BufWriter::new(dst);
+        let header = Header::current();
+        format::write_header(&mut writer, &header).with_path(partial.path())?;
+        self.crypto_algorithm
+            .encrypt(
+                &header,
+                &mut BufReader::new(tmp),
+                &mut Section::new(&mut writer).with_path(partial.path())?,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::EncryptionError, to))?;
+        let dst = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(partial.path())?;
+        dst.sync_all().with_path(partial.path())?;
+        drop(dst);
+        partial.persist(to).with_path(to)
+    }
+
+    /// Decrypts the file `from`, written by `encrypt_file`, into `to`.
+    fn decrypt_file(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let mut reader = BufReader::new(File::open(from).with_path(from)?);
+        let header = format::read_header(&mut reader)
+            .map_err(|e| e.with_path(from))?
+            .ok_or_else(|| Error::new(ErrorKind::Corrupted, "no header").with_path(from))?;
+        let tmp = tempfile::tempfile().with_path(env::temp_dir())?;
+        let mut writer = BufWriter::new(tmp);
+        self.crypto_algorithm
+            .decrypt(
+                &header,
+                &mut Section::new(&mut reader).with_path(from)?,
+                &mut writer,
+            )
+            .map_err(|e| Error::from_anyhow(e, ErrorKind::DecryptionError, from))?;
+        let mut tmp = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(from)?;
+        tmp.rewind().with_path(from)?;
+
+        let metadata = Metadata::read(&mut tmp).map_err(|e| e.with_path(from))?;
+        if metadata.padded {
+            padding::strip(&mut tmp).map_err(|e| e.with_path(from))?;
+        }
+        let mut reader = BufReader::new(tmp);
+        self.unpack(
+            &metadata,
+            &mut Section::new(&mut reader).with_path(from)?,
+            to,
+            &Progress::none(),
+        )
+        .map_err(|e| Error::from_anyhow(e, ErrorKind::DecryptionError, from))
+    }
+}
+
+/// Files and directories below `dir`, parents first, named relative to it.
+/// Symbolic links and special files are left out.
+fn tree(dir: &Path) -> Result<Vec<(PathBuf, bool)>, Error> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(parent) = pending.pop() {
+        let path = dir.join(&parent);
+        for entry in fs::read_dir(&path).with_path(&path)? {
+            let entry = entry.with_path(&path)?;
+            let file_type = entry.file_type().with_path(entry.path())?;
+            let name = parent.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(name.clone());
+                entries.push((name, true));
+            } else if file_type.is_file() {
+                entries.push((name, false));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// What names in a directory are bound to: the path of the directory in the mirror.
+fn context(parent: &Path) -> String {
+    let components: Vec<_> = parent
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    components.join("/")
+}
+
+fn is_file_name(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name != b"."
+        && name != b".."
+        && !name.iter().any(|b| [b'/', b'\\', 0].contains(b))
+}
+
+/// Bytes of the file name `name`, encrypted as they are so that no two names encrypt alike.
+/// `None` where names are not bytes and `name` is not valid Unicode.
+#[cfg(unix)]
+fn name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    name.to_str().map(|name| name.as_bytes().to_vec())
+}
+
+/// File name of the bytes `name_bytes` returned.
+#[cfg(unix)]
+fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(Into::into)
+}
+
+fn is_long_name_holder(name: &Path) -> bool {
+    let name = name.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with(LONG_NAME_PREFIX) && name.ends_with(LONG_NAME_SUFFIX)
+}
+
+/// Removes the encrypted files below `dir` that are not in `keep`, and the files holding
+/// their long names, then the directories left empty. Other files are never removed.
+fn prune(dir: &Path, keep: &HashSet<PathBuf>) -> Result<(), Error> {
+    let entries = tree(dir)?;
+    for (name, is_dir) in &entries {
+        let path = dir.join(name);
+        if *is_dir || keep.contains(name) {
+            continue;
+        }
+        if is_long_name_holder(name) || is_encrypted(&path).with_path(&path)? {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+    }
+    // children come after their parents
+    for (name, _) in entries
+        .iter()
+        .rev()
+        .filter(|(name, is_dir)| *is_dir && !keep.contains(name.as_path()))
+    {
+        // fails unless empty
+        let _ = fs::remove_dir(dir.join(name));
+    }
+    Ok(())
+}
+
+/// Contents of the `MIRROR_FILE` of a mirror:
+///
+/// ```text
+/// mkencbox mirror
+/// names: encrypted | plain
+/// settings | pending: fingerprint
+/// ```
+///
+/// In a mirror whose names are encrypted, every name that does not authenticate is corrupted
+/// rather than a name that was not encrypted. The fingerprint tells whether the files were
+/// encrypted with the same key and settings, see `Process::mirror_fingerprint`. It is pending
+/// until every file was encrypted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MirrorRecord {
+    sealed_names: bool,
+    fingerprint: String,
+    complete: bool,
+}
+
+impl MirrorRecord {
+    /// The record of the mirror `dir`, or `None` if `dir` is no mirror.
+    fn read(dir: &Path) -> Option<Self> {
+        let path = dir.join(MIRROR_FILE);
+        if !path.is_file() || is_encrypted(&path).unwrap_or(true) {
+            return None;
+        }
+        let contents = fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        if lines.next() != Some("mkencbox mirror") {
+            return None;
+        }
+        let sealed_names = match lines.next()? {
+            "names: encrypted" => true,
+            "names: plain" => false,
+            _ => return None,
+        };
+        let (complete, fingerprint) = match lines.next()?.split_once(": ")? {
+            ("settings", fingerprint) => (true, fingerprint),
+            ("pending", fingerprint) => (false, fingerprint),
+            _ => return None,
+        };
+        Some(Self {
+            sealed_names,
+            fingerprint: fingerprint.to_string(),
+            complete,
+        })
+    }
+
+    fn write(&self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join(MIRROR_FILE);
+        let contents = format!(
+            "mkencbox mirror\nnames: {}\n{}: {}\n",
+            match self.sealed_names {
+                true => "encrypted",
+                false => "plain",
+            },
+            match self.complete {
+                true => "settings",
+                false => "pending",
+            },
+            self.fingerprint
+        );
+        fs::write(&path, contents).with_path(&path)
+    }
+}
+
+/// Whether something that is not an empty directory is at `path`.
+fn is_occupied(path: &Path) -> bool {
+    match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => path.exists(),
+    }
+}
+
+fn is_encrypted(path: &Path) -> std::io::Result<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{Chacha20, Kdf, KeyMaterial, Process, Tar, Target};
+
+    use super::NAME_BUCKET;
+
+    #[test]
+    fn name_padding_test() {
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ));
+        let process = Process::new(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(crypto),
+            "photos",
+            "photos.enc",
+        );
+        let seal = |name: &str| {
+            let (sealed, long_name) = process.seal_name(Path::new(""), Path::new(name)).unwrap();
+            assert_eq!(None, long_name);
+            sealed
+        };
+
+        // names of one bucket cannot be told apart by their length
+        let short = seal("a.txt");
+        assert_eq!(
+            short.as_os_str().len(),
+            seal("holiday.jpeg").as_os_str().len()
+        );
+        assert_eq!(
+            short.as_os_str().len(),
+            seal(&"x".repeat(NAME_BUCKET)).as_os_str().len()
+        );
+        assert!(short.as_os_str().len() < seal("holiday_2026.jpeg").as_os_str().len());
+
+        for name in ["a.txt", "holiday_2026.jpeg"] {
+            assert_eq!(
+                name,
+                process
+                    .open_name(&seal(name), true)
+                    .unwrap()
+                    .to_string_lossy()
+            );
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn raw_name_test() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let crypto = Chacha20::with_key_material(KeyMaterial::new(
+            Kdf::CURRENT,
+            &mut [1u8; 32],
+            &mut [2u8; 12],
+        ));
+        let process = Process::new(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(crypto),
+            "photos",
+            "photos.enc",
+        );
+
+        // names that are not valid Unicode keep their bytes and stay apart
+        let names = [b"caf\xe9".as_slice(), b"caf\xe8".as_slice()];
+        let sealed: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let name = Path::new(OsStr::from_bytes(name));
+                process.seal_name(Path::new(""), name).unwrap().0
+            })
+            .collect();
+        assert_ne!(sealed[0], sealed[1]);
+        for (name, sealed) in names.iter().zip(&sealed) {
+            assert_eq!(*name, process.open_name(sealed, true).unwrap().as_bytes());
+        }
+    }
+}
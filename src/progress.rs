@@ -0,0 +1,262 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::output::{cancelled_io_error, is_cancelled};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Packing,
+    Encrypting,
+    Decrypting,
+    Unpacking,
+    /// Converting to the current format.
+    Upgrading,
+    /// Repairing with parity.
+    Repairing,
+    /// Comparing files with an archive.
+    Checking,
+}
+
+impl Phase {
+    pub fn description(&self) -> &str {
+        match self {
+            Phase::Packing => "packing",
+            Phase::Encrypting => "encrypting",
+            Phase::Decrypting => "decrypting",
+            Phase::Unpacking => "unpacking",
+            Phase::Upgrading => "upgrading",
+            Phase::Repairing => "repairing",
+            Phase::Checking => "checking",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A new phase started. `total` is the expected number of bytes it handles.
+    Phase {
+        phase: Phase,
+        total: u64,
+    },
+    /// Bytes handled so far in the current phase.
+    Position(u64),
+    /// An entry was packed or unpacked.
+    Entry(PathBuf),
+    /// Something could not be done as asked, without failing the process.
+    Warning(String),
+    Finish(Summary),
+}
+
+/// Handle used by `Process`, `Pack` and `Crypto` implementations to report progress.
+/// It does nothing when no receiver is attached.
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    tx: Option<Sender<ProgressEvent>>,
+    files: Arc<AtomicU64>,
+}
+
+impl Progress {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn new(tx: Sender<ProgressEvent>) -> Self {
+        Self {
+            tx: Some(tx),
+            files: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn phase(&self, phase: Phase, total: u64) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.blocking_send(ProgressEvent::Phase { phase, total });
+        }
+    }
+
+    pub fn position(&self, position: u64) {
+        if let Some(tx) = &self.tx {
+            // positions are cumulative, so a dropped one is superseded by the next
+            let _ = tx.try_send(ProgressEvent::Position(position));
+        }
+    }
+
+    pub fn entry(&self, path: &Path) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        if let Some(tx) = &self.tx {
+            let _ = tx.try_send(ProgressEvent::Entry(path.to_path_buf()));
+        }
+    }
+
+    pub fn warning(&self, message: impl Into<String>) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.blocking_send(ProgressEvent::Warning(message.into()));
+        }
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(&self, summary: Summary) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.blocking_send(ProgressEvent::Finish(summary));
+        }
+    }
+}
+
+const REPORT_INTERVAL: u64 = 256 * 1024; // 256KiB
+
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+    position: u64,
+    reported: u64,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub(crate) fn new(inner: R, progress: &'a Progress) -> Self {
+        Self {
+            inner,
+            progress,
+            position: 0,
+            reported: 0,
+        }
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.position += n;
+        if self.position.saturating_sub(self.reported) >= REPORT_INTERVAL {
+            self.reported = self.position;
+            self.progress.position(self.position);
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if is_cancelled() {
+            return Err(cancelled_io_error());
+        }
+        let read = self.inner.read(buf)?;
+        self.advance(read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.position = position;
+        self.reported = position;
+        Ok(position)
+    }
+}
+
+impl<R> Drop for ProgressReader<'_, R> {
+    fn drop(&mut self) {
+        self.progress.position(self.position);
+    }
+}
+
+pub(crate) struct ProgressWriter<'a, W> {
+    inner: W,
+    progress: &'a Progress,
+    position: u64,
+    reported: u64,
+}
+
+impl<'a, W> ProgressWriter<'a, W> {
+    pub(crate) fn new(inner: W, progress: &'a Progress) -> Self {
+        Self {
+            inner,
+            progress,
+            position: 0,
+            reported: 0,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.progress.position(self.position);
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if is_cancelled() {
+            return Err(cancelled_io_error());
+        }
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        if self.position.saturating_sub(self.reported) >= REPORT_INTERVAL {
+            self.reported = self.position;
+            self.progress.position(self.position);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for ProgressWriter<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.position = position;
+        self.reported = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Cursor, Read},
+        path::Path,
+    };
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{Progress, ProgressEvent, ProgressReader};
+
+    #[test]
+    fn progress_reader_test() {
+        let (tx, mut rx) = channel(8);
+        let progress = Progress::new(tx);
+
+        let mut reader = ProgressReader::new(Cursor::new(vec![0u8; 1000]), &progress);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        drop(reader);
+        progress.entry(Path::new("a.txt"));
+
+        assert_eq!(Some(ProgressEvent::Position(1000)), rx.try_recv().ok());
+        assert_eq!(
+            Some(ProgressEvent::Entry("a.txt".into())),
+            rx.try_recv().ok()
+        );
+        assert_eq!(1, progress.files());
+    }
+}
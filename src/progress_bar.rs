@@ -0,0 +1,148 @@
+use std::{
+    io::IsTerminal,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use mkencbox::{Phase, ProgressEvent, Summary};
+use tokio::sync::mpsc::Receiver;
+
+const PLAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn run(mut rx: Receiver<ProgressEvent>) {
+    if std::io::stderr().is_terminal() {
+        bar(&mut rx).await;
+    } else {
+        plain(&mut rx).await;
+    }
+}
+
+/// Prints only the warnings, above `pb`.
+pub async fn warnings(mut rx: Receiver<ProgressEvent>, pb: ProgressBar) {
+    while let Some(event) = rx.recv().await {
+        if let ProgressEvent::Warning(message) = event {
+            pb.suspend(|| eprintln!("warning: {message}"));
+        }
+    }
+}
+
+/// Bar counting finished inputs in batch mode. Hidden when stderr is not a terminal.
+pub fn items(total: u64) -> ProgressBar {
+    if !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{bar:30.cyan/blue}] {pos}/{len} {elapsed_precise} {wide_msg}",
+        )
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+async fn bar(rx: &mut Receiver<ProgressEvent>) {
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} {prefix:>10} [{bar:30.cyan/blue}] {bytes}/{total_bytes} \
+             {bytes_per_sec} ETA {eta} {wide_msg}",
+        )
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            ProgressEvent::Phase { phase, total } => {
+                pb.reset();
+                pb.set_length(total);
+                pb.set_prefix(phase.description().to_string());
+                pb.set_message("");
+            }
+            ProgressEvent::Position(position) => pb.set_position(position),
+            ProgressEvent::Entry(path) => pb.set_message(path.display().to_string()),
+            ProgressEvent::Warning(message) => pb.println(format!("warning: {message}")),
+            ProgressEvent::Finish(summary) => {
+                pb.finish_and_clear();
+                eprintln!("{}", summary_line(&summary));
+            }
+        }
+    }
+    pb.finish_and_clear();
+}
+
+async fn plain(rx: &mut Receiver<ProgressEvent>) {
+    let mut phase = Phase::Packing;
+    let mut total = 0;
+    let mut started = Instant::now();
+    let mut printed = Instant::now();
+    let mut entry = String::new();
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            ProgressEvent::Phase {
+                phase: next,
+                total: next_total,
+            } => {
+                phase = next;
+                total = next_total;
+                started = Instant::now();
+                printed = Instant::now();
+                entry.clear();
+                eprintln!("{}: {}", phase.description(), HumanBytes(total));
+            }
+            ProgressEvent::Position(position) => {
+                if printed.elapsed() < PLAIN_INTERVAL {
+                    continue;
+                }
+                printed = Instant::now();
+                eprintln!(
+                    "{}: {}",
+                    phase.description(),
+                    status_line(position, total, started.elapsed(), &entry)
+                );
+            }
+            ProgressEvent::Entry(path) => entry = path.display().to_string(),
+            ProgressEvent::Warning(message) => eprintln!("warning: {message}"),
+            ProgressEvent::Finish(summary) => eprintln!("{}", summary_line(&summary)),
+        }
+    }
+}
+
+fn status_line(position: u64, total: u64, elapsed: Duration, entry: &str) -> String {
+    let percent = (position.min(total) * 100)
+        .checked_div(total)
+        .unwrap_or(100);
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 {
+        position as f64 / secs
+    } else {
+        0.0
+    };
+    let eta = if rate > 0.0 {
+        Duration::from_secs_f64(total.saturating_sub(position) as f64 / rate)
+    } else {
+        Duration::ZERO
+    };
+    format!(
+        "{percent}% {}/{} {}/s ETA {} {entry}",
+        HumanBytes(position),
+        HumanBytes(total),
+        HumanBytes(rate as u64),
+        HumanDuration(eta),
+    )
+}
+
+fn summary_line(summary: &Summary) -> String {
+    format!(
+        "done: {} files, {} in, {} out, {}",
+        summary.files,
+        HumanBytes(summary.bytes_in),
+        HumanBytes(summary.bytes_out),
+        HumanDuration(summary.elapsed),
+    )
+}
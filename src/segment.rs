@@ -0,0 +1,230 @@
+//! Segments of an encrypted file that entries can be appended to, see `Target::Append` and
+//! `Metadata::indexed`:
+//!
+//! ```text
+//! header | segment 0 | segment 1 | ... | index | index length: u64 | "MKENCSEG"
+//! ```
+//!
+//! Segment 0 is the encrypted region of the original file, indexed on its own until something is
+//! appended, and each later segment encrypts a tar archive of appended entries on its own, with its
+//! own nonce. The index is encrypted the same way:
+//!
+//! ```text
+//! "MKENCIDX" | count: u32 | count × (offset: u64 | length: u64 | head) | metadata
+//! ```
+//!
+//! The head of a segment is its first bytes, the nonce with `Chacha20`, so that a segment cannot
+//! be swapped for another one encrypted with the same key. The metadata lists the roots added by
+//! appending, see `Metadata::roots`.
+
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+use crate::{Error, ErrorKind, Metadata};
+
+pub const SEGMENT_MAGIC: &[u8; 8] = b"MKENCSEG";
+const INDEX_MAGIC: &[u8; 8] = b"MKENCIDX";
+const TRAILER_SIZE: u64 = 8 + 8;
+const HEAD_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 8 + 8 + HEAD_SIZE;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub(crate) range: Range<u64>,
+    head: [u8; HEAD_SIZE],
+}
+
+impl Segment {
+    /// The segment at `range` of `reader`.
+    pub(crate) fn read<R: Read + Seek>(reader: &mut R, range: Range<u64>) -> Result<Self, Error> {
+        let mut head = [0u8; HEAD_SIZE];
+        reader
+            .seek(SeekFrom::Start(range.start))
+            .and_then(|_| reader.read_exact(&mut head))
+            .map_err(corrupted)?;
+        Ok(Self { range, head })
+    }
+}
+
+/// Segments of a file, in order, and the roots they added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Index {
+    pub(crate) segments: Vec<Segment>,
+    pub(crate) roots: Vec<String>,
+}
+
+impl Index {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + 4 + self.segments.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(INDEX_MAGIC);
+        bytes.extend_from_slice(&(self.segments.len() as u32).to_le_bytes());
+        for segment in &self.segments {
+            bytes.extend_from_slice(&segment.range.start.to_le_bytes());
+            bytes.extend_from_slice(&(segment.range.end - segment.range.start).to_le_bytes());
+            bytes.extend_from_slice(&segment.head);
+        }
+        let metadata = Metadata {
+            roots: self.roots.clone(),
+            ..Metadata::default()
+        };
+        // writing to a vector cannot fail
+        let _ = metadata.write(&mut bytes);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let truncated = || Error::new(ErrorKind::Corrupted, "truncated segment index");
+        if bytes.get(..8) != Some(INDEX_MAGIC) {
+            return Err(Error::new(ErrorKind::Corrupted, "invalid segment index"));
+        }
+        let count = bytes.get(8..12).ok_or_else(truncated)?;
+        let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+        let entries = count
+            .checked_mul(ENTRY_SIZE)
+            .and_then(|len| bytes.get(12..12 + len))
+            .ok_or_else(truncated)?;
+        let mut segments = Vec::with_capacity(count);
+        for entry in entries.chunks_exact(ENTRY_SIZE) {
+            let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let end = offset.checked_add(len).ok_or_else(truncated)?;
+            segments.push(Segment {
+                range: offset..end,
+                head: entry[16..].try_into().unwrap(),
+            });
+        }
+        let metadata = Metadata::read(&mut &bytes[12 + entries.len()..])?;
+        Ok(Self {
+            segments,
+            roots: metadata.roots,
+        })
+    }
+
+    /// Fails unless the segments follow each other from `start` to `end` in `reader`,
+    /// each with the head it was indexed with.
+    pub(crate) fn check<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        start: u64,
+        end: u64,
+    ) -> Result<(), Error> {
+        let mut next = start;
+        for segment in &self.segments {
+            if segment.range.start != next {
+                return Err(Error::new(
+                    ErrorKind::Corrupted,
+                    "segments overlap or leave gaps",
+                ));
+            }
+            if Segment::read(reader, segment.range.clone())? != *segment {
+                return Err(Error::new(
+                    ErrorKind::Corrupted,
+                    "a segment does not match the index",
+                ));
+            }
+            next = segment.range.end;
+        }
+        match next == end && !self.segments.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::Corrupted,
+                "segments do not fill the file",
+            )),
+        }
+    }
+}
+
+/// Where the encrypted index of `reader` lies, or `None` if it has none.
+/// The position of `reader` is kept.
+pub(crate) fn find_index<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Range<u64>>> {
+    let position = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut trailer = [0u8; TRAILER_SIZE as usize];
+    let found = len >= TRAILER_SIZE
+        && reader
+            .seek(SeekFrom::Start(len - TRAILER_SIZE))
+            .and_then(|_| reader.read_exact(&mut trailer))
+            .is_ok()
+        && &trailer[8..] == SEGMENT_MAGIC;
+    reader.seek(SeekFrom::Start(position))?;
+    if !found {
+        return Ok(None);
+    }
+    let index_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let end = len - TRAILER_SIZE;
+    match end.checked_sub(index_len) {
+        Some(start) => Ok(Some(start..end)),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "segment index larger than the file",
+        )),
+    }
+}
+
+/// Ends a file with the trailer of an index of `index_len` bytes, written just before.
+pub(crate) fn write_trailer(writer: &mut dyn Write, index_len: u64) -> std::io::Result<()> {
+    writer.write_all(&index_len.to_le_bytes())?;
+    writer.write_all(SEGMENT_MAGIC)
+}
+
+fn corrupted(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Corrupted, e)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    use crate::ErrorKind;
+
+    use super::{find_index, write_trailer, Index, Segment};
+
+    #[test]
+    fn index_test() {
+        let mut file = Cursor::new(Vec::new());
+        file.write_all(b"header").unwrap();
+        file.write_all(&[1u8; 40]).unwrap();
+        file.write_all(&[2u8; 50]).unwrap();
+        let index = Index {
+            segments: vec![
+                Segment::read(&mut file, 6..46).unwrap(),
+                Segment::read(&mut file, 46..96).unwrap(),
+            ],
+            roots: vec!["notes.txt".into()],
+        };
+        // stands in for the encrypted index
+        let bytes = index.to_bytes();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&bytes).unwrap();
+        write_trailer(&mut file, bytes.len() as u64).unwrap();
+
+        file.seek(SeekFrom::Start(3)).unwrap();
+        let range = find_index(&mut file).unwrap().unwrap();
+        assert_eq!(96..96 + bytes.len() as u64, range);
+        assert_eq!(3, file.position());
+        let read = Index::from_bytes(&file.get_ref()[96..range.end as usize]).unwrap();
+        assert_eq!(index, read);
+        read.check(&mut file, 6, 96).unwrap();
+        assert_eq!(
+            ErrorKind::Corrupted,
+            read.check(&mut file, 6, 100).unwrap_err().kind()
+        );
+
+        // a segment swapped for another one
+        file.get_mut()[50] = 3;
+        assert_eq!(
+            ErrorKind::Corrupted,
+            read.check(&mut file, 6, 96).unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::Corrupted,
+            Index::from_bytes(&bytes[..20]).unwrap_err().kind()
+        );
+
+        // files that were never appended to
+        let mut file = Cursor::new(vec![0u8; 100]);
+        assert_eq!(None, find_index(&mut file).unwrap());
+    }
+}
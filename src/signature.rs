@@ -0,0 +1,358 @@
+//! Ed25519 signatures of encrypted files, kept next to them in `NAME.sig`:
+//!
+//! ```text
+//! "MKENCSIG" | version: u8 | public key: 32 bytes | signature: 64 bytes
+//! ```
+//!
+//! The signature covers the SHA-256 digest of the whole encrypted file, header included,
+//! or of the file the volumes of a set make up.
+//! Keys are stored as hex text: the 32 byte secret of a signing key, and public keys one per line.
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::read_to_string,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{error::PathContext, Error, ErrorKind};
+
+pub const SIGNATURE_MAGIC: &[u8; 8] = b"MKENCSIG";
+const SIGNATURE_VERSION: u8 = 1;
+const SIGNATURE_FILE_SIZE: usize = 8 + 1 + PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
+/// Signed before the digest, so that signatures cannot be taken for anything else.
+const CONTEXT: &[u8] = b"mkencbox signature v1\0";
+/// Size of the blocks `Digesting` reads its input in.
+const BLOCK_SIZE: u64 = 1 << 20;
+
+/// Path of the signature of `path`, e.g. `backup.enc.sig`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// Key that signs encrypted files, see `Process::signing_key`.
+pub struct SigningKey {
+    key: ed25519_dalek::SigningKey,
+}
+
+impl SigningKey {
+    /// Reads a key file holding the 32 byte secret as hex, e.g. from `openssl rand -hex 32`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = Zeroizing::new(read_to_string(path).with_path(path)?);
+        let mut secret = Zeroizing::new([0u8; 32]);
+        hex::decode_to_slice(text.trim(), secret.as_mut_slice()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidKeyfile,
+                "expected 64 hex digits for a signing key",
+            )
+            .with_path(path)
+        })?;
+        Ok(Self {
+            key: ed25519_dalek::SigningKey::from_bytes(&secret),
+        })
+    }
+
+    /// The public key as hex, as `TrustedKeys` reads it.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Contents of the signature file of an encrypted file with `digest`.
+    pub(crate) fn sign(&self, digest: &[u8; 32]) -> Vec<u8> {
+        let signature = self.key.sign(&message(digest));
+        let mut bytes = Vec::with_capacity(SIGNATURE_FILE_SIZE);
+        bytes.extend_from_slice(SIGNATURE_MAGIC);
+        bytes.push(SIGNATURE_VERSION);
+        bytes.extend_from_slice(self.key.verifying_key().as_bytes());
+        bytes.extend_from_slice(&signature.to_bytes());
+        bytes
+    }
+}
+
+/// Public keys whose signatures are accepted, see `Process::trusted_keys`.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedKeys {
+    keys: Vec<VerifyingKey>,
+}
+
+impl TrustedKeys {
+    /// Reads public keys as hex, one per line. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = read_to_string(path).with_path(path)?;
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .try_fold(Self::default(), |keys, line| keys.key(line))
+            .map_err(|e| e.with_path(path))
+    }
+
+    /// Adds the public key `hex`.
+    pub fn key(mut self, hex: &str) -> Result<Self, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidKeyfile,
+                format!("invalid public key {hex}"),
+            )
+        };
+        let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+        hex::decode_to_slice(hex, &mut bytes).map_err(|_| invalid())?;
+        self.keys
+            .push(VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())?);
+        Ok(self)
+    }
+
+    /// Checks the contents of a signature file against `digest`.
+    /// Returns the public key that signed, as hex.
+    pub(crate) fn verify(&self, signature: &[u8], digest: &[u8; 32]) -> Result<String, Error> {
+        let bad = |message: String| Error::new(ErrorKind::BadSignature, message);
+        if signature.len() != SIGNATURE_FILE_SIZE || &signature[..8] != SIGNATURE_MAGIC {
+            return Err(bad("not a signature file".into()));
+        }
+        if signature[8] != SIGNATURE_VERSION {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                format!("signature version {}", signature[8]),
+            ));
+        }
+        let (key, signature) = signature[9..].split_at(PUBLIC_KEY_LENGTH);
+        let signer = hex::encode(key);
+        let Some(key) = self.keys.iter().find(|k| k.as_bytes() == key) else {
+            return Err(bad(format!("signed by untrusted key {signer}")));
+        };
+        let signature =
+            Signature::from_slice(signature).map_err(|_| bad("malformed signature".into()))?;
+        key.verify_strict(&message(digest), &signature)
+            .map_err(|_| bad(format!("signature by {signer} does not match the file")))?;
+        Ok(signer)
+    }
+}
+
+/// SHA-256 digest of everything `reader` yields.
+pub(crate) fn digest(reader: &mut dyn Read) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Reader computing the digest of its input while it is used, whatever order it is read in.
+/// It reads whole blocks, and fails reading a block again that changed since, so that everything
+/// read is what the digest of `finish` covers.
+pub(crate) struct Digesting<R> {
+    inner: R,
+    position: u64,
+    block: Vec<u8>,
+    /// Index of the block in `block`.
+    current: Option<u64>,
+    hasher: Sha256,
+    /// Digests of the blocks hashed so far, in order.
+    hashed: Vec<[u8; 32]>,
+    /// Digests of blocks read before the blocks in front of them.
+    ahead: HashMap<u64, [u8; 32]>,
+}
+
+impl<R: Read + Seek> Digesting<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            position: 0,
+            block: Vec::new(),
+            current: None,
+            hasher: Sha256::new(),
+            hashed: Vec::new(),
+            ahead: HashMap::new(),
+        }
+    }
+
+    /// SHA-256 digest of the whole input, reading the blocks that were not read yet.
+    pub(crate) fn finish(&mut self) -> io::Result<[u8; 32]> {
+        loop {
+            self.load(self.hashed.len() as u64)?;
+            if (self.block.len() as u64) < BLOCK_SIZE {
+                break;
+            }
+        }
+        // blocks were read past the end of the input
+        if !self.ahead.is_empty() {
+            return Err(changed());
+        }
+        Ok(self.hasher.clone().finalize().into())
+    }
+
+    fn load(&mut self, index: u64) -> io::Result<()> {
+        if self.current == Some(index) {
+            return Ok(());
+        }
+        self.current = None;
+        self.block.clear();
+        self.inner.seek(SeekFrom::Start(index * BLOCK_SIZE))?;
+        (&mut self.inner)
+            .take(BLOCK_SIZE)
+            .read_to_end(&mut self.block)?;
+        let digest: [u8; 32] = Sha256::digest(&self.block).into();
+        let hashed = self.hashed.len() as u64;
+        let expected = match index.cmp(&hashed) {
+            Ordering::Less => Some(self.hashed[index as usize]),
+            Ordering::Equal => self.ahead.remove(&index),
+            Ordering::Greater => Some(*self.ahead.entry(index).or_insert(digest)),
+        };
+        if expected.is_some_and(|expected| expected != digest) {
+            return Err(changed());
+        }
+        if index == hashed {
+            self.hasher.update(&self.block);
+            self.hashed.push(digest);
+        }
+        self.current = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.load(self.position / BLOCK_SIZE)?;
+        let offset = (self.position % BLOCK_SIZE) as usize;
+        let available = self.block.get(offset..).unwrap_or_default();
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for Digesting<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self
+                .inner
+                .seek(SeekFrom::End(0))?
+                .checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+fn changed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Error::new(
+            ErrorKind::BadSignature,
+            "the file changed while it was read",
+        ),
+    )
+}
+
+fn message(digest: &[u8; 32]) -> Vec<u8> {
+    [CONTEXT, digest].concat()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{write, File},
+        io::{Cursor, Read, Seek, SeekFrom},
+    };
+
+    use tempfile::tempdir;
+
+    use super::{digest, Digesting, SigningKey, TrustedKeys, BLOCK_SIZE};
+    use crate::ErrorKind;
+
+    #[test]
+    fn sign_and_verify_test() {
+        let dir = tempdir().unwrap();
+        let key_path = dir.path().join("signing.key");
+        write(&key_path, format!("{}\n", "2a".repeat(32))).unwrap();
+        let key = SigningKey::from_file(&key_path).unwrap();
+        let other_path = dir.path().join("other.key");
+        write(&other_path, "07".repeat(32)).unwrap();
+        let other = SigningKey::from_file(&other_path).unwrap();
+
+        let trusted_path = dir.path().join("trusted");
+        write(
+            &trusted_path,
+            format!("# backups\n{}\n\n", key.public_key()),
+        )
+        .unwrap();
+        let trusted = TrustedKeys::from_file(&trusted_path).unwrap();
+
+        let digest = [1u8; 32];
+        let signature = key.sign(&digest);
+        assert_eq!(
+            key.public_key(),
+            trusted.verify(&signature, &digest).unwrap()
+        );
+
+        let kind = |signature: &[u8], digest: &[u8; 32]| {
+            trusted.verify(signature, digest).unwrap_err().kind()
+        };
+        assert_eq!(ErrorKind::BadSignature, kind(&signature, &[2u8; 32]));
+        assert_eq!(ErrorKind::BadSignature, kind(&other.sign(&digest), &digest));
+        let mut forged = signature.clone();
+        forged[50] ^= 1;
+        assert_eq!(ErrorKind::BadSignature, kind(&forged, &digest));
+        assert_eq!(ErrorKind::BadSignature, kind(&signature[..40], &digest));
+
+        write(&key_path, "not hex").unwrap();
+        assert_eq!(
+            ErrorKind::InvalidKeyfile,
+            SigningKey::from_file(&key_path).err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn digesting_test() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let expected = digest(&mut data.as_slice()).unwrap();
+
+        // the end first, then from the start with a step back, as decrypting reads indexed files
+        let mut input = Digesting::new(Cursor::new(&data));
+        let mut tail = [0u8; 16];
+        input.seek(SeekFrom::End(-16)).unwrap();
+        input.read_exact(&mut tail).unwrap();
+        assert_eq!(data[data.len() - 16..], tail);
+        input.seek(SeekFrom::Start(10)).unwrap();
+        let mut head = vec![0u8; BLOCK_SIZE as usize + 10];
+        input.read_exact(&mut head).unwrap();
+        input.seek(SeekFrom::Start(20)).unwrap();
+        input.read_exact(&mut head).unwrap();
+        assert_eq!(data[20..20 + head.len()], head);
+        assert_eq!(expected, input.finish().unwrap());
+        assert_eq!(
+            expected,
+            Digesting::new(Cursor::new(&data)).finish().unwrap()
+        );
+
+        // the end read before the file changed
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("input");
+        write(&path, &data).unwrap();
+        let mut input = Digesting::new(File::open(&path).unwrap());
+        input.seek(SeekFrom::End(-16)).unwrap();
+        input.read_exact(&mut tail).unwrap();
+        let mut changed = data.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        write(&path, &changed).unwrap();
+        assert!(input.finish().is_err());
+
+        // the start read again after it changed
+        let mut input = Digesting::new(File::open(&path).unwrap());
+        input.read_exact(&mut head).unwrap();
+        changed[0] ^= 1;
+        write(&path, &changed).unwrap();
+        input.seek(SeekFrom::Start(0)).unwrap();
+        assert!(input.read_exact(&mut head).is_err());
+    }
+}
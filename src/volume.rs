@@ -0,0 +1,519 @@
+//! Encrypted files split into volumes of a fixed size:
+//!
+//! ```text
+//! "MKENCVOL" | version: u8 | set id: 16 bytes | number: u32 | count: u32 | part of the file
+//! ```
+//!
+//! Volume `n` of `NAME` is named `NAME.00n`, numbers starting at 1. Every volume of a set has
+//! the same random set id and the count of volumes, so that missing, swapped or foreign volumes
+//! are detected before decrypting.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{error::PathContext, output::Partial, Error, ErrorKind, OutputPolicy};
+
+pub const VOLUME_MAGIC: &[u8; 8] = b"MKENCVOL";
+const VOLUME_VERSION: u8 = 1;
+const SET_ID_SIZE: usize = 16;
+const COUNT_OFFSET: u64 = 8 + 1 + SET_ID_SIZE as u64 + 4;
+/// Bytes of each volume taken by its header.
+pub const VOLUME_HEADER_SIZE: u64 = COUNT_OFFSET + 4;
+
+/// Path of volume `number` of `base`, e.g. `backup.enc.001`.
+pub fn volume_path(base: &Path, number: u32) -> PathBuf {
+    let mut name = OsString::from(base.as_os_str());
+    name.push(format!(".{number:03}"));
+    PathBuf::from(name)
+}
+
+struct VolumeHeader {
+    set_id: [u8; SET_ID_SIZE],
+    number: u32,
+    count: u32,
+}
+
+impl VolumeHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(VOLUME_HEADER_SIZE as usize);
+        bytes.extend_from_slice(VOLUME_MAGIC);
+        bytes.push(VOLUME_VERSION);
+        bytes.extend_from_slice(&self.set_id);
+        bytes.extend_from_slice(&self.number.to_le_bytes());
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the header of a volume. `None` if `reader` is no volume.
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, Error> {
+        let mut bytes = [0u8; VOLUME_HEADER_SIZE as usize];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::new(ErrorKind::Io, e)),
+        }
+        if &bytes[..8] != VOLUME_MAGIC {
+            return Ok(None);
+        }
+        if bytes[8] != VOLUME_VERSION {
+            return Err(Error::new(
+                ErrorKind::UnsupportedVersion,
+                format!("volume version {}", bytes[8]),
+            ));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Ok(Some(Self {
+            set_id: bytes[9..9 + SET_ID_SIZE].try_into().unwrap(),
+            number: u32_at(9 + SET_ID_SIZE),
+            count: u32_at(COUNT_OFFSET as usize),
+        }))
+    }
+}
+
+/// Where an encrypted file is written: one partial file, or volumes.
+pub(crate) enum Output {
+    File(Partial, File),
+    Volumes(VolumeWriter),
+}
+
+impl Output {
+    pub(crate) fn create(to_path: &Path, volume_size: Option<u64>) -> Result<Self, Error> {
+        match volume_size {
+            Some(size) => Ok(Output::Volumes(VolumeWriter::new(to_path, size)?)),
+            None => {
+                let partial = Partial::sibling(to_path);
+                // read back to index the encrypted file, see `Metadata::indexed`
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(partial.path())
+                    .with_path(partial.path())?;
+                Ok(Output::File(partial, file))
+            }
+        }
+    }
+
+    /// Path to report errors with.
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Output::File(partial, _) => partial.path(),
+            Output::Volumes(volumes) => &volumes.base,
+        }
+    }
+
+    /// SHA-256 digest of what was written, as it reads back once in place.
+    pub(crate) fn digest(&self) -> Result<[u8; 32], Error> {
+        let (paths, skip) = match self {
+            Output::File(partial, _) => (vec![partial.path()], 0),
+            Output::Volumes(volumes) => (
+                volumes.volumes.iter().map(|p| p.path()).collect(),
+                VOLUME_HEADER_SIZE,
+            ),
+        };
+        let mut hasher = Sha256::new();
+        for path in paths {
+            let mut file = File::open(path).with_path(path)?;
+            file.seek(SeekFrom::Start(skip))
+                .and_then(|_| std::io::copy(&mut file, &mut hasher))
+                .with_path(path)?;
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Moves the output into place. Returns the path of the output, the first volume if split,
+    /// and the number of bytes written.
+    pub(crate) fn persist(
+        self,
+        to_path: &Path,
+        policy: OutputPolicy,
+    ) -> Result<(PathBuf, u64), Error> {
+        match self {
+            Output::File(partial, file) => {
+                file.sync_all().with_path(partial.path())?;
+                let bytes_out = file.metadata().with_path(partial.path())?.len();
+                partial.persist_with(to_path, policy)?;
+                Ok((to_path.to_path_buf(), bytes_out))
+            }
+            Output::Volumes(volumes) => volumes.persist(policy),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::File(_, file) => file.write(buf),
+            Output::Volumes(volumes) => volumes.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::File(_, file) => file.flush(),
+            Output::Volumes(volumes) => volumes.flush(),
+        }
+    }
+}
+
+impl Seek for Output {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Output::File(_, file) => file.seek(pos),
+            Output::Volumes(volumes) => volumes.seek(pos),
+        }
+    }
+}
+
+/// Writes an encrypted file as volumes of `volume_size` bytes, the last one possibly shorter.
+/// Volumes are partial outputs until `persist`.
+pub(crate) struct VolumeWriter {
+    base: PathBuf,
+    set_id: [u8; SET_ID_SIZE],
+    /// Bytes of the file per volume.
+    capacity: u64,
+    volumes: Vec<Partial>,
+    file: Option<File>,
+    left: u64,
+    position: u64,
+}
+
+impl VolumeWriter {
+    pub(crate) fn new(base: &Path, volume_size: u64) -> Result<Self, Error> {
+        if volume_size <= VOLUME_HEADER_SIZE {
+            return Err(Error::new(
+                ErrorKind::EncryptionError,
+                format!("volumes must be larger than {VOLUME_HEADER_SIZE} bytes"),
+            ));
+        }
+        let mut set_id = [0u8; SET_ID_SIZE];
+        OsRng.fill_bytes(&mut set_id);
+        Ok(Self {
+            base: base.to_path_buf(),
+            set_id,
+            capacity: volume_size - VOLUME_HEADER_SIZE,
+            volumes: Vec::new(),
+            file: None,
+            left: 0,
+            position: 0,
+        })
+    }
+
+    fn next_volume(&mut self) -> std::io::Result<()> {
+        let number = self.volumes.len() as u32 + 1;
+        let partial = Partial::sibling(&volume_path(&self.base, number));
+        let mut file = File::create(partial.path())?;
+        let header = VolumeHeader {
+            set_id: self.set_id,
+            number,
+            // known once all volumes are written
+            count: 0,
+        };
+        file.write_all(&header.to_bytes())?;
+        self.volumes.push(partial);
+        self.file = Some(file);
+        self.left = self.capacity;
+        Ok(())
+    }
+
+    /// Completes the volumes and moves them into place.
+    /// Returns the path of the first volume and the number of bytes written.
+    pub(crate) fn persist(mut self, policy: OutputPolicy) -> Result<(PathBuf, u64), Error> {
+        if self.volumes.is_empty() {
+            self.next_volume().with_path(&self.base)?;
+        }
+        self.file = None;
+        let count = self.volumes.len() as u32;
+        for partial in &self.volumes {
+            let mut file = File::options()
+                .write(true)
+                .open(partial.path())
+                .with_path(partial.path())?;
+            file.seek(SeekFrom::Start(COUNT_OFFSET))
+                .and_then(|_| file.write_all(&count.to_le_bytes()))
+                .and_then(|_| file.sync_all())
+                .with_path(partial.path())?;
+        }
+        for (number, partial) in (1..).zip(self.volumes) {
+            partial.persist_with(&volume_path(&self.base, number), policy)?;
+        }
+        let bytes_out = self.position + count as u64 * VOLUME_HEADER_SIZE;
+        Ok((volume_path(&self.base, 1), bytes_out))
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.file.is_none() || self.left == 0 {
+            self.next_volume()?;
+        }
+        let n = buf.len().min(self.left as usize);
+        let written = self.file.as_mut().unwrap().write(&buf[..n])?;
+        self.left -= written as u64;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Only tells the position, written volumes are not revisited.
+impl Seek for VolumeWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "volumes are written in one pass",
+            )),
+        }
+    }
+}
+
+/// Reads the volumes of a set as one file.
+pub(crate) struct VolumeReader {
+    paths: Vec<PathBuf>,
+    /// Position of each volume in the file.
+    starts: Vec<u64>,
+    len: u64,
+    current: usize,
+    file: File,
+    position: u64,
+}
+
+impl VolumeReader {
+    /// Name of the set without volume number.
+    pub(crate) fn base(&self) -> PathBuf {
+        self.paths[0].with_extension("")
+    }
+
+    /// Opens the set of volumes that `path` is a volume of, or the base name of.
+    /// `None` if `path` is an ordinary file.
+    pub(crate) fn open(path: &Path) -> Result<Option<Self>, Error> {
+        let base = match File::open(path) {
+            Ok(mut file) => match VolumeHeader::read(&mut file).map_err(|e| e.with_path(path))? {
+                // `NAME.001` and the like
+                Some(_) => path.with_extension(""),
+                None => return Ok(None),
+            },
+            Err(_) if volume_path(path, 1).is_file() => path.to_path_buf(),
+            Err(_) => return Ok(None),
+        };
+
+        let first = volume_path(&base, 1);
+        let mut file = File::open(&first)
+            .map_err(|_| invalid_volume(&first, "the first volume is missing"))?;
+        let header = match VolumeHeader::read(&mut file).map_err(|e| e.with_path(&first))? {
+            Some(header) if header.number == 1 && header.count > 0 => header,
+            _ => return Err(invalid_volume(&first, "not the first volume of a set")),
+        };
+        let mut paths = vec![first];
+        let mut starts = vec![0];
+        let mut len = payload_len(&file, &paths[0])?;
+        for number in 2..=header.count {
+            let path = volume_path(&base, number);
+            let mut volume = File::open(&path).map_err(|_| {
+                invalid_volume(
+                    &path,
+                    format!("volume {number} of {} is missing", header.count),
+                )
+            })?;
+            match VolumeHeader::read(&mut volume).map_err(|e| e.with_path(&path))? {
+                Some(h) if h.set_id != header.set_id => {
+                    return Err(invalid_volume(&path, "part of another set of volumes"))
+                }
+                Some(h) if h.number != number || h.count != header.count => {
+                    return Err(invalid_volume(
+                        &path,
+                        format!(
+                            "volume {} of {} found in place of volume {number}",
+                            h.number, h.count
+                        ),
+                    ))
+                }
+                Some(_) => {}
+                None => return Err(invalid_volume(&path, "not a volume")),
+            }
+            starts.push(len);
+            len += payload_len(&volume, &path)?;
+            paths.push(path);
+        }
+        Ok(Some(Self {
+            paths,
+            starts,
+            len,
+            current: 0,
+            file,
+            position: 0,
+        }))
+    }
+
+    /// Bytes of all volumes, headers included.
+    pub(crate) fn volumes_len(&self) -> u64 {
+        self.len + self.paths.len() as u64 * VOLUME_HEADER_SIZE
+    }
+
+    fn open_volume(&mut self, index: usize, offset: u64) -> std::io::Result<()> {
+        if index != self.current {
+            self.file = File::open(&self.paths[index])?;
+            self.current = index;
+        }
+        self.file
+            .seek(SeekFrom::Start(VOLUME_HEADER_SIZE + offset))?;
+        Ok(())
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || buf.is_empty() || self.current + 1 == self.paths.len() {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            self.open_volume(self.current + 1, 0)?;
+        }
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the volumes",
+            )
+        })?;
+        let index = self.starts.partition_point(|start| *start <= position) - 1;
+        self.open_volume(index, position - self.starts[index])?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+fn payload_len(file: &File, path: &Path) -> Result<u64, Error> {
+    let len = file.metadata().with_path(path)?.len();
+    Ok(len.saturating_sub(VOLUME_HEADER_SIZE))
+}
+
+fn invalid_volume(path: &Path, message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::Corrupted, message.into()).with_path(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{read, rename, write},
+        io::{Read, Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    use tempfile::tempdir;
+
+    use super::{volume_path, Output, VolumeReader, VOLUME_HEADER_SIZE};
+    use crate::{ErrorKind, OutputPolicy};
+
+    fn write_volumes(base: &Path, data: &[u8], volume_size: u64) -> u64 {
+        let mut output = Output::create(base, Some(volume_size)).unwrap();
+        for chunk in data.chunks(7) {
+            output.write_all(chunk).unwrap();
+        }
+        let (first, bytes_out) = output.persist(base, OutputPolicy::Fail).unwrap();
+        assert_eq!(first, volume_path(base, 1));
+        bytes_out
+    }
+
+    #[test]
+    fn volumes_test() {
+        let dir = tempdir().unwrap();
+        let base = dir.path().join("data.enc");
+        let data: Vec<u8> = (0..250u8).collect();
+        let volume_size = VOLUME_HEADER_SIZE + 100;
+        let bytes_out = write_volumes(&base, &data, volume_size);
+        assert_eq!(bytes_out, data.len() as u64 + 3 * VOLUME_HEADER_SIZE);
+        assert_eq!(
+            read(volume_path(&base, 1)).unwrap().len() as u64,
+            volume_size
+        );
+        assert!(volume_path(&base, 3).is_file());
+        assert!(!volume_path(&base, 4).exists());
+
+        // from the base name or any volume
+        for path in [base.clone(), volume_path(&base, 2)] {
+            let mut reader = VolumeReader::open(&path).unwrap().unwrap();
+            assert_eq!(reader.volumes_len(), bytes_out);
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, data);
+        }
+
+        let mut reader = VolumeReader::open(&base).unwrap().unwrap();
+        let mut buf = [0u8; 10];
+        reader.seek(SeekFrom::Start(95)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), &data[95..105]);
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), &data[240..]);
+        assert!(reader.seek(SeekFrom::Current(-300)).is_err());
+
+        // ordinary files are no volumes
+        let plain = dir.path().join("plain");
+        write(&plain, b"not a volume, but long enough to hold a header").unwrap();
+        assert!(VolumeReader::open(&plain).unwrap().is_none());
+        assert!(VolumeReader::open(&dir.path().join("missing"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_volumes_test() {
+        let dir = tempdir().unwrap();
+        let base = dir.path().join("data.enc");
+        let other = dir.path().join("other.enc");
+        let data = [1u8; 250];
+        write_volumes(&base, &data, VOLUME_HEADER_SIZE + 100);
+        write_volumes(&other, &data, VOLUME_HEADER_SIZE + 100);
+        let kind = |path: &Path| VolumeReader::open(path).err().map(|e| e.kind());
+
+        // swapped
+        let tmp = dir.path().join("tmp");
+        rename(volume_path(&base, 2), &tmp).unwrap();
+        rename(volume_path(&base, 3), volume_path(&base, 2)).unwrap();
+        rename(&tmp, volume_path(&base, 3)).unwrap();
+        assert_eq!(kind(&base), Some(ErrorKind::Corrupted));
+
+        // from another set
+        rename(volume_path(&other, 2), volume_path(&base, 2)).unwrap();
+        rename(volume_path(&other, 3), volume_path(&base, 3)).unwrap();
+        assert_eq!(kind(&base), Some(ErrorKind::Corrupted));
+
+        // missing
+        std::fs::remove_file(volume_path(&base, 3)).unwrap();
+        assert_eq!(kind(&base), Some(ErrorKind::Corrupted));
+        std::fs::remove_file(volume_path(&base, 1)).unwrap();
+        assert_eq!(kind(&volume_path(&base, 2)), Some(ErrorKind::Corrupted));
+    }
+}
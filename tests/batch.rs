@@ -0,0 +1,49 @@
+use common::{kfile, prepare, relative_path, ws_path};
+use mkencbox::{Batch, Chacha20, ErrorKind, Process, Tar, Target};
+use std::{fs::read, sync::Arc};
+
+mod common;
+
+#[tokio::test]
+async fn test_batch() {
+    let tag = "test_batch";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let output_dir = ws_path(tag);
+
+    let inputs = ["files/a.txt", "files/b.txt", "files/c.txt", "missing.txt"];
+    let mut batch = Batch::new(2);
+    for input in inputs {
+        let (infile, _) = relative_path(tag, input, "");
+        batch.push(Process::with_default_output(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            infile,
+            Some(output_dir.clone()),
+        ));
+    }
+
+    let mut finished = 0;
+    let items = batch.execute(|_| finished += 1).await;
+    assert_eq!(4, finished);
+
+    for (item, input) in items.iter().zip(inputs) {
+        let (infile, outfile) = relative_path(tag, input, "");
+        assert_eq!(infile, item.input);
+        if input == "missing.txt" {
+            let e = item.result.as_ref().unwrap_err();
+            assert_eq!(ErrorKind::Io, e.kind());
+            continue;
+        }
+        let encfile = item.result.as_ref().unwrap();
+        assert_eq!(
+            &outfile.join(format!(
+                "{}.enc",
+                infile.file_name().unwrap().to_string_lossy()
+            )),
+            encfile
+        );
+        assert!(read(encfile).unwrap().starts_with(b"MKENCBOX"));
+    }
+}
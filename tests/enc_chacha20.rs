@@ -1,6 +1,14 @@
-use common::{kfile, prepare, relative_path};
-use mkencbox::{Chacha20, Process, Tar, Target};
-use std::fs::read;
+use common::{dir_entries, kfile, prepare, relative_path, rs_path, ws_path};
+use mkencbox::{
+    parity_path, signature_path, volume_path, Chacha20, ErrorKind, Filters, OutputPolicy, Padding,
+    Process, SigningKey, Tar, Target, TrustedKeys, Zip, FORMAT_VERSION, MAGIC, PARITY_MAGIC,
+    SEGMENT_MAGIC, SIGNATURE_MAGIC, VOLUME_MAGIC,
+};
+use std::{
+    fs::{create_dir_all, read, remove_file, write, File},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 mod common;
 
@@ -38,7 +46,8 @@ async fn test_chacha() {
     let plain = read(&infile).unwrap();
     let encrypted = read(&outfile).unwrap();
     let decrypted = read(&decfile).unwrap();
-    assert_eq!(vec![250, 62, 4, 190, 89], encrypted);
+    assert_eq!(MAGIC, &encrypted[..8]);
+    assert_ne!(plain, encrypted[9..]);
     assert_eq!(plain, decrypted);
 
     // with salt
@@ -71,6 +80,985 @@ async fn test_chacha() {
     let salt_encrypted = read(&outfile).unwrap();
     let salt_decrypted = read(&decfile).unwrap();
     assert_ne!(encrypted, salt_encrypted);
-    assert_eq!(vec![229, 25, 140, 139, 136], salt_encrypted);
     assert_eq!(plain, salt_decrypted);
 }
+
+#[tokio::test]
+async fn test_chacha_headerless() {
+    let tag = "test_chacha_headerless";
+    prepare(tag);
+    let kfile = kfile();
+    let (infile, _) = relative_path(tag, "a.txt", "");
+    let plain = read(&infile).unwrap();
+
+    // written by versions without a header
+    let vectors = [
+        (None, "nosalt.enc", vec![250, 62, 4, 190, 89]),
+        (Some("salt"), "salt.enc", vec![229, 25, 140, 139, 136]),
+    ];
+    for (salt, name, encrypted) in vectors {
+        let (_, encfile) = relative_path(tag, "", name);
+        write(&encfile, encrypted).unwrap();
+
+        let processor = Process::with_default_output(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(Chacha20::new(salt.map(String::from), &kfile)),
+            &encfile,
+            None,
+        );
+        processor.execute().await.unwrap();
+
+        let decfile = encfile.with_extension("");
+        assert_eq!(plain, read(&decfile).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_chacha_original_name() {
+    let tag = "test_chacha_original_name";
+    prepare(tag);
+    let kfile = kfile();
+    let (infile, encfile) = relative_path(tag, "a.txt", "renamed.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(Chacha20::new(None, &kfile)),
+        &infile,
+        &encfile,
+    );
+    processor.execute().await.unwrap();
+
+    let output_dir = ws_path(tag).join("restored");
+    let processor = Process::with_default_output(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(Chacha20::new(None, &kfile)),
+        &encfile,
+        Some(output_dir.clone()),
+    );
+    processor.execute().await.unwrap();
+
+    assert_eq!(
+        read(&infile).unwrap(),
+        read(output_dir.join("a.txt")).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_chacha_upgrade() {
+    let tag = "test_chacha_upgrade";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let (infile, oldfile) = relative_path(tag, "a.txt", "old.enc");
+    let plain = read(&infile).unwrap();
+    // written by versions without a header
+    write(&oldfile, [250, 62, 4, 190, 89]).unwrap();
+
+    let newfile = ws_path(tag).join("new.enc");
+    let processor = Process::new(
+        Target::Upgrade,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &oldfile,
+        &newfile,
+    );
+    processor.execute().await.unwrap();
+    let upgraded = read(&newfile).unwrap();
+    assert_eq!(MAGIC, &upgraded[..8]);
+    assert_eq!(FORMAT_VERSION, upgraded[8]);
+
+    let decfile = ws_path(tag).join("new.dec");
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &newfile,
+        &decfile,
+    );
+    processor.execute().await.unwrap();
+    assert_eq!(plain, read(&decfile).unwrap());
+
+    // in place, reading the input as legacy explicitly
+    let processor = Process::with_default_output(
+        Target::Upgrade,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &oldfile,
+        None,
+    )
+    .legacy(true);
+    assert_eq!(oldfile, processor.execute().await.unwrap());
+    assert_eq!(MAGIC, &read(&oldfile).unwrap()[..8]);
+
+    let decfile = ws_path(tag).join("old.dec");
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg),
+        &oldfile,
+        &decfile,
+    );
+    processor.execute().await.unwrap();
+    assert_eq!(plain, read(&decfile).unwrap());
+}
+
+#[tokio::test]
+async fn test_chacha_keep_root() {
+    let tag = "test_chacha_keep_root";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let (indir, encfile) = relative_path(tag, "dir", "bundle.enc");
+    let infile = rs_path().join("a.txt");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &encfile,
+    )
+    .siblings(vec![infile.clone()]);
+    processor.execute().await.unwrap();
+
+    // roots are restored inside the output directory, under their own names
+    let output_dir = ws_path(tag).join("restored");
+    let dec = || {
+        Process::with_default_output(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &encfile,
+            Some(output_dir.clone()),
+        )
+    };
+    assert_eq!(output_dir, dec().execute().await.unwrap());
+    assert_eq!(
+        dir_entries(indir.clone()),
+        dir_entries(output_dir.join("dir"))
+    );
+    assert_eq!(
+        read(&infile).unwrap(),
+        read(output_dir.join("a.txt")).unwrap()
+    );
+    let e = dec().execute().await.unwrap_err();
+    assert_eq!(ErrorKind::OutputExists, e.kind());
+
+    let encfile = ws_path(tag).join("dir.enc");
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &encfile,
+    )
+    .keep_root(true);
+    processor.execute().await.unwrap();
+
+    let output_dir = ws_path(tag).join("renamed");
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg),
+        &encfile,
+        &output_dir,
+    );
+    processor.execute().await.unwrap();
+    assert_eq!(dir_entries(indir), dir_entries(output_dir.join("dir")));
+}
+
+#[tokio::test]
+async fn test_chacha_zip() {
+    let tag = "test_chacha_zip";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let (indir, encfile) = relative_path(tag, "dir", "dir.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Zip::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &encfile,
+    );
+    processor.execute().await.unwrap();
+
+    // the format recorded in the file picks the unpacker
+    let outdir = ws_path(tag).join("dir.out");
+    let dec = |outdir| {
+        Process::new(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &encfile,
+            outdir,
+        )
+    };
+    let e = dec(&outdir).execute().await.unwrap_err();
+    assert_eq!(ErrorKind::UnsupportedVersion, e.kind());
+    dec(&outdir)
+        .unpacker(Box::new(Zip::new()))
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(dir_entries(indir), dir_entries(outdir));
+}
+
+#[tokio::test]
+async fn test_chacha_mirror() {
+    let tag = "test_chacha_mirror";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = ws_path(tag).join("src");
+    create_dir_all(indir.join("child")).unwrap();
+    write(indir.join("b.txt"), "b").unwrap();
+    write(indir.join("child/c.txt"), "c").unwrap();
+    let mirror = ws_path(tag).join("src.enc");
+
+    let enc = || {
+        Process::with_default_output(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &indir,
+            None,
+        )
+        .mirror(true)
+    };
+    assert_eq!(mirror, enc().execute().await.unwrap());
+    let encrypted_b = read(mirror.join("b.txt")).unwrap();
+    let encrypted_c = read(mirror.join("child/c.txt")).unwrap();
+    assert!(encrypted_b.starts_with(MAGIC));
+    assert!(encrypted_c.starts_with(MAGIC));
+
+    // only changed files are encrypted again, and removed ones are removed from the mirror
+    write(mirror.join("notes"), "not encrypted").unwrap();
+    remove_file(indir.join("b.txt")).unwrap();
+    write(indir.join("child/c.txt"), "changed").unwrap();
+    File::options()
+        .write(true)
+        .open(indir.join("child/c.txt"))
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        .unwrap();
+    write(indir.join("d.txt"), "d").unwrap();
+    enc().execute().await.unwrap();
+    assert!(!mirror.join("b.txt").exists());
+    assert!(mirror.join("notes").exists());
+    assert_ne!(encrypted_c, read(mirror.join("child/c.txt")).unwrap());
+    let encrypted_d = read(mirror.join("d.txt")).unwrap();
+    enc().execute().await.unwrap();
+    assert_eq!(encrypted_d, read(mirror.join("d.txt")).unwrap());
+
+    let outdir = ws_path(tag).join("restored");
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &mirror,
+        &outdir,
+    );
+    processor.execute().await.unwrap();
+    assert_eq!(dir_entries(indir.clone()), dir_entries(outdir.clone()));
+    assert_eq!(
+        b"changed".to_vec(),
+        read(outdir.join("child/c.txt")).unwrap()
+    );
+    assert_eq!(
+        File::open(indir.join("child/c.txt"))
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .modified()
+            .unwrap(),
+        File::open(outdir.join("child/c.txt"))
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .modified()
+            .unwrap()
+    );
+
+    // entries left out by the walk configuration are removed from the mirror
+    enc()
+        .filters(Filters::new().exclude("child"))
+        .execute()
+        .await
+        .unwrap();
+    assert!(!mirror.join("child/c.txt").exists());
+    assert_eq!(encrypted_d, read(mirror.join("d.txt")).unwrap());
+
+    // other settings or another key only replace the mirror when asked to, encrypting every file
+    let err = enc().padding(Padding::Padme).execute().await.unwrap_err();
+    assert_eq!(ErrorKind::OutputExists, err.kind());
+    assert_eq!(encrypted_d, read(mirror.join("d.txt")).unwrap());
+    let salted = Arc::new(Chacha20::new(Some("salt".into()), kfile()));
+    let enc_salted = || {
+        Process::with_default_output(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(salted.clone()),
+            &indir,
+            None,
+        )
+        .mirror(true)
+    };
+    let err = enc_salted().execute().await.unwrap_err();
+    assert_eq!(ErrorKind::OutputExists, err.kind());
+    enc_salted()
+        .output_policy(OutputPolicy::Overwrite)
+        .execute()
+        .await
+        .unwrap();
+    assert_ne!(encrypted_d, read(mirror.join("d.txt")).unwrap());
+    enc_salted().execute().await.unwrap();
+    let outdir = ws_path(tag).join("salted");
+    Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(salted.clone()),
+        &mirror,
+        &outdir,
+    )
+    .execute()
+    .await
+    .unwrap();
+    assert_eq!(b"d".to_vec(), read(outdir.join("d.txt")).unwrap());
+}
+
+#[tokio::test]
+async fn test_chacha_mirror_names() {
+    let tag = "test_chacha_mirror_names";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = ws_path(tag).join("src");
+    let long_name = "l".repeat(200);
+    create_dir_all(indir.join("payroll")).unwrap();
+    write(indir.join("payroll/salaries_2026.xlsx"), "secret").unwrap();
+    write(indir.join(&long_name), "long").unwrap();
+    let mirror = ws_path(tag).join("mirror");
+
+    let enc = || {
+        Process::new(
+            Target::Enc,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &indir,
+            &mirror,
+        )
+        .mirror(true)
+        .encrypt_names(true)
+    };
+    enc().execute().await.unwrap();
+    let names = dir_entries(mirror.clone());
+    // with the record of the mirror
+    assert_eq!(6, names.len());
+    assert!(names
+        .iter()
+        .all(|n| !n.contains("payroll") && !n.contains("salaries") && !n.contains("lll")));
+    assert!(names
+        .iter()
+        .any(|n| n.starts_with("long.") && n.ends_with(".name")));
+
+    // names are deterministic, so the mirror stays as it is
+    enc().execute().await.unwrap();
+    assert_eq!(names, dir_entries(mirror.clone()));
+
+    // a renamed file replaces its old encrypted file
+    std::fs::rename(
+        indir.join("payroll/salaries_2026.xlsx"),
+        indir.join("payroll/salaries_2027.xlsx"),
+    )
+    .unwrap();
+    enc().execute().await.unwrap();
+    assert_eq!(6, dir_entries(mirror.clone()).len());
+    assert_ne!(names, dir_entries(mirror.clone()));
+
+    let outdir = ws_path(tag).join("restored");
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &mirror,
+        &outdir,
+    );
+    processor.execute().await.unwrap();
+    let mut expected = dir_entries(indir);
+    let mut restored = dir_entries(outdir.clone());
+    expected.sort();
+    restored.sort();
+    assert_eq!(expected, restored);
+    assert_eq!(
+        b"secret".to_vec(),
+        read(outdir.join("payroll/salaries_2027.xlsx")).unwrap()
+    );
+    assert_eq!(b"long".to_vec(), read(outdir.join(&long_name)).unwrap());
+
+    // a file moved to another directory no longer authenticates
+    let moved = dir_entries(mirror.clone())
+        .into_iter()
+        .find(|n| n.contains('/'))
+        .unwrap();
+    let moved = mirror.join(moved);
+    std::fs::rename(&moved, mirror.join(moved.file_name().unwrap())).unwrap();
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg),
+        &mirror,
+        ws_path(tag).join("moved"),
+    );
+    let err = processor.execute().await.unwrap_err();
+    assert_eq!(ErrorKind::Corrupted, err.kind());
+}
+
+#[tokio::test]
+async fn test_chacha_volumes() {
+    let tag = "test_chacha_volumes";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = rs_path().join("dir");
+    let outfile = ws_path(tag).join("dir.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &outfile,
+    )
+    .volume_size(Some(1024));
+    let first = processor.execute().await.unwrap();
+    assert_eq!(volume_path(&outfile, 1), first);
+    assert!(!outfile.exists());
+    assert!(volume_path(&outfile, 2).is_file());
+    assert_eq!(VOLUME_MAGIC, &read(&first).unwrap()[..8]);
+
+    // from the name without number and from a volume
+    for (input, name) in [(outfile.clone(), "dir.dec"), (first.clone(), "dir.dec.001")] {
+        let outdir = ws_path(tag).join(name);
+        let processor = Process::new(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &input,
+            &outdir,
+        );
+        processor.execute().await.unwrap();
+        assert_eq!(dir_entries(indir.clone()), dir_entries(outdir));
+    }
+
+    remove_file(volume_path(&outfile, 2)).unwrap();
+    let processor = Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg),
+        &outfile,
+        ws_path(tag).join("missing"),
+    );
+    let err = processor.execute().await.unwrap_err();
+    assert_eq!(ErrorKind::Corrupted, err.kind());
+}
+
+#[tokio::test]
+async fn test_chacha_padding() {
+    let tag = "test_chacha_padding";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+
+    let mut sizes = Vec::new();
+    for (input, name) in [("a.txt", "a"), ("dir", "dir")] {
+        let (infile, outfile) = relative_path(tag, input, &format!("{name}.enc"));
+        let processor = Process::new(
+            Target::Enc,
+            Box::new(Zip::new()),
+            Box::new(crypto_alg.clone()),
+            &infile,
+            &outfile,
+        )
+        .padding(Padding::Bucket(64 * 1024));
+        processor.execute().await.unwrap();
+        sizes.push(read(&outfile).unwrap().len());
+
+        let decfile = ws_path(tag).join(format!("{name}.dec"));
+        let processor = Process::new(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &outfile,
+            &decfile,
+        )
+        .unpacker(Box::new(Zip::new()));
+        processor.execute().await.unwrap();
+        assert_eq!(dir_entries(infile.clone()), dir_entries(decfile.clone()));
+        if infile.is_file() {
+            assert_eq!(read(&infile).unwrap(), read(&decfile).unwrap());
+        }
+    }
+    // inputs of different sizes look alike
+    assert_eq!(sizes[0], sizes[1]);
+    assert!(sizes[0] > 64 * 1024);
+}
+
+#[tokio::test]
+async fn test_chacha_repair() {
+    let tag = "test_chacha_repair";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = rs_path().join("dir");
+    let outfile = ws_path(tag).join("dir.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &outfile,
+    )
+    .parity(Some(10));
+    processor.execute().await.unwrap();
+    assert_eq!(PARITY_MAGIC, &read(parity_path(&outfile)).unwrap()[..8]);
+
+    let encrypted = read(&outfile).unwrap();
+    let mut damaged = encrypted.clone();
+    for b in &mut damaged[100..200] {
+        *b ^= 0xff;
+    }
+    write(&outfile, &damaged).unwrap();
+    let dec = |input: &std::path::Path, name: &str| {
+        Process::new(
+            Target::Dec,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            input,
+            ws_path(tag).join(name),
+        )
+    };
+    let err = dec(&outfile, "damaged").execute().await.unwrap_err();
+    assert_eq!(ErrorKind::Corrupted, err.kind());
+
+    // into another file, which gets its own parity file
+    let repaired = ws_path(tag).join("repaired.enc");
+    let processor = Process::new(
+        Target::Repair,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &outfile,
+        &repaired,
+    );
+    processor.execute().await.unwrap();
+    assert_eq!(encrypted, read(&repaired).unwrap());
+    assert!(parity_path(&repaired).is_file());
+
+    // in place
+    let processor = Process::with_default_output(
+        Target::Repair,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &outfile,
+        None,
+    );
+    assert_eq!(outfile, processor.execute().await.unwrap());
+    assert_eq!(encrypted, read(&outfile).unwrap());
+    dec(&outfile, "restored").execute().await.unwrap();
+    assert_eq!(
+        dir_entries(indir),
+        dir_entries(ws_path(tag).join("restored"))
+    );
+}
+
+#[tokio::test]
+async fn test_chacha_signature() {
+    let tag = "test_chacha_signature";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = rs_path().join("dir");
+    let outfile = ws_path(tag).join("dir.enc");
+    let key_path = ws_path(tag).join("signing.key");
+    write(&key_path, "5c".repeat(32)).unwrap();
+    let signing_key = Arc::new(SigningKey::from_file(&key_path).unwrap());
+    let trusted = Arc::new(
+        TrustedKeys::default()
+            .key(&signing_key.public_key())
+            .unwrap(),
+    );
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &outfile,
+    )
+    .signing_key(Some(signing_key.clone()));
+    processor.execute().await.unwrap();
+    assert_eq!(
+        SIGNATURE_MAGIC,
+        &read(signature_path(&outfile)).unwrap()[..8]
+    );
+
+    let process = |target: Target, name: &str, trusted_keys: Option<Arc<TrustedKeys>>| {
+        Process::new(
+            target,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &outfile,
+            ws_path(tag).join(name),
+        )
+        .trusted_keys(trusted_keys)
+    };
+    process(Target::Dec, "restored", Some(trusted.clone()))
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(
+        dir_entries(indir),
+        dir_entries(ws_path(tag).join("restored"))
+    );
+    assert_eq!(
+        outfile,
+        process(Target::Verify, "unused", Some(trusted.clone()))
+            .execute()
+            .await
+            .unwrap()
+    );
+
+    // verifying without trusted keys names the signer
+    let err = process(Target::Verify, "unused", None)
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::BadSignature, err.kind());
+    assert!(err.to_string().contains(&signing_key.public_key()));
+
+    // an untrusted signer
+    write(&key_path, "07".repeat(32)).unwrap();
+    let other = SigningKey::from_file(&key_path).unwrap();
+    let untrusted = Arc::new(TrustedKeys::default().key(&other.public_key()).unwrap());
+    let err = process(Target::Dec, "untrusted", Some(untrusted))
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::BadSignature, err.kind());
+    assert!(!ws_path(tag).join("untrusted").exists());
+
+    // a tampered file
+    let encrypted = read(&outfile).unwrap();
+    let mut tampered = encrypted.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    write(&outfile, &tampered).unwrap();
+    let err = process(Target::Dec, "tampered", Some(trusted.clone()))
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::BadSignature, err.kind());
+
+    // a missing signature
+    write(&outfile, &encrypted).unwrap();
+    remove_file(signature_path(&outfile)).unwrap();
+    let err = process(Target::Dec, "unsigned", Some(trusted))
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::BadSignature, err.kind());
+}
+
+#[tokio::test]
+async fn test_chacha_check() {
+    let tag = "test_chacha_check";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = rs_path().join("dir");
+    let encfile = ws_path(tag).join("dir.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &indir,
+        &encfile,
+    );
+    processor.execute().await.unwrap();
+    let process = |target: Target| {
+        Process::with_default_output(
+            target,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &encfile,
+            None,
+        )
+    };
+    let outdir = process(Target::Dec).execute().await.unwrap();
+    assert_eq!(ws_path(tag).join("dir"), outdir);
+
+    // the restored files are where `dec` put them
+    assert_eq!(outdir, process(Target::Check).execute().await.unwrap());
+
+    write(outdir.join("child/c.txt"), "changed").unwrap();
+    let err = process(Target::Check).execute().await.unwrap_err();
+    assert_eq!(ErrorKind::Mismatch, err.kind());
+    assert_eq!(10, err.kind().exit_code());
+    remove_file(outdir.join("child/c.txt")).unwrap();
+    let err = process(Target::Check).execute().await.unwrap_err();
+    assert_eq!(ErrorKind::Mismatch, err.kind());
+}
+
+#[tokio::test]
+async fn test_chacha_tar_file() {
+    let tag = "test_chacha_tar_file";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    // a single file that is a tar archive stays a file
+    let infile = ws_path(tag).join("a.tar");
+    let mut builder = tar::Builder::new(File::create(&infile).unwrap());
+    builder
+        .append_path_with_name(rs_path().join("a.txt"), "a.txt")
+        .unwrap();
+    builder.into_inner().unwrap();
+    let encfile = ws_path(tag).join("a.tar.enc");
+
+    let processor = Process::new(
+        Target::Enc,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        &infile,
+        &encfile,
+    );
+    processor.execute().await.unwrap();
+
+    let outdir = ws_path(tag).join("restored");
+    let process = |target: Target| {
+        Process::with_default_output(
+            target,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            &encfile,
+            Some(outdir.clone()),
+        )
+    };
+    let decfile = process(Target::Dec).execute().await.unwrap();
+    assert_eq!(outdir.join("a.tar"), decfile);
+    assert_eq!(read(&infile).unwrap(), read(&decfile).unwrap());
+    assert_eq!(decfile, process(Target::Check).execute().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_chacha_reproducible() {
+    let tag = "test_chacha_reproducible";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()).deterministic_nonce(true));
+    let indir = ws_path(tag).join("tree");
+    create_dir_all(indir.join("sub")).unwrap();
+    write(indir.join("a.txt"), "alpha").unwrap();
+    write(indir.join("sub/b.txt"), "beta").unwrap();
+
+    let enc = |name: &str| {
+        Process::new(
+            Target::Enc,
+            Box::new(Tar::new().reproducible(true)),
+            Box::new(crypto_alg.clone()),
+            &indir,
+            ws_path(tag).join(name),
+        )
+    };
+    enc("first.enc").execute().await.unwrap();
+    File::options()
+        .write(true)
+        .open(indir.join("a.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+    enc("second.enc").execute().await.unwrap();
+    let first = read(ws_path(tag).join("first.enc")).unwrap();
+    assert_eq!(first, read(ws_path(tag).join("second.enc")).unwrap());
+
+    // a random nonce unless asked for
+    Process::new(
+        Target::Enc,
+        Box::new(Tar::new().reproducible(true)),
+        Box::new(Chacha20::new(None, kfile())),
+        &indir,
+        ws_path(tag).join("random.enc"),
+    )
+    .execute()
+    .await
+    .unwrap();
+    assert_ne!(first, read(ws_path(tag).join("random.enc")).unwrap());
+
+    Process::new(
+        Target::Dec,
+        Box::new(Tar::new()),
+        Box::new(crypto_alg.clone()),
+        ws_path(tag).join("first.enc"),
+        ws_path(tag).join("restored"),
+    )
+    .execute()
+    .await
+    .unwrap();
+    assert_eq!(
+        dir_entries(indir),
+        dir_entries(ws_path(tag).join("restored"))
+    );
+}
+
+#[tokio::test]
+async fn test_chacha_append() {
+    let tag = "test_chacha_append";
+    prepare(tag);
+    let crypto_alg = Arc::new(Chacha20::new(None, kfile()));
+    let indir = ws_path(tag).join("tree");
+    create_dir_all(indir.join("sub")).unwrap();
+    write(indir.join("a.txt"), "alpha").unwrap();
+    write(indir.join("sub/b.txt"), "beta").unwrap();
+    let more = ws_path(tag).join("more");
+    create_dir_all(&more).unwrap();
+    write(more.join("a.txt"), "alpha, changed").unwrap();
+    write(more.join("c.txt"), "gamma").unwrap();
+    let note = ws_path(tag).join("note.txt");
+    write(&note, "delta").unwrap();
+    let encfile = ws_path(tag).join("tree.enc");
+
+    let process = |target: Target, from: &std::path::Path, to: &std::path::Path| {
+        Process::new(
+            target,
+            Box::new(Tar::new()),
+            Box::new(crypto_alg.clone()),
+            from,
+            to,
+        )
+    };
+    process(Target::Enc, &indir, &encfile)
+        .execute()
+        .await
+        .unwrap();
+    let original = read(&encfile).unwrap();
+    process(Target::Append, &more, &encfile)
+        .execute()
+        .await
+        .unwrap();
+    process(Target::Append, &note, &encfile)
+        .execute()
+        .await
+        .unwrap();
+    let appended = read(&encfile).unwrap();
+    // the index of the original file is replaced, its segment is kept
+    assert!(original.ends_with(SEGMENT_MAGIC));
+    let trailer = original.len() - 8 - SEGMENT_MAGIC.len();
+    let index_len = u64::from_le_bytes(original[trailer..trailer + 8].try_into().unwrap());
+    let first_end = trailer - index_len as usize;
+    assert_eq!(original[..first_end], appended[..first_end]);
+    assert!(appended.ends_with(SEGMENT_MAGIC));
+
+    // later segments replace entries of the same name
+    let outdir = ws_path(tag).join("restored");
+    process(Target::Dec, &encfile, &outdir)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(
+        "alpha, changed",
+        std::fs::read_to_string(outdir.join("a.txt")).unwrap()
+    );
+    assert_eq!(
+        "beta",
+        std::fs::read_to_string(outdir.join("sub/b.txt")).unwrap()
+    );
+    assert_eq!(
+        "gamma",
+        std::fs::read_to_string(outdir.join("c.txt")).unwrap()
+    );
+    assert_eq!(
+        "delta",
+        std::fs::read_to_string(outdir.join("note.txt")).unwrap()
+    );
+    process(Target::Check, &encfile, &outdir)
+        .execute()
+        .await
+        .unwrap();
+
+    // cutting the file back to its first segment drops the index it must have
+    let cut = ws_path(tag).join("cut.enc");
+    write(&cut, &appended[..first_end]).unwrap();
+    for (target, to) in [
+        (Target::Dec, ws_path(tag).join("cut")),
+        (Target::Check, outdir.clone()),
+        (Target::Upgrade, ws_path(tag).join("cut_upgraded.enc")),
+    ] {
+        let err = process(target, &cut, &to).execute().await.unwrap_err();
+        assert_eq!(ErrorKind::Corrupted, err.kind());
+    }
+    let err = process(Target::Append, &note, &cut)
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::Corrupted, err.kind());
+
+    // another key leaves the file as it was
+    let err = Process::new(
+        Target::Append,
+        Box::new(Tar::new()),
+        Box::new(Chacha20::new(Some("salt".into()), kfile())),
+        &note,
+        &encfile,
+    )
+    .execute()
+    .await
+    .unwrap_err();
+    assert_eq!(ErrorKind::WrongKey, err.kind());
+    assert_eq!(appended, read(&encfile).unwrap());
+
+    // the parity file is written again, and repairing checks every segment
+    process(Target::Append, &note, &encfile)
+        .parity(Some(10))
+        .execute()
+        .await
+        .unwrap();
+    assert!(parity_path(&encfile).exists());
+    assert!(process(Target::Append, &note, &encfile)
+        .execute()
+        .await
+        .is_err());
+    process(Target::Repair, &encfile, &ws_path(tag).join("repaired.enc"))
+        .execute()
+        .await
+        .unwrap();
+
+    // roots are appended next to the roots of the archive
+    let rooted = ws_path(tag).join("rooted.enc");
+    process(Target::Enc, &indir, &rooted)
+        .keep_root(true)
+        .execute()
+        .await
+        .unwrap();
+    process(Target::Append, &note, &rooted)
+        .execute()
+        .await
+        .unwrap();
+    let outdir = ws_path(tag).join("rooted");
+    process(Target::Dec, &rooted, &outdir)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(
+        "alpha",
+        std::fs::read_to_string(outdir.join("tree/a.txt")).unwrap()
+    );
+    assert_eq!(
+        "delta",
+        std::fs::read_to_string(outdir.join("note.txt")).unwrap()
+    );
+
+    // a single file is no archive
+    let single = ws_path(tag).join("note.enc");
+    process(Target::Enc, &note, &single)
+        .execute()
+        .await
+        .unwrap();
+    let err = process(Target::Append, &more, &single)
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::UnsupportedVersion, err.kind());
+}